@@ -0,0 +1,20 @@
+use std::fmt::Debug;
+
+/// A hash function which can be used to construct the pairwise
+/// hash-chains. Its name is recorded in serialized state, such that state
+/// can't be loaded with a different hash function than it was created with.
+pub trait DigestAlgorithm: digest::Digest + Clone + Debug {
+    const NAME: &'static str;
+}
+
+impl DigestAlgorithm for sha2::Sha256 {
+    const NAME: &'static str = "sha256";
+}
+
+impl DigestAlgorithm for sha2::Sha512_256 {
+    const NAME: &'static str = "sha512_256";
+}
+
+impl DigestAlgorithm for blake3::Hasher {
+    const NAME: &'static str = "blake3";
+}
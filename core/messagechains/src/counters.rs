@@ -0,0 +1,143 @@
+use std::borrow::Borrow;
+
+use crate::hashing::EntryHasher;
+use crate::{DeviceIdentifier, DigestAlgorithm, DigestVersion, Error, MessageChains, Operation};
+
+/// A message registered through [`MessageChains::send_counted_message`].
+/// Both fields must be sent along with the message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutgoingMessage {
+    pub digest_version: DigestVersion,
+    /// The sender's per-pair counters, one for each recipient. `None` if the
+    /// message is sent with a digest version which can't include them.
+    pub counters: Option<Vec<u64>>,
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> MessageChains<D, I> {
+    /// Send a message like [`MessageChains::send_message`], including the
+    /// number of messages previously sent to each recipient in this way.
+    ///
+    /// These per-pair counters are covered by the message digest, such that
+    /// each recipient can immediately detect that the server has dropped a
+    /// previous message, even if the messages were sent concurrently (see
+    /// [`crate::Envelope::counters`]). This requires
+    /// [`DigestVersion::V3`] to be supported by all recipients.
+    ///
+    /// Like for received messages, recipients which are retired or unknown
+    /// to the membership oracle are refused.
+    pub fn send_counted_message<BD: Borrow<I>>(
+        &mut self,
+        message: &[u8],
+        recipients: impl Iterator<Item = BD>,
+    ) -> Result<OutgoingMessage, Error<I>> {
+        let recipients_vec: Vec<BD> = recipients.collect();
+        self.check_quarantine(recipients_vec.iter().map(Borrow::<I>::borrow))?;
+
+        // Counters are kept in the pairwise chains, which may only be started
+        // for admitted recipients. The same applies to any message sent:
+        for r in recipients_vec.iter().map(Borrow::<I>::borrow) {
+            if *r != self.own_device {
+                self.check_admission(r)?;
+            }
+        }
+
+        let digest_version = recipients_vec
+            .iter()
+            .map(|r| self.peer_digest_version(r.borrow()))
+            .fold(self.config.digest_version, std::cmp::min);
+        if digest_version < DigestVersion::V3 {
+            self.send_message(message, recipients_vec.into_iter())?;
+            return Ok(OutgoingMessage {
+                digest_version,
+                counters: None,
+            });
+        }
+
+        // Count this message for all recipients:
+        let mut counters = Vec::with_capacity(recipients_vec.len());
+        for r in recipients_vec.iter().map(Borrow::<I>::borrow) {
+            let counter = if *r == self.own_device {
+                self.own_counter
+            } else {
+                self.admit_device(r)?;
+                self.chains[r].sent_counter
+            };
+            counters.push(counter);
+            self.apply(Operation::SetSentCounter {
+                device: r.clone(),
+                counter: counter + 1,
+            });
+        }
+
+        let digest = EntryHasher::<D, _>::with_counters(&recipients_vec, Some(&counters), message)
+            .entry_digest::<I>(
+                digest_version,
+                Some(&self.pending_messages.back().unwrap().digest),
+            );
+        let counted_recipients = recipients_vec.iter().map(|r| r.borrow().clone()).collect();
+        self.push_pending(digest_version, digest, Some(counted_recipients));
+
+        Ok(OutgoingMessage {
+            digest_version,
+            counters: Some(counters),
+        })
+    }
+
+    /// Check the per-pair counters of a message received from another
+    /// device, given its sorted recipients. The sender must be one of them,
+    /// such that its pairwise chain tracks the counted messages received.
+    pub(crate) fn check_counters<BD: Borrow<I>>(
+        &self,
+        sender: &I,
+        recipients: &[BD],
+        counters: &[u64],
+        version: DigestVersion,
+    ) -> Result<(), Error<I>> {
+        let sender_is_recipient = recipients.iter().any(|r| r.borrow() == sender);
+        if counters.len() != recipients.len() || version < DigestVersion::V3 || !sender_is_recipient
+        {
+            log::debug!(
+                "check_counters: invariant violated - {} counters for {} \
+                 recipients with digest version {:?} from {:?} (recipient: {})",
+                counters.len(),
+                recipients.len(),
+                version,
+                sender,
+                sender_is_recipient,
+            );
+            return Err(Error::InvalidCounters {
+                sender: sender.clone(),
+                recipients: recipients.len(),
+                counters: counters.len(),
+                version,
+            });
+        }
+
+        // We are known to be a recipient of the message:
+        let own_index = recipients
+            .iter()
+            .position(|r| *r.borrow() == self.own_device)
+            .unwrap();
+        let expected = self
+            .chains
+            .get(sender)
+            .map(|chain| chain.received_counter)
+            .unwrap_or(0);
+
+        if counters[own_index] != expected {
+            log::debug!(
+                "check_counters: message from {:?} has counter {}, expected {}",
+                sender,
+                counters[own_index],
+                expected,
+            );
+            return Err(Error::CounterGap {
+                sender: sender.clone(),
+                expected,
+                received: counters[own_index],
+            });
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+use crate::{DeviceIdentifier, DigestAlgorithm, DumpError, MessageChains, Operation};
+
+/// Records the epoch of the latest state persisted by the application, to
+/// detect when older state is restored. It must be kept outside of the
+/// storage holding the state itself, e.g., in a platform key store or a
+/// hardware monotonic counter.
+///
+/// Every dump of the state (see [`MessageChains::to_dump`]) and every commit
+/// to a [`crate::ChainStore`] starts a new epoch. Once the state has been
+/// persisted, its epoch must be recorded through [`EpochCounter::advance`].
+/// State can only be restored along with the counter, which rejects state
+/// older than recorded. If the application crashes before recording the
+/// epoch, the persisted state is newer than recorded, which is accepted and
+/// advances the counter.
+///
+/// This is implemented for `u64`, holding the latest epoch in memory.
+pub trait EpochCounter {
+    /// The epoch of the latest state persisted.
+    fn latest(&self) -> u64;
+
+    /// Record that state of `epoch` has been persisted. This is never called
+    /// with an epoch lower than [`EpochCounter::latest`].
+    fn advance(&mut self, epoch: u64);
+}
+
+impl EpochCounter for u64 {
+    fn latest(&self) -> u64 {
+        *self
+    }
+
+    fn advance(&mut self, epoch: u64) {
+        *self = epoch;
+    }
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> MessageChains<D, I> {
+    /// The epoch of the state, which is part of its dumps.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Start a new epoch, before persisting the state.
+    pub(crate) fn advance_epoch(&mut self) {
+        self.apply(Operation::SetEpoch {
+            epoch: self.epoch + 1,
+        });
+    }
+
+    /// Check restored state against the latest epoch recorded by `counter`,
+    /// which is advanced to the state's epoch if it is newer.
+    pub(crate) fn check_epoch(&self, counter: &mut impl EpochCounter) -> Result<(), DumpError> {
+        let latest = counter.latest();
+        if self.epoch < latest {
+            log::debug!(
+                "check_epoch: state of epoch {} rolled back, latest epoch is {}",
+                self.epoch,
+                latest
+            );
+            return Err(DumpError::Rollback {
+                epoch: self.epoch,
+                latest,
+            });
+        }
+
+        if self.epoch > latest {
+            counter.advance(self.epoch);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,347 @@
+use std::fmt;
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{DeviceId, DeviceIdentifier, DigestVersion, ForkEvidence};
+
+/// Errors returned by [`crate::MessageChains`].
+///
+/// Errors serialize with their variant name in snake case as `code`, and the
+/// variant's context (if any) as `details`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(
+    bound = "",
+    tag = "code",
+    content = "details",
+    rename_all = "snake_case"
+)]
+pub enum Error<I: DeviceIdentifier = DeviceId> {
+    TooFewRecipients,
+    MissingSelfRecipient,
+    /// The recipients are not sorted, or contain duplicates.
+    InvalidRecipientsOrder {
+        previous: I,
+        next: I,
+    },
+    /// A message sent by us does not match the oldest pending message
+    /// `pending_id`, or there is no pending message at all.
+    OwnMessageInvalidReordered {
+        pending_id: Option<u64>,
+    },
+    UnknownDevice(I),
+    UnknownMessage(usize),
+    /// A validation payload has been attached to a loopback message.
+    LoopbackValidationPayload,
+    /// A validation payload has been received from a device we don't share
+    /// a pairwise chain with.
+    UnknownValidationSender(I),
+    /// A validation payload refers to a sequence number outside of the
+    /// locally kept range `valid` of the pairwise chain.
+    InvalidValidationSeq {
+        sender: I,
+        seq: usize,
+        valid: Range<usize>,
+    },
+    MissingValidationPayload(I),
+    /// A gossip claim by `sender` is about its chain with `third_party`,
+    /// which is either the sender itself or our own device.
+    InvalidGossipThirdParty {
+        sender: I,
+        third_party: I,
+    },
+    /// A gossip claim by `sender` refers to sequence number `seq` of our
+    /// pairwise chain with it, which only extends up to `end`.
+    InvalidGossipSeq {
+        sender: I,
+        seq: usize,
+        end: usize,
+    },
+    /// A gossip claim by `sender` refers to message `local_seq`, which
+    /// hasn't been shared with `third_party`.
+    UnsharedGossipMessage {
+        sender: I,
+        third_party: I,
+        local_seq: usize,
+    },
+    /// `claimer` has sent a gossip claim about message `local_seq` in its
+    /// chain with `third_party` which differs from its previous claim.
+    ConflictingGossipClaims {
+        claimer: I,
+        third_party: I,
+        local_seq: usize,
+    },
+    ForkDetected(Box<ForkEvidence<I>>),
+    ThirdPartyForkDetected(I, I),
+    /// A message has been delivered before, as determined by its server
+    /// sequence number or sender counter.
+    DuplicateMessage {
+        sender: I,
+        server_seq: Option<u64>,
+        sender_counter: Option<u64>,
+    },
+    CounterGap {
+        sender: I,
+        expected: u64,
+        received: u64,
+    },
+    /// A message from `sender` carries `counters` per-pair counters for
+    /// `recipients` recipients, carries them with a digest version which
+    /// doesn't cover them, or the sender isn't one of its recipients.
+    InvalidCounters {
+        sender: I,
+        recipients: usize,
+        counters: usize,
+        version: DigestVersion,
+    },
+    UnknownPendingMessage(u64),
+    InvalidPendingOperation(u64),
+    /// Inserting the message would exceed the length limit of the chain
+    /// with `device`, or the total limit if `device` is `None`.
+    ChainLimitExceeded {
+        device: Option<I>,
+        limit: usize,
+    },
+    /// Inserting the message would exceed the number of message records
+    /// which may be kept under the configured limits.
+    MessageRecordLimitExceeded {
+        limit: usize,
+    },
+    /// Our own device can't be retired.
+    RetireOwnDevice,
+    RetiredRecipient(I),
+    /// The retired device still has a pairwise chain with us, so it can't be
+    /// re-added until it has retired us as well.
+    PeerNotReset(I),
+    UnknownRecipient(I),
+    /// The peer has been quarantined (see [`crate::PeerHealth`]).
+    PeerQuarantined(I),
+}
+
+struct HexDigest<'a>(&'a [u8]);
+
+impl fmt::Display for HexDigest<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+impl<I: DeviceIdentifier> fmt::Display for Error<I> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::TooFewRecipients => write!(f, "message has no recipients"),
+            Error::MissingSelfRecipient => write!(f, "own device is not a recipient"),
+            Error::InvalidRecipientsOrder { previous, next } => write!(
+                f,
+                "recipients are not strictly ordered: {:?} >= {:?}",
+                previous, next
+            ),
+            Error::OwnMessageInvalidReordered {
+                pending_id: Some(id),
+            } => write!(
+                f,
+                "own message does not match pending message {}, server \
+                 reordered or modified it",
+                id
+            ),
+            Error::OwnMessageInvalidReordered { pending_id: None } => {
+                write!(f, "own message received without any pending message")
+            }
+            Error::UnknownDevice(device) => write!(f, "unknown device {:?}", device),
+            Error::UnknownMessage(local_seq) => write!(f, "unknown message {}", local_seq),
+            Error::LoopbackValidationPayload => {
+                write!(f, "validation payload on loopback message")
+            }
+            Error::UnknownValidationSender(sender) => {
+                write!(f, "validation payload from unknown sender {:?}", sender)
+            }
+            Error::InvalidValidationSeq { sender, seq, valid } => write!(
+                f,
+                "validation payload from {:?} refers to sequence number {}, \
+                 expected within [{}; {})",
+                sender, seq, valid.start, valid.end
+            ),
+            Error::MissingValidationPayload(sender) => {
+                write!(f, "missing validation payload from {:?}", sender)
+            }
+            Error::InvalidGossipThirdParty {
+                sender,
+                third_party,
+            } => write!(
+                f,
+                "gossip claim by {:?} about its chain with {:?}",
+                sender, third_party
+            ),
+            Error::InvalidGossipSeq { sender, seq, end } => write!(
+                f,
+                "gossip claim by {:?} refers to sequence number {}, expected \
+                 below {}",
+                sender, seq, end
+            ),
+            Error::UnsharedGossipMessage {
+                sender,
+                third_party,
+                local_seq,
+            } => write!(
+                f,
+                "gossip claim by {:?} refers to message {} not shared with {:?}",
+                sender, local_seq, third_party
+            ),
+            Error::ConflictingGossipClaims {
+                claimer,
+                third_party,
+                local_seq,
+            } => write!(
+                f,
+                "conflicting gossip claims by {:?} about message {} in its \
+                 chain with {:?}",
+                claimer, local_seq, third_party
+            ),
+            Error::ForkDetected(evidence) => write!(
+                f,
+                "fork detected with {:?} at sequence number {}: expected \
+                 digest {}, claimed {}",
+                evidence.sender,
+                evidence.seq,
+                HexDigest(&evidence.local_digest),
+                HexDigest(&evidence.claimed_digest)
+            ),
+            Error::ThirdPartyForkDetected(a, b) => {
+                write!(f, "fork detected between {:?} and {:?}", a, b)
+            }
+            Error::DuplicateMessage {
+                sender,
+                server_seq,
+                sender_counter,
+            } => write!(
+                f,
+                "duplicate message from {:?} (server sequence number {:?}, \
+                 sender counter {:?})",
+                sender, server_seq, sender_counter
+            ),
+            Error::CounterGap {
+                sender,
+                expected,
+                received,
+            } => write!(
+                f,
+                "message from {:?} has counter {}, expected {}",
+                sender, received, expected
+            ),
+            Error::InvalidCounters {
+                sender,
+                recipients,
+                counters,
+                version,
+            } => write!(
+                f,
+                "message from {:?} carries {} counters for {} recipients with \
+                 digest version {:?}",
+                sender, counters, recipients, version
+            ),
+            Error::UnknownPendingMessage(id) => write!(f, "unknown pending message {}", id),
+            Error::InvalidPendingOperation(id) => {
+                write!(f, "invalid operation on pending message {}", id)
+            }
+            Error::ChainLimitExceeded {
+                device: Some(device),
+                limit,
+            } => write!(
+                f,
+                "chain with {:?} exceeds its length limit of {}",
+                device, limit
+            ),
+            Error::ChainLimitExceeded {
+                device: None,
+                limit,
+            } => write!(f, "chains exceed their total length limit of {}", limit),
+            Error::MessageRecordLimitExceeded { limit } => {
+                write!(f, "message records exceed their limit of {}", limit)
+            }
+            Error::RetireOwnDevice => write!(f, "own device can't be retired"),
+            Error::RetiredRecipient(device) => write!(f, "recipient {:?} is retired", device),
+            Error::PeerNotReset(device) => {
+                write!(f, "peer {:?} has not reset its chain with us", device)
+            }
+            Error::UnknownRecipient(device) => write!(f, "recipient {:?} is unknown", device),
+            Error::PeerQuarantined(device) => write!(f, "peer {:?} is quarantined", device),
+        }
+    }
+}
+
+impl<I: DeviceIdentifier> std::error::Error for Error<I> {}
+
+/// Errors restoring dumped [`crate::MessageChains`] state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DumpError {
+    /// The dump is malformed, of an unsupported schema version or uses a
+    /// different digest algorithm.
+    Decode(String),
+    /// A sealed dump has been modified, or sealed with a different key.
+    Unauthenticated,
+    /// The state is of an older epoch than the latest one persisted, and
+    /// has hence been rolled back (see [`crate::EpochCounter`]).
+    Rollback { epoch: u64, latest: u64 },
+}
+
+impl fmt::Display for DumpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DumpError::Decode(reason) => write!(f, "failed to decode state: {}", reason),
+            DumpError::Unauthenticated => write!(f, "sealed state failed to authenticate"),
+            DumpError::Rollback { epoch, latest } => write!(
+                f,
+                "state of epoch {} has been rolled back, latest epoch is {}",
+                epoch, latest
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DumpError {}
+
+/// Errors persisting [`crate::MessageChains`] state to a
+/// [`crate::ChainStore`], or recovering it.
+#[derive(Debug)]
+pub enum StoreError {
+    Io(std::io::Error),
+    /// The snapshot can't be restored.
+    Dump(DumpError),
+    /// A batch of operations can't be decoded.
+    Corrupt(String),
+    /// The store holds no state to recover.
+    Empty,
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Io(e) => write!(f, "store I/O failed: {}", e),
+            StoreError::Dump(e) => write!(f, "failed to restore snapshot: {}", e),
+            StoreError::Corrupt(reason) => write!(f, "corrupt operation log: {}", reason),
+            StoreError::Empty => write!(f, "store holds no state"),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StoreError::Io(e) => Some(e),
+            StoreError::Dump(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for StoreError {
+    fn from(e: std::io::Error) -> Self {
+        StoreError::Io(e)
+    }
+}
+
+impl From<DumpError> for StoreError {
+    fn from(e: DumpError) -> Self {
+        StoreError::Dump(e)
+    }
+}
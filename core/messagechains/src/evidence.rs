@@ -0,0 +1,204 @@
+use serde::{Deserialize, Serialize};
+
+use crate::hashing::EntryHasher;
+use crate::{DeviceIdentifier, DigestAlgorithm, DigestVersion, Hash, MessageChains};
+
+/// A pairwise hash-chain entry included in [`ForkEvidence`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct ForkEvidenceEntry<I: DeviceIdentifier> {
+    /// Sequence number of this entry in the pairwise hash-chain.
+    pub seq: usize,
+    /// Local sequence number of the message on the device which collected
+    /// the evidence, to look up the message contents.
+    pub local_seq: usize,
+    /// Sorted recipients of the message.
+    pub recipients: Vec<I>,
+    /// The sender's per-pair counters included in the message digest, if
+    /// any.
+    #[serde(default)]
+    pub counters: Option<Vec<u64>>,
+    /// Format of the entry digest.
+    pub version: DigestVersion,
+    /// Digest of this hash-chain entry, as computed locally.
+    pub digest: Vec<u8>,
+}
+
+/// Evidence that the pairwise hash-chain between `receiver` and `sender` has
+/// forked: `sender` claimed a digest for sequence number `seq` which differs
+/// from the one `receiver` has computed.
+///
+/// The evidence is self-contained, such that an independent party holding
+/// the messages referenced by `entries` can recompute the local chain
+/// through [`ForkEvidence::verify`]. It does not authenticate the claimed
+/// digest itself, which has to be established through the (authenticated)
+/// message carrying the validation payload.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct ForkEvidence<I: DeviceIdentifier> {
+    /// Name of the [`DigestAlgorithm`] used to compute the digests.
+    pub algorithm: String,
+    /// Device which sent the offending validation payload.
+    pub sender: I,
+    /// Device which detected the fork.
+    pub receiver: I,
+    /// Sequence number referenced by the validation payload.
+    pub seq: usize,
+    /// Digest of entry `seq` in the receiver's chain.
+    pub local_digest: Vec<u8>,
+    /// Digest for entry `seq` as claimed by the sender.
+    pub claimed_digest: Vec<u8>,
+    /// Sequence number and digest of the last entry previously validated by
+    /// the sender, preceding `entries`. If `None`, `entries` start at the
+    /// beginning of the pairwise hash-chain. Otherwise, a fork at the very
+    /// first locally kept entry cannot be verified.
+    pub base: Option<(usize, Vec<u8>)>,
+    /// All chain entries after `base`, up to and including `seq`.
+    pub entries: Vec<ForkEvidenceEntry<I>>,
+}
+
+impl<I: DeviceIdentifier> ForkEvidence<I> {
+    /// Recompute the receiver's hash-chain from `base` through all `entries`,
+    /// given the contents of each entry's message, in order. Returns `true`
+    /// iff the recomputed chain is consistent with the recorded digests and
+    /// leads to `local_digest`, which differs from `claimed_digest`.
+    pub fn verify<D: DigestAlgorithm>(
+        &self,
+        messages: impl IntoIterator<Item = impl AsRef<[u8]>>,
+    ) -> bool {
+        if self.algorithm != D::NAME || self.local_digest == self.claimed_digest {
+            return false;
+        }
+
+        let (mut next_seq, mut prev_digest) = match &self.base {
+            Some((seq, digest)) => {
+                let mut base_digest = Hash::<D>::default();
+                if base_digest.len() != digest.len() {
+                    return false;
+                }
+                base_digest.copy_from_slice(digest);
+                (seq + 1, Some(base_digest))
+            }
+            None => (0, None),
+        };
+
+        let mut messages = messages.into_iter();
+        for entry in self.entries.iter() {
+            // Entries must be contiguous, belong to the pairwise hash-chain
+            // of sender and receiver, and carry a counter for each recipient
+            // (if any):
+            if entry.seq != next_seq
+                || entry.recipients.binary_search(&self.sender).is_err()
+                || entry.recipients.binary_search(&self.receiver).is_err()
+                || entry
+                    .counters
+                    .as_ref()
+                    .is_some_and(|counters| counters.len() != entry.recipients.len())
+            {
+                return false;
+            }
+
+            let message = match messages.next() {
+                Some(message) => message,
+                None => return false,
+            };
+
+            let digest = EntryHasher::<D, _>::with_counters(
+                &entry.recipients,
+                entry.counters.as_deref(),
+                message.as_ref(),
+            )
+            .entry_digest::<I>(entry.version, prev_digest.as_ref());
+            if digest[..] != entry.digest[..] {
+                return false;
+            }
+
+            next_seq += 1;
+            prev_digest = Some(digest);
+        }
+
+        next_seq == self.seq + 1
+            && prev_digest.map(|digest| digest[..] == self.local_digest[..]) == Some(true)
+    }
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> MessageChains<D, I> {
+    /// Collect evidence for a mismatch of the digest claimed by `sender` for
+    /// sequence number `seq`, which must be within the locally kept range of
+    /// the pairwise hash-chain or refer to its checkpoint.
+    pub(crate) fn fork_evidence(
+        &self,
+        sender: &I,
+        seq: usize,
+        claimed_digest: &Hash<D>,
+    ) -> ForkEvidence<I> {
+        let pairwise_chain = &self.chains[sender];
+
+        // The entries leading up to the checkpoint have been compacted, so
+        // this evidence can't be verified by third parties:
+        if seq < pairwise_chain.offset {
+            let (_, checkpoint) = pairwise_chain.checkpoint.as_ref().unwrap();
+            return ForkEvidence {
+                algorithm: D::NAME.to_string(),
+                sender: sender.clone(),
+                receiver: self.own_device.clone(),
+                seq,
+                local_digest: checkpoint.digest.to_vec(),
+                claimed_digest: claimed_digest.to_vec(),
+                base: None,
+                entries: Vec::new(),
+            };
+        }
+
+        let index = seq - pairwise_chain.offset;
+
+        // Find the last entry before `seq` which has been validated by the
+        // sender, as both devices agree on it. Otherwise, fall back to the
+        // checkpoint of compacted entries (if any):
+        let base_index = pairwise_chain
+            .chain
+            .iter()
+            .take(index)
+            .rposition(|entry| entry.local_seq < pairwise_chain.validated_local_seq);
+        let first_index = base_index.map(|i| i + 1).unwrap_or(0);
+        let checkpoint_base = pairwise_chain
+            .checkpoint
+            .as_ref()
+            .map(|(checkpoint_seq, entry)| (*checkpoint_seq, entry.digest.to_vec()));
+
+        ForkEvidence {
+            algorithm: D::NAME.to_string(),
+            sender: sender.clone(),
+            receiver: self.own_device.clone(),
+            seq,
+            local_digest: pairwise_chain.chain[index].digest.to_vec(),
+            claimed_digest: claimed_digest.to_vec(),
+            base: base_index
+                .map(|i| {
+                    (
+                        pairwise_chain.offset + i,
+                        pairwise_chain.chain[i].digest.to_vec(),
+                    )
+                })
+                .or(checkpoint_base),
+            entries: pairwise_chain
+                .chain
+                .range(first_index..=index)
+                .enumerate()
+                .map(|(i, entry)| {
+                    let record = self.message_record(entry.local_seq);
+                    ForkEvidenceEntry {
+                        seq: pairwise_chain.offset + first_index + i,
+                        local_seq: entry.local_seq,
+                        recipients: record
+                            .map(|record| record.recipients.clone())
+                            .unwrap_or_default(),
+                        counters: record.and_then(|record| record.counters.clone()),
+                        version: entry.version,
+                        digest: entry.digest.to_vec(),
+                    }
+                })
+                .collect(),
+        }
+    }
+}
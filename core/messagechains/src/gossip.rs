@@ -0,0 +1,300 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{DeviceIdentifier, DigestAlgorithm, Error, Hash, MessageChains, Operation};
+
+/// A claim about the pairwise hash-chain between the sender of the claim and
+/// a third party, sent along with a validation payload in gossip mode.
+///
+/// The claim references a message shared by the sender, the recipient and
+/// the third party through its sequence number in the pairwise chain of
+/// sender and recipient. It states at which sequence number and with which
+/// digest the message is included in the sender's chain with the third
+/// party.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct GossipClaim<D: DigestAlgorithm, I: DeviceIdentifier> {
+    pub third_party: I,
+    pub seq: usize,
+    pub third_party_seq: usize,
+    pub third_party_digest: Hash<D>,
+}
+
+/// A gossip claim received from `claimer`, about its pairwise chain with the
+/// other device of the pair the claim is stored under.
+#[derive(Debug)]
+pub(crate) struct GossipRecord<D: DigestAlgorithm, I: DeviceIdentifier> {
+    pub(crate) claimer: I,
+    pub(crate) third_party_seq: usize,
+    pub(crate) digest: Hash<D>,
+}
+
+/// A [`GossipRecord`] which may not have been stored yet.
+struct GossipRecordRef<'a, D: DigestAlgorithm, I: DeviceIdentifier> {
+    claimer: &'a I,
+    third_party_seq: usize,
+    digest: &'a Hash<D>,
+}
+
+/// Gossip claims received about third-party pairs (keyed by the sorted
+/// pair), indexed by the local sequence number of the shared message.
+pub(crate) type GossipClaims<D, I> = BTreeMap<usize, GossipRecord<D, I>>;
+
+fn sorted_pair<I: DeviceIdentifier>(a: &I, b: &I) -> (I, I) {
+    if a < b {
+        (a.clone(), b.clone())
+    } else {
+        (b.clone(), a.clone())
+    }
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> MessageChains<D, I> {
+    /// Compose the gossip claims to send to `recipient`. For every other
+    /// device we share a pairwise chain with, this refers to the latest
+    /// message in that chain which `recipient` has received as well.
+    ///
+    /// Returns no claims unless gossip mode is enabled.
+    pub fn gossip_payload(&self, recipient: &I) -> Vec<GossipClaim<D, I>> {
+        let recipient_chain = match self.chains.get(recipient) {
+            Some(chain) if self.config.gossip => chain,
+            _ => return Vec::new(),
+        };
+
+        let mut claims = Vec::new();
+        for (third_party, third_party_chain) in self.chains.iter() {
+            if third_party == recipient {
+                continue;
+            }
+
+            // Find the latest message shared with the recipient. Message
+            // records are only ever trimmed from the front, so stop at the
+            // first message we don't have the recipients of:
+            let shared_entry = third_party_chain
+                .chain
+                .iter()
+                .enumerate()
+                .rev()
+                .map_while(|(i, entry)| Some((i, entry, self.message_recipients(entry.local_seq)?)))
+                .find(|(_, _, recipients)| recipients.binary_search(recipient).is_ok());
+
+            let (third_party_index, entry, _) = match shared_entry {
+                Some(shared_entry) => shared_entry,
+                None => continue,
+            };
+
+            // This message is part of our chain with the recipient, unless
+            // it has been trimmed already:
+            if let Ok(index) = recipient_chain
+                .chain
+                .binary_search_by_key(&entry.local_seq, |entry| entry.local_seq)
+            {
+                claims.push(GossipClaim {
+                    third_party: third_party.clone(),
+                    seq: recipient_chain.offset + index,
+                    third_party_seq: third_party_chain.offset + third_party_index,
+                    third_party_digest: entry.digest.clone(),
+                });
+            }
+        }
+
+        claims
+    }
+
+    /// Check gossip claims sent by `sender` against the claims previously
+    /// received from the respective third parties, about the same pairwise
+    /// chain. Two claims referring to the same message must agree on its
+    /// sequence number and digest in that chain, or else the server must
+    /// have presented different messages to the two devices.
+    ///
+    /// This should be called after the validation payload accompanying the
+    /// claims has been validated. Claims are ignored unless gossip mode is
+    /// enabled. If an error is returned, none of the claims are stored.
+    pub fn validate_gossip(
+        &mut self,
+        sender: &I,
+        claims: impl IntoIterator<Item = GossipClaim<D, I>>,
+    ) -> Result<(), Error<I>> {
+        let result = self.validate_gossip_inner(sender, claims);
+        self.observe_result(result)
+    }
+
+    fn validate_gossip_inner(
+        &mut self,
+        sender: &I,
+        claims: impl IntoIterator<Item = GossipClaim<D, I>>,
+    ) -> Result<(), Error<I>> {
+        if !self.config.gossip {
+            return Ok(());
+        }
+
+        // All claims are checked before any of them is stored:
+        let mut operations = Vec::new();
+        for claim in claims {
+            if claim.third_party == *sender || claim.third_party == self.own_device {
+                log::debug!(
+                    "validate_gossip: invariant violated - gossip claim by {:?} \
+                     about its chain with {:?}",
+                    sender,
+                    claim.third_party,
+                );
+                return Err(Error::InvalidGossipThirdParty {
+                    sender: sender.clone(),
+                    third_party: claim.third_party,
+                });
+            }
+
+            let sender_chain = self
+                .chains
+                .get(sender)
+                .ok_or_else(|| Error::UnknownValidationSender(sender.clone()))?;
+
+            // The claim must refer to a message we've received. If we have
+            // trimmed it already, we can't check the claim:
+            let end = sender_chain.offset + sender_chain.chain.len();
+            if claim.seq >= end {
+                log::debug!(
+                    "validate_gossip: invariant violated - gossip claim by {:?} \
+                     refers to unknown sequence number {}",
+                    sender,
+                    claim.seq,
+                );
+                return Err(Error::InvalidGossipSeq {
+                    sender: sender.clone(),
+                    seq: claim.seq,
+                    end,
+                });
+            } else if claim.seq < sender_chain.offset {
+                continue;
+            }
+
+            let local_seq = sender_chain.chain[claim.seq - sender_chain.offset].local_seq;
+            let recipients = match self.message_recipients(local_seq) {
+                Some(recipients) => recipients,
+                None => continue,
+            };
+
+            // From our point of view, the third party must have been a
+            // recipient of this message as well:
+            if recipients.binary_search(&claim.third_party).is_err() {
+                log::debug!(
+                    "validate_gossip: invariant violated - gossip claim by {:?} \
+                     refers to message {} not shared with {:?}",
+                    sender,
+                    local_seq,
+                    claim.third_party,
+                );
+                return Err(Error::UnsharedGossipMessage {
+                    sender: sender.clone(),
+                    third_party: claim.third_party,
+                    local_seq,
+                });
+            }
+
+            let pair = sorted_pair(sender, &claim.third_party);
+            match self.gossip_record(&operations, &pair, local_seq) {
+                Some(record) if *record.claimer != *sender => {
+                    if record.third_party_seq != claim.third_party_seq
+                        || *record.digest != claim.third_party_digest
+                    {
+                        log::debug!(
+                            "validate_gossip: fork detected between {:?} and {:?} \
+                             for message {}: ({}, {:?}) vs. ({}, {:?})",
+                            pair.0,
+                            pair.1,
+                            local_seq,
+                            record.third_party_seq,
+                            record.digest,
+                            claim.third_party_seq,
+                            claim.third_party_digest,
+                        );
+                        return Err(Error::ThirdPartyForkDetected(pair.0, pair.1));
+                    }
+
+                    // Both devices agree on their chain up to this message,
+                    // so any older claims are no longer of interest:
+                    operations.push(Operation::SettleGossipClaims { pair, local_seq });
+                }
+                Some(record) => {
+                    // The sender's chain with the third party can't have
+                    // changed since its previous claim about this message:
+                    if record.third_party_seq != claim.third_party_seq
+                        || *record.digest != claim.third_party_digest
+                    {
+                        log::debug!(
+                            "validate_gossip: conflicting gossip claims by {:?} \
+                             about message {}: ({}, {:?}) vs. ({}, {:?})",
+                            sender,
+                            local_seq,
+                            record.third_party_seq,
+                            record.digest,
+                            claim.third_party_seq,
+                            claim.third_party_digest,
+                        );
+                        return Err(Error::ConflictingGossipClaims {
+                            claimer: sender.clone(),
+                            third_party: claim.third_party,
+                            local_seq,
+                        });
+                    }
+                }
+                None => {
+                    operations.push(Operation::AddGossipClaim {
+                        pair,
+                        local_seq,
+                        claimer: sender.clone(),
+                        third_party_seq: claim.third_party_seq,
+                        digest: claim.third_party_digest,
+                    });
+                }
+            }
+        }
+
+        for operation in operations {
+            self.apply(operation);
+        }
+
+        Ok(())
+    }
+
+    /// The claim stored about message `local_seq` for `pair`, after applying
+    /// the `pending` operations of claims checked before.
+    fn gossip_record<'a>(
+        &'a self,
+        pending: &'a [Operation<D, I>],
+        pair: &(I, I),
+        local_seq: usize,
+    ) -> Option<GossipRecordRef<'a, D, I>> {
+        for operation in pending.iter().rev() {
+            match operation {
+                Operation::AddGossipClaim {
+                    pair: added_pair,
+                    local_seq: added_local_seq,
+                    claimer,
+                    third_party_seq,
+                    digest,
+                } if added_pair == pair && *added_local_seq == local_seq => {
+                    return Some(GossipRecordRef {
+                        claimer,
+                        third_party_seq: *third_party_seq,
+                        digest,
+                    });
+                }
+                Operation::SettleGossipClaims {
+                    pair: settled_pair,
+                    local_seq: settled_local_seq,
+                } if settled_pair == pair && local_seq <= *settled_local_seq => return None,
+                _ => (),
+            }
+        }
+
+        self.gossip_claims
+            .get(pair)
+            .and_then(|pair_claims| pair_claims.get(&local_seq))
+            .map(|record| GossipRecordRef {
+                claimer: &record.claimer,
+                third_party_seq: record.third_party_seq,
+                digest: &record.digest,
+            })
+    }
+}
@@ -0,0 +1,295 @@
+use std::borrow::Borrow;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{DeviceIdentifier, DigestAlgorithm, Hash};
+
+/// Format of the digests of hash-chain entries. Both ends of a pairwise
+/// hash-chain must use the same format for each entry, but the format may
+/// change from one entry to the next.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(into = "u8", try_from = "u8")]
+pub enum DigestVersion {
+    /// Each entry digest covers the previous entry digest, the recipients
+    /// and the message contents (see [`hash_message`]). Inserting a message
+    /// hashes its contents once for every recipient.
+    V1,
+    /// The recipients and message contents are digested once (see
+    /// [`digest_message`]). Each entry digest then only covers the previous
+    /// entry digest and this message digest (see [`hash_chain_entry`]).
+    V2,
+    /// Like [`DigestVersion::V2`], but using an unambiguous encoding: all
+    /// variable-length fields are length-prefixed, and every hash input
+    /// starts with a domain-separation label and the version byte (see
+    /// [`canonical_digest_message`] and [`canonical_hash_chain_entry`]).
+    #[default]
+    V3,
+}
+
+impl From<DigestVersion> for u8 {
+    fn from(version: DigestVersion) -> u8 {
+        match version {
+            DigestVersion::V1 => 1,
+            DigestVersion::V2 => 2,
+            DigestVersion::V3 => 3,
+        }
+    }
+}
+
+impl TryFrom<u8> for DigestVersion {
+    type Error = String;
+
+    fn try_from(version: u8) -> Result<Self, Self::Error> {
+        match version {
+            1 => Ok(DigestVersion::V1),
+            2 => Ok(DigestVersion::V2),
+            3 => Ok(DigestVersion::V3),
+            _ => Err(format!("unsupported digest version {}", version)),
+        }
+    }
+}
+
+/// Domain-separation label prepended to all [`DigestVersion::V3`] hash inputs.
+pub const DOMAIN_LABEL: &[u8] = b"frida-messagechains";
+
+fn update_field<D: DigestAlgorithm>(hasher: &mut D, field: &[u8]) {
+    hasher.update(u64::to_be_bytes(field.len() as u64));
+    hasher.update(field);
+}
+
+fn update_canonical_header<D: DigestAlgorithm>(hasher: &mut D, context: &[u8]) {
+    update_field(hasher, DOMAIN_LABEL);
+    hasher.update([u8::from(DigestVersion::V3)]);
+    update_field(hasher, context);
+}
+
+fn update_prev_digest<D: DigestAlgorithm>(hasher: &mut D, prev_digest: Option<&Hash<D>>) {
+    if let Some(digest) = prev_digest {
+        hasher.update(b"prev");
+        hasher.update(digest);
+    } else {
+        hasher.update(b"no_prev");
+    }
+}
+
+fn update_recipients<D: DigestAlgorithm, I: DeviceIdentifier, BD: Borrow<I>>(
+    hasher: &mut D,
+    recipients: &mut impl Iterator<Item = BD>,
+) {
+    for (i, r) in recipients.enumerate() {
+        hasher.update(u64::to_be_bytes(i as u64));
+        hasher.update(r.borrow().canonical_bytes());
+    }
+}
+
+/// Compute the digest of a [`DigestVersion::V1`] hash-chain entry for a
+/// message, given the digest of the preceding entry (if any) and the
+/// message's sorted recipients.
+pub fn hash_message<D: DigestAlgorithm, I: DeviceIdentifier, BD: Borrow<I>>(
+    prev_digest: Option<&Hash<D>>,
+    recipients: &mut impl Iterator<Item = BD>,
+    message: &[u8],
+) -> Hash<D> {
+    let mut hasher = D::new();
+
+    update_prev_digest(&mut hasher, prev_digest);
+    update_recipients::<D, I, BD>(&mut hasher, recipients);
+
+    hasher.update(b"message");
+    hasher.update(message);
+
+    hasher.finalize()
+}
+
+/// Compute the digest of a message and its sorted recipients, which is
+/// included in all [`DigestVersion::V2`] hash-chain entries of this message.
+pub fn digest_message<D: DigestAlgorithm, I: DeviceIdentifier, BD: Borrow<I>>(
+    recipients: &mut impl Iterator<Item = BD>,
+    message: &[u8],
+) -> Hash<D> {
+    let mut hasher = D::new();
+
+    hasher.update(b"recipients");
+    update_recipients::<D, I, BD>(&mut hasher, recipients);
+
+    hasher.update(b"message");
+    hasher.update(message);
+
+    hasher.finalize()
+}
+
+/// Compute the digest of a [`DigestVersion::V2`] hash-chain entry, given the
+/// digest of the preceding entry (if any) and the message digest as computed
+/// by [`digest_message`].
+pub fn hash_chain_entry<D: DigestAlgorithm>(
+    prev_digest: Option<&Hash<D>>,
+    message_digest: &Hash<D>,
+) -> Hash<D> {
+    let mut hasher = D::new();
+
+    update_prev_digest(&mut hasher, prev_digest);
+
+    hasher.update(b"message_digest");
+    hasher.update(message_digest);
+
+    hasher.finalize()
+}
+
+/// Compute the digest of a message and its sorted recipients, which is
+/// included in all [`DigestVersion::V3`] hash-chain entries of this message.
+///
+/// The hash input consists of the length-prefixed [`DOMAIN_LABEL`], the
+/// version byte and the length-prefixed context `b"message"`, followed by the
+/// number of recipients, each recipient's length-prefixed canonical bytes and
+/// the length-prefixed message. All lengths and counts are encoded as
+/// big-endian u64.
+pub fn canonical_digest_message<D: DigestAlgorithm, I: DeviceIdentifier, BD: Borrow<I>>(
+    recipients: &mut impl ExactSizeIterator<Item = BD>,
+    message: &[u8],
+) -> Hash<D> {
+    let mut hasher = D::new();
+
+    update_canonical_header(&mut hasher, b"message");
+    hasher.update(u64::to_be_bytes(recipients.len() as u64));
+    for r in recipients {
+        update_field(&mut hasher, &r.borrow().canonical_bytes());
+    }
+    update_field(&mut hasher, message);
+
+    hasher.finalize()
+}
+
+/// Like [`canonical_digest_message`], but additionally covering the sender's
+/// per-pair counters, one for each recipient. The context is
+/// `b"counted_message"`, and each recipient's canonical bytes are followed by
+/// its counter, encoded as big-endian u64.
+///
+/// # Panics
+///
+/// Panics if the number of counters does not match the number of
+/// recipients.
+pub fn canonical_digest_counted_message<D: DigestAlgorithm, I: DeviceIdentifier, BD: Borrow<I>>(
+    recipients: &mut impl ExactSizeIterator<Item = BD>,
+    counters: &[u64],
+    message: &[u8],
+) -> Hash<D> {
+    assert!(recipients.len() == counters.len());
+    let mut hasher = D::new();
+
+    update_canonical_header(&mut hasher, b"counted_message");
+    hasher.update(u64::to_be_bytes(recipients.len() as u64));
+    for (r, counter) in recipients.zip(counters) {
+        update_field(&mut hasher, &r.borrow().canonical_bytes());
+        hasher.update(u64::to_be_bytes(*counter));
+    }
+    update_field(&mut hasher, message);
+
+    hasher.finalize()
+}
+
+/// Compute the digest of a [`DigestVersion::V3`] hash-chain entry, given the
+/// digest of the preceding entry (if any) and the message digest as computed
+/// by [`canonical_digest_message`].
+///
+/// The hash input consists of the header as described for
+/// [`canonical_digest_message`] with the context `b"entry"`, followed by a
+/// single byte indicating whether there is a preceding entry, its
+/// length-prefixed digest (if any) and the length-prefixed message digest.
+pub fn canonical_hash_chain_entry<D: DigestAlgorithm>(
+    prev_digest: Option<&Hash<D>>,
+    message_digest: &Hash<D>,
+) -> Hash<D> {
+    let mut hasher = D::new();
+
+    update_canonical_header(&mut hasher, b"entry");
+    if let Some(digest) = prev_digest {
+        hasher.update([1]);
+        update_field(&mut hasher, digest);
+    } else {
+        hasher.update([0]);
+    }
+    update_field(&mut hasher, message_digest);
+
+    hasher.finalize()
+}
+
+/// Computes the hash-chain entry digests of a single message. For
+/// [`DigestVersion::V2`] and [`DigestVersion::V3`], the message digest is
+/// computed only once per version, on demand.
+///
+/// Per-pair counters can only be included with [`DigestVersion::V3`], and
+/// are ignored otherwise.
+pub(crate) struct EntryHasher<'a, D: DigestAlgorithm, BD> {
+    recipients: &'a [BD],
+    counters: Option<&'a [u64]>,
+    message: &'a [u8],
+    message_digest: Option<(DigestVersion, Hash<D>)>,
+}
+
+impl<'a, D: DigestAlgorithm, BD> EntryHasher<'a, D, BD> {
+    pub fn new(recipients: &'a [BD], message: &'a [u8]) -> Self {
+        Self::with_counters(recipients, None, message)
+    }
+
+    pub fn with_counters(
+        recipients: &'a [BD],
+        counters: Option<&'a [u64]>,
+        message: &'a [u8],
+    ) -> Self {
+        EntryHasher {
+            recipients,
+            counters,
+            message,
+            message_digest: None,
+        }
+    }
+
+    pub fn entry_digest<I: DeviceIdentifier>(
+        &mut self,
+        version: DigestVersion,
+        prev_digest: Option<&Hash<D>>,
+    ) -> Hash<D>
+    where
+        BD: Borrow<I>,
+    {
+        match version {
+            DigestVersion::V1 => hash_message::<D, I, _>(
+                prev_digest,
+                &mut self.recipients.iter().map(Borrow::<I>::borrow),
+                self.message,
+            ),
+            DigestVersion::V2 => {
+                hash_chain_entry::<D>(prev_digest, self.message_digest::<I>(version))
+            }
+            DigestVersion::V3 => {
+                canonical_hash_chain_entry::<D>(prev_digest, self.message_digest::<I>(version))
+            }
+        }
+    }
+
+    fn message_digest<I: DeviceIdentifier>(&mut self, version: DigestVersion) -> &Hash<D>
+    where
+        BD: Borrow<I>,
+    {
+        let (recipients, message) = (self.recipients, self.message);
+        let recipients = &mut recipients.iter().map(Borrow::<I>::borrow);
+
+        if !matches!(self.message_digest, Some((cached, _)) if cached == version) {
+            let digest = match version {
+                // V1 entries don't include a separate message digest:
+                DigestVersion::V1 | DigestVersion::V2 => {
+                    digest_message::<D, I, _>(recipients, message)
+                }
+                DigestVersion::V3 => match self.counters {
+                    Some(counters) => {
+                        canonical_digest_counted_message::<D, I, _>(recipients, counters, message)
+                    }
+                    None => canonical_digest_message::<D, I, _>(recipients, message),
+                },
+            };
+            self.message_digest = Some((version, digest));
+        }
+
+        &self.message_digest.as_ref().unwrap().1
+    }
+}
@@ -1,124 +1,498 @@
-use log;
+use std::borrow::Cow;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::VecDeque;
 use std::fmt::Debug;
 
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
+mod algorithm;
+pub use algorithm::DigestAlgorithm;
+
+mod error;
+pub use error::{DumpError, Error, StoreError};
+
+mod epoch;
+pub use epoch::EpochCounter;
+
+mod evidence;
+pub use evidence::{ForkEvidence, ForkEvidenceEntry};
+
+mod hashing;
+use hashing::EntryHasher;
+pub use hashing::{
+    canonical_digest_counted_message, canonical_digest_message, canonical_hash_chain_entry,
+    digest_message, hash_chain_entry, hash_message, DigestVersion, DOMAIN_LABEL,
+};
+
+mod gossip;
+pub use gossip::GossipClaim;
+
+mod counters;
+pub use counters::OutgoingMessage;
+
+mod limits;
+pub use limits::{DeviceMemoryUsage, LimitPolicy, MemoryUsage};
+
+mod membership;
+pub use membership::MembershipOracle;
+
+mod retire;
+pub use retire::RetirementReport;
+
+mod observer;
+pub use observer::{Event, Observer};
+
+mod pending;
+pub use pending::PendingMessageInfo;
+
+mod quarantine;
+pub use quarantine::{PeerHealth, DEFAULT_QUARANTINE_THRESHOLD};
+
+mod receive;
+pub use receive::{DuplicatePolicy, Envelope, ReceiveOutcome};
+
+mod schema;
+pub use schema::SCHEMA_VERSION;
+
+mod seal;
+pub use seal::SealingKey;
+
+mod status;
+pub use status::{MessageStatus, RecipientStatus, ValidationStatus};
+
+mod store;
+pub use store::{
+    ChainStore, FileStore, MemoryStore, Operation, StoredState, DEFAULT_SNAPSHOT_INTERVAL,
+};
+
+mod suspect;
+pub use suspect::{SuspectMessage, SuspectReport};
+
 #[cfg(target_arch = "wasm32")]
 pub mod wasm_wrapper;
 
+/// Identifier of a device. Recipient lists must be sorted according to its
+/// [`Ord`] implementation, and recipients are included in the message
+/// digests through their canonical byte encoding.
+pub trait DeviceIdentifier:
+    Ord + std::hash::Hash + Clone + Debug + Serialize + DeserializeOwned
+{
+    fn canonical_bytes(&self) -> Cow<'_, [u8]>;
+}
+
+impl DeviceIdentifier for String {
+    fn canonical_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.as_bytes())
+    }
+}
+
+/// Raw 32-byte public keys, such as Curve25519 identity keys.
+impl DeviceIdentifier for [u8; 32] {
+    fn canonical_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self[..])
+    }
+}
+
+/// The default device identifier type.
 pub type DeviceId = String;
-pub type Hash = [u8; 32];
+pub type Hash<D = sha2::Sha256> = digest::Output<D>;
+
+pub type Sha256MessageChains<I = DeviceId> = MessageChains<sha2::Sha256, I>;
+pub type Sha512_256MessageChains<I = DeviceId> = MessageChains<sha2::Sha512_256, I>;
+pub type Blake3MessageChains<I = DeviceId> = MessageChains<blake3::Hasher, I>;
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ChainEntry {
+#[derive(Debug)]
+struct ChainEntry<D: DigestAlgorithm> {
     local_seq: usize,
-    digest: Hash,
+    version: DigestVersion,
+    digest: Hash<D>,
+}
+
+fn legacy_digest_version() -> DigestVersion {
+    DigestVersion::V1
+}
+
+/// An entry of the hash-chain over our own messages which have been sent, but
+/// not yet received back from the server.
+#[derive(Debug)]
+struct PendingMessage<D: DigestAlgorithm, I: DeviceIdentifier> {
+    version: DigestVersion,
+    digest: Hash<D>,
+    // Tick at which the message was sent (or last retried):
+    tick: u64,
+    // Recipients whose per-pair counters have been incremented for this
+    // message, if it was sent through `send_counted_message`:
+    counted_recipients: Option<Vec<I>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct DeviceState {
+#[derive(Debug)]
+struct DeviceState<D: DigestAlgorithm> {
     offset: usize,
+    // This can be initialized to 0 as it points to the first
+    // *non-validated* local sequence number:
     validated_local_seq: usize,
-    chain: VecDeque<ChainEntry>,
+    chain: VecDeque<ChainEntry<D>>,
+    // Highest digest version this device is known to support:
+    digest_version: Option<DigestVersion>,
+    // Per-pair counters of the next counted message sent to and expected
+    // from this device:
+    sent_counter: u64,
+    received_counter: u64,
+    // Entries dropped from the front of the chain due to memory limits,
+    // starting at this sequence number, can no longer be validated. The
+    // last compacted entry is kept as a checkpoint:
+    unverifiable_from: Option<usize>,
+    checkpoint: Option<(usize, ChainEntry<D>)>,
 }
 
-impl Default for DeviceState {
-    fn default() -> DeviceState {
+impl<D: DigestAlgorithm> Default for DeviceState<D> {
+    fn default() -> Self {
         DeviceState {
             offset: 0,
-            // We can initialize this to 0 as this points to the first
-            // *non-validated* local sequence number:
             validated_local_seq: 0,
             chain: VecDeque::new(),
+            digest_version: None,
+            sent_counter: 0,
+            received_counter: 0,
+            unverifiable_from: None,
+            checkpoint: None,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct MessageChains {
-    own_device: DeviceId,
-    pending_messages: VecDeque<Hash>,
-    chains: HashMap<DeviceId, DeviceState>,
+/// An entry of a pairwise chain referenced by a valid validation payload.
+struct ValidatedEntry {
     local_seq: usize,
+    // Index of the entry in the chain, unless it is the checkpoint:
+    index: Option<usize>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Error {
-    TooFewRecipients,
-    MissingSelfRecipient,
-    InvalidRecipientsOrder,
-    InvariantViolated,
-    OwnMessageInvalidReordered,
-    UnknownDevice,
+/// A received message which may be inserted into the [`MessageChains`] without
+/// any further checks.
+struct PreparedInsert<BD> {
+    recipients: Vec<BD>,
+    counters: Option<Vec<u64>>,
+    version: DigestVersion,
+    negotiated: bool,
+    own_message: bool,
 }
 
-fn hash_message<'a, BD: std::borrow::Borrow<DeviceId>>(
-    prev_digest: Option<&Hash>,
-    recipients: &mut impl Iterator<Item = BD>,
-    message: &[u8],
-) -> Hash {
-    use sha2::Digest;
+/// Recipients of a message inserted into the [`MessageChains`], kept
+/// to determine which of them have validated it.
+#[derive(Debug)]
+struct MessageRecord<I: DeviceIdentifier> {
+    recipients: Vec<I>,
+    // The sender's per-pair counters, if any:
+    counters: Option<Vec<u64>>,
+}
 
-    let mut hasher = sha2::Sha256::new();
+/// Configuration options of a [`MessageChains`] instance.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Config {
+    /// Require every message from a peer which has previously sent us a
+    /// validation payload to carry one as well. Once a peer has sent a
+    /// validation payload, its pairwise hash-chain with us can never become
+    /// empty again, so a message without one indicates that the payload has
+    /// been stripped (e.g., by the server).
+    pub strict_validation_payloads: bool,
+    /// Exchange claims about pairwise chains with third parties, to detect
+    /// forks between other devices based on the messages shared with them
+    /// (see [`MessageChains::gossip_payload`]).
+    pub gossip: bool,
+    /// Highest format of the digests of hash-chain entries supported by this
+    /// device, which should be advertised to its peers. Messages are sent
+    /// with the highest format supported by all of their recipients (see
+    /// [`MessageChains::send_message`]). Entries inserted previously keep
+    /// their format.
+    ///
+    /// Messages inserted through [`MessageChains::insert_message`] are
+    /// assumed to use this format, which requires all devices to use the same
+    /// one. Use [`MessageChains::insert_versioned_message`] instead to
+    /// negotiate the format.
+    ///
+    /// State dumped before digests were versioned continues to use
+    /// [`DigestVersion::V1`], as its peers do.
+    #[serde(default = "legacy_digest_version")]
+    pub digest_version: DigestVersion,
+    /// Format assumed to be supported by peers which have not advertised
+    /// one (see [`MessageChains::set_peer_digest_version`]). If `None`,
+    /// peers are assumed to support [`Config::digest_version`]. While
+    /// rolling out a new format, this should be set to the previous one.
+    #[serde(default)]
+    pub default_peer_digest_version: Option<DigestVersion>,
+    /// How to handle messages passed to [`MessageChains::receive`] which
+    /// have been delivered before.
+    #[serde(default)]
+    pub duplicate_policy: DuplicatePolicy,
+    /// Maximum number of entries kept in the pairwise chain with any single
+    /// device.
+    #[serde(default)]
+    pub max_chain_len: Option<usize>,
+    /// Maximum number of entries kept across all pairwise chains.
+    #[serde(default)]
+    pub max_total_chain_len: Option<usize>,
+    /// How to handle inserting a message which exceeds one of the above
+    /// limits. They also bound the number of message records kept (see
+    /// [`MessageChains::message_status`]) to the number of entries all
+    /// pairwise chains may hold together.
+    #[serde(default)]
+    pub limit_policy: LimitPolicy,
+    /// Number of violations after which a peer is quarantined (see
+    /// [`PeerHealth`]), at least two. If `None`,
+    /// [`DEFAULT_QUARANTINE_THRESHOLD`] is used.
+    #[serde(default)]
+    pub quarantine_threshold: Option<usize>,
+}
 
-    if let Some(digest) = prev_digest {
-        hasher.update(b"prev");
-        hasher.update(digest);
-    } else {
-        hasher.update(b"no_prev");
-    }
+/// Serialized in a versioned schema, see [`SCHEMA_VERSION`]. Alternatively,
+/// state can be persisted incrementally to a [`ChainStore`].
+#[derive(Debug)]
+pub struct MessageChains<D: DigestAlgorithm = sha2::Sha256, I: DeviceIdentifier = DeviceId> {
+    own_device: I,
+    config: Config,
+    pending_messages: VecDeque<PendingMessage<D, I>>,
+    // Identifier of the first pending message (following the base entry),
+    // and the current tick as set by the application:
+    pending_offset: u64,
+    tick: u64,
+    chains: HashMap<I, DeviceState<D>>,
+    local_seq: usize,
+    // Recipients of all inserted messages starting at local sequence
+    // number `messages_offset`. Records are dropped from the front
+    // once they have been validated by all of their recipients:
+    messages_offset: usize,
+    messages: VecDeque<MessageRecord<I>>,
+    // Messages before this local sequence number may have been dropped
+    // without being validated by all of their recipients, as they have been
+    // inserted before message records were kept, or their records have been
+    // evicted due to the configured limits:
+    messages_known_from: usize,
+    gossip_claims: HashMap<(I, I), gossip::GossipClaims<D, I>>,
+    // Server sequence number and per-sender counters of the latest
+    // messages received, to detect redelivered messages:
+    last_server_seq: Option<u64>,
+    sender_counters: HashMap<I, u64>,
+    // Counter of the next counted message sent to ourselves:
+    own_counter: u64,
+    // Devices which have been retired, and may not be recipients of any
+    // inserted messages:
+    retired: BTreeSet<I>,
+    // Peers which are not healthy, after they have been involved in
+    // invariant violations:
+    peer_health: HashMap<I, PeerHealth>,
+    // Number of violations recorded for each peer which is not healthy:
+    peer_violations: HashMap<I, usize>,
+    // Incremented whenever the state is about to be persisted, to detect
+    // rollbacks to older state (see `EpochCounter`):
+    epoch: u64,
+    membership: membership::Membership<I>,
+    observer: observer::Observation<I>,
+    store: store::Persistence<D, I>,
+}
 
-    for (i, r) in recipients.enumerate() {
-        hasher.update(&u64::to_be_bytes(i as u64));
-        hasher.update(r.borrow().as_bytes());
+impl<D: DigestAlgorithm, I: DeviceIdentifier> MessageChains<D, I> {
+    pub fn new(own_device: I) -> Self {
+        Self::with_config(own_device, Config::default())
     }
 
-    hasher.update(b"message");
-    hasher.update(message);
-
-    let mut digest: [u8; 32] = [0; 32];
-    hasher.finalize_into_reset((&mut digest).into());
-    digest
-}
-
-// TODO: Implement quorum for message.
-impl MessageChains {
-    pub fn new(own_device: DeviceId) -> Self {
+    pub fn with_config(own_device: I, config: Config) -> Self {
         let mut pending_messages = VecDeque::new();
-        pending_messages.push_back(Hash::default());
+        pending_messages.push_back(PendingMessage {
+            version: config.digest_version,
+            digest: Hash::<D>::default(),
+            tick: 0,
+            counted_recipients: None,
+        });
 
         MessageChains {
             own_device,
+            config,
             pending_messages,
+            pending_offset: 0,
+            tick: 0,
             chains: HashMap::new(),
             local_seq: 0,
+            messages_offset: 0,
+            messages: VecDeque::new(),
+            messages_known_from: 0,
+            gossip_claims: HashMap::new(),
+            last_server_seq: None,
+            sender_counters: HashMap::new(),
+            own_counter: 0,
+            retired: BTreeSet::new(),
+            peer_health: HashMap::new(),
+            peer_violations: HashMap::new(),
+            epoch: 0,
+            membership: membership::Membership::default(),
+            observer: observer::Observation::default(),
+            store: store::Persistence::default(),
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn config_mut(&mut self) -> &mut Config {
+        &mut self.config
+    }
+
+    /// Record the highest digest version supported by `peer`, as advertised
+    /// by it. Messages subsequently received from `peer` with a higher
+    /// version raise it again.
+    ///
+    /// This is ignored for devices which are unknown to the membership
+    /// oracle or retired.
+    pub fn set_peer_digest_version(&mut self, peer: &I, version: DigestVersion) {
+        if *peer == self.own_device {
+            return;
+        }
+        if let Err(e) = self.admit_device(peer) {
+            log::debug!("set_peer_digest_version: {}", e);
+            return;
+        }
+
+        self.apply(Operation::SetPeerDigestVersion {
+            device: peer.clone(),
+            version: Some(version),
+        });
+    }
+
+    /// Check that a pairwise chain may be kept with `device`, i.e., that it
+    /// is neither retired nor unknown to the membership oracle.
+    pub(crate) fn check_admission(&self, device: &I) -> Result<(), Error<I>> {
+        if self.chains.contains_key(device) {
+            return Ok(());
+        }
+        if self.retired.contains(device) {
+            log::debug!("Retired recipient: {:?}", device);
+            return Err(Error::RetiredRecipient(device.clone()));
+        }
+        if !self.membership.is_known(device) {
+            log::debug!("Unknown recipient: {:?}", device);
+            return Err(Error::UnknownRecipient(device.clone()));
+        }
+
+        Ok(())
+    }
+
+    /// Start the pairwise chain with `device` before any message has been
+    /// shared with it, if it may be kept.
+    pub(crate) fn admit_device(&mut self, device: &I) -> Result<(), Error<I>> {
+        self.check_admission(device)?;
+        if !self.chains.contains_key(device) {
+            self.apply(Operation::AddChain {
+                device: device.clone(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// The highest digest version supported by `peer`, as far as we know.
+    pub fn peer_digest_version(&self, peer: &I) -> DigestVersion {
+        if *peer == self.own_device {
+            return self.config.digest_version;
         }
+
+        self.chains
+            .get(peer)
+            .and_then(|chain| chain.digest_version)
+            .or(self.config.default_peer_digest_version)
+            .unwrap_or(self.config.digest_version)
     }
 
-    pub fn send_message<BD: std::borrow::Borrow<DeviceId>>(
+    /// Send a message to the given sorted recipients. Returns the digest
+    /// version chosen for this message, the highest one supported by all of
+    /// its recipients, which must be sent along with the message.
+    ///
+    /// Messages to quarantined peers are refused with
+    /// [`Error::PeerQuarantined`] (see [`PeerHealth`]). Like for received
+    /// messages, recipients which are retired or unknown to the membership
+    /// oracle are refused as well.
+    pub fn send_message<BD: std::borrow::Borrow<I>>(
         &mut self,
         message: &[u8],
-        mut recipients: impl Iterator<Item = BD>,
-    ) {
-        let message_hash_entry = hash_message(
-            Some(self.pending_messages.back().unwrap()),
-            &mut recipients,
-            message,
-        );
+        recipients: impl Iterator<Item = BD>,
+    ) -> Result<DigestVersion, Error<I>> {
+        let recipients_vec: Vec<BD> = recipients.collect();
+        self.check_quarantine(recipients_vec.iter().map(|r| r.borrow()))?;
+        for r in recipients_vec.iter().map(|r| r.borrow()) {
+            if *r != self.own_device {
+                self.check_admission(r)?;
+            }
+        }
+        let version = recipients_vec
+            .iter()
+            .map(|r| self.peer_digest_version(r.borrow()))
+            .fold(self.config.digest_version, std::cmp::min);
+        let digest = EntryHasher::<D, _>::new(&recipients_vec, message)
+            .entry_digest::<I>(version, Some(&self.pending_messages.back().unwrap().digest));
+
+        self.push_pending(version, digest, None);
+
+        Ok(version)
+    }
+
+    /// Insert a message received from the server, assuming it uses
+    /// [`Config::digest_version`] (unless sent by this device).
+    ///
+    /// Without a server sequence number or per-pair counters, a message
+    /// delivered before can't be told apart from a new one with the same
+    /// contents, and is inserted again. If the server may redeliver
+    /// messages, use [`MessageChains::receive`] instead.
+    pub fn insert_message<BD: std::borrow::Borrow<I>>(
+        &mut self,
+        sender: &I,
+        message: &[u8],
+        recipients: impl Iterator<Item = BD>,
+    ) -> Result<usize, Error<I>> {
+        self.insert_message_inner(sender, message, recipients, None)
+    }
 
-        self.pending_messages.push_back(message_hash_entry);
+    /// Insert a message received from the server, which its sender has
+    /// hashed using the given digest version. This also records that the
+    /// sender supports this version.
+    pub fn insert_versioned_message<BD: std::borrow::Borrow<I>>(
+        &mut self,
+        sender: &I,
+        message: &[u8],
+        recipients: impl Iterator<Item = BD>,
+        version: DigestVersion,
+    ) -> Result<usize, Error<I>> {
+        self.insert_message_inner(sender, message, recipients, Some(version))
     }
 
-    pub fn insert_message<BD: std::borrow::Borrow<DeviceId>>(
+    fn insert_message_inner<BD: std::borrow::Borrow<I>>(
         &mut self,
-        sender: &DeviceId,
+        sender: &I,
+        message: &[u8],
+        recipients: impl Iterator<Item = BD>,
+        version: Option<DigestVersion>,
+    ) -> Result<usize, Error<I>> {
+        let result = self
+            .prepare_insert(sender, message, recipients, version, None)
+            .map(|prepared| self.apply_insert(sender, message, prepared));
+        self.observe_result(result)
+    }
+
+    /// Check whether a received message can be inserted, without modifying
+    /// any state.
+    fn prepare_insert<BD: std::borrow::Borrow<I>>(
+        &self,
+        sender: &I,
         message: &[u8],
-        mut recipients: impl Iterator<Item = BD>,
-    ) -> Result<usize, Error> {
+        recipients: impl Iterator<Item = BD>,
+        version: Option<DigestVersion>,
+        counters: Option<&[u64]>,
+    ) -> Result<PreparedInsert<BD>, Error<I>> {
         use std::borrow::Borrow;
 
+        self.check_quarantine([sender])?;
+        let own_message = *sender == self.own_device;
+
         // Validate that the recipients list is sorted as defined by
         // the [`Ord`] trait, as well as that we've seen our own
         // device as part of the recipients.
@@ -127,42 +501,49 @@ impl MessageChains {
         // an intermediate vector. This could potentially be
         // optimized.
         let mut recipients_vec: Vec<BD> = Vec::new();
-        let (recipients_count, acc) = recipients.try_fold(
-            (0, None),
-            |(count, acc): (usize, Option<(DeviceId, bool)>), r| {
-                let new_acc = if let Some((prev_recipient, seen_self)) = acc {
-                    if prev_recipient >= *r.borrow() {
-                        println!(
-                            "Invalid recipients order: {:?} >= {:?}",
-                            prev_recipient,
-                            *r.borrow()
-                        );
-                        Err(Error::InvalidRecipientsOrder)?
-                    } else {
-                        Some((
-                            r.borrow().clone(),
-                            seen_self || *r.borrow() == self.own_device,
-                        ))
-                    }
-                } else {
-                    Some((r.borrow().clone(), *r.borrow() == self.own_device))
-                };
+        let mut seen_self = false;
+        for r in recipients {
+            if let Some(prev_recipient) = recipients_vec.last() {
+                if Borrow::<I>::borrow(prev_recipient) >= r.borrow() {
+                    log::debug!(
+                        "Invalid recipients order: {:?} >= {:?}",
+                        Borrow::<I>::borrow(prev_recipient),
+                        r.borrow()
+                    );
+                    return Err(Error::InvalidRecipientsOrder {
+                        previous: Borrow::<I>::borrow(prev_recipient).clone(),
+                        next: r.borrow().clone(),
+                    });
+                }
+            }
 
-                recipients_vec.push(r);
+            // Our own messages may have been sent before the recipient was
+            // retired:
+            if !own_message && self.retired.contains(r.borrow()) {
+                log::debug!("Retired recipient: {:?}", r.borrow());
+                return Err(Error::RetiredRecipient(r.borrow().clone()));
+            }
+            self.check_quarantine([r.borrow()])?;
+
+            // Don't create chains for devices we don't know about, unless we
+            // have admitted them when sending the message:
+            if !own_message
+                && *r.borrow() != self.own_device
+                && !self.membership.is_known(r.borrow())
+            {
+                log::debug!("Unknown recipient: {:?}", r.borrow());
+                return Err(Error::UnknownRecipient(r.borrow().clone()));
+            }
 
-                Ok((count + 1, new_acc))
-            },
-        )?;
+            seen_self = seen_self || *r.borrow() == self.own_device;
+            recipients_vec.push(r);
+        }
 
         // The message must go to at least one recipient (ourselves):
-        if recipients_count < 1 {
+        if recipients_vec.is_empty() {
             return Err(Error::TooFewRecipients);
         }
 
-        // Now that we are sure to have executed the fold lambda
-        // twice, the accumulator must have a non-None value:
-        let (_, seen_self) = acc.unwrap();
-
         // Our own device ID was not found in the recipient list, this
         // is invalid:
         if !seen_self {
@@ -173,116 +554,421 @@ impl MessageChains {
         // head of the pending_messages queue. If it does not, the
         // server must have reordered it or changed its contents or
         // recipients:
-        if *sender == self.own_device {
+        let negotiated = version.is_some();
+        let mut version = version.unwrap_or(self.config.digest_version);
+        if own_message {
             // We must have at least two elements in the VecDeque: the
             // base hash and the resulting (expected) message hash.
             let mut pending_messages_iter = self.pending_messages.iter();
             let base_hash = pending_messages_iter.next().unwrap();
             let expected_hash = pending_messages_iter
                 .next()
-                .ok_or(Error::OwnMessageInvalidReordered)?;
+                .ok_or(Error::OwnMessageInvalidReordered { pending_id: None })?;
 
-            let calculated_hash = hash_message(
-                Some(base_hash),
-                &mut recipients_vec.iter().map(|r| r.borrow()),
-                message,
-            );
+            // Counters must only be attached to a counted message, one for
+            // each recipient, before they can be hashed:
+            if counters.is_some() != expected_hash.counted_recipients.is_some() {
+                return Err(Error::OwnMessageInvalidReordered {
+                    pending_id: Some(self.pending_offset),
+                });
+            }
+            if let Some(counters) = counters {
+                if counters.len() != recipients_vec.len() {
+                    log::debug!(
+                        "prepare_insert: invariant violated - {} counters for {} \
+                         recipients of own message",
+                        counters.len(),
+                        recipients_vec.len(),
+                    );
+                    return Err(Error::InvalidCounters {
+                        sender: sender.clone(),
+                        recipients: recipients_vec.len(),
+                        counters: counters.len(),
+                        version: expected_hash.version,
+                    });
+                }
+            }
 
-            if *expected_hash != calculated_hash {
-                return Err(Error::OwnMessageInvalidReordered);
+            // The expected hash has been calculated with the digest
+            // version in effect when sending the message:
+            let calculated_hash =
+                EntryHasher::<D, _>::with_counters(&recipients_vec, counters, message)
+                    .entry_digest::<I>(expected_hash.version, Some(&base_hash.digest));
+
+            if expected_hash.digest != calculated_hash {
+                return Err(Error::OwnMessageInvalidReordered {
+                    pending_id: Some(self.pending_offset),
+                });
             }
 
-            self.pending_messages.pop_front();
+            version = expected_hash.version;
+        } else if let Some(counters) = counters {
+            // Any gap in the counters indicates that the server has dropped
+            // or reordered messages of the sender:
+            self.check_counters(sender, &recipients_vec, counters, version)?;
+        }
+
+        self.check_limits(&recipients_vec)?;
+
+        Ok(PreparedInsert {
+            recipients: recipients_vec,
+            counters: counters.map(<[u64]>::to_vec),
+            version,
+            negotiated,
+            own_message,
+        })
+    }
+
+    /// Insert a message which has passed [`MessageChains::prepare_insert`].
+    fn apply_insert<BD: std::borrow::Borrow<I>>(
+        &mut self,
+        sender: &I,
+        message: &[u8],
+        prepared: PreparedInsert<BD>,
+    ) -> usize {
+        use std::borrow::Borrow;
+
+        let PreparedInsert {
+            recipients: recipients_vec,
+            counters,
+            version,
+            negotiated,
+            own_message,
+        } = prepared;
+
+        // The message has been checked against the head of the
+        // pending_messages queue:
+        if own_message {
+            let id = self.pending_offset;
+            self.apply(Operation::PopPending);
+            self.emit(|| Event::PendingConfirmed { id });
         }
 
         // Assign this message a sequence number in the device-global
-        // sequence space:
+        // sequence space (taken by the message record below):
         let local_seq = self.local_seq;
-        self.local_seq += 1;
 
         // Hash the message in the context of all its recipient's
         // pairwise hash-chains:
-        for r in recipients_vec
-            .iter()
-            .filter(|r| *Borrow::<DeviceId>::borrow(*r) != self.own_device)
-        {
-            let chain = self
-                .chains
-                .entry(r.borrow().clone())
-                .or_insert_with(|| DeviceState {
-                    offset: 0,
-                    validated_local_seq: 0,
-                    chain: VecDeque::new(),
-                });
+        let mut entry_hasher =
+            EntryHasher::<D, _>::with_counters(&recipients_vec, counters.as_deref(), message);
+        for r in recipients_vec.iter().map(Borrow::<I>::borrow) {
+            // No chain is kept with retired recipients of our own messages:
+            if *r == self.own_device || self.retired.contains(r) {
+                continue;
+            }
 
-            let message_hash_entry = hash_message(
-                chain.chain.back().map(|entry| &entry.digest),
-                &mut recipients_vec.iter().map(|r| r.borrow()),
-                message,
-            );
+            if !self.chains.contains_key(r) {
+                self.apply(Operation::AddChain { device: r.clone() });
+            }
+            let previous = self
+                .chains
+                .get(r)
+                .and_then(|chain| chain.chain.back())
+                .map(|entry| &entry.digest);
+            let message_hash_entry = entry_hasher.entry_digest::<I>(version, previous);
 
-            chain.chain.push_back(ChainEntry {
+            self.apply(Operation::AppendEntry {
+                device: r.clone(),
                 local_seq,
+                version,
                 digest: message_hash_entry,
             });
         }
 
-        Ok(local_seq)
+        if let Some(chain) = self.chains.get(sender) {
+            // The sender supports at least the version it has used:
+            if negotiated && chain.digest_version < Some(version) {
+                self.apply(Operation::SetPeerDigestVersion {
+                    device: sender.clone(),
+                    version: Some(version),
+                });
+            }
+        }
+
+        // Counted messages of other devices list their sender as a
+        // recipient (see `check_counters`), whose chain hence exists:
+        if counters.is_some() && !own_message {
+            self.apply(Operation::SetReceivedCounter {
+                device: sender.clone(),
+                counter: self.chains[sender].received_counter + 1,
+            });
+        }
+
+        // Remember the recipients of this message, such that we can later
+        // determine which of them have validated it:
+        self.apply(Operation::PushMessage {
+            recipients: recipients_vec.iter().map(|r| r.borrow().clone()).collect(),
+            counters,
+        });
+        self.trim_messages();
+        self.enforce_limits();
+
+        self.emit(|| Event::MessageInserted {
+            sender: sender.clone(),
+            local_seq,
+        });
+        local_seq
+    }
+
+    fn message_validated_count(
+        &self,
+        local_seq: usize,
+        record: &MessageRecord<I>,
+    ) -> (usize, usize) {
+        // Retired devices will never validate any messages:
+        record
+            .recipients
+            .iter()
+            .filter(|r| **r != self.own_device && !self.retired.contains(*r))
+            .fold((0, 0), |(validated, total), r| {
+                let validated_by_r = self
+                    .chains
+                    .get(r)
+                    .map(|chain| local_seq < chain.validated_local_seq)
+                    .unwrap_or(false);
+                (validated + validated_by_r as usize, total + 1)
+            })
+    }
+
+    // Drop the records of all messages at the front of the queue which have
+    // been validated by all of their recipients. Queries for these local
+    // sequence numbers are answered based on `messages_offset` instead.
+    fn trim_messages(&mut self) {
+        let count = self
+            .messages
+            .iter()
+            .zip(self.messages_offset..)
+            .take_while(|(record, local_seq)| {
+                let (validated, total) = self.message_validated_count(*local_seq, record);
+                validated == total
+            })
+            .count();
+
+        if count > 0 {
+            self.apply(Operation::TrimMessages { count });
+        }
+    }
+
+    fn message_record(&self, local_seq: usize) -> Option<&MessageRecord<I>> {
+        local_seq
+            .checked_sub(self.messages_offset)
+            .and_then(|index| self.messages.get(index))
+    }
+
+    fn message_recipients(&self, local_seq: usize) -> Option<&[I]> {
+        self.message_record(local_seq)
+            .map(|record| &record.recipients[..])
+    }
+
+    /// Check whether the message with local sequence number `local_seq` has
+    /// been validated by at least `quorum` of its recipients (excluding our
+    /// own device). If the message has fewer than `quorum` recipients, it
+    /// must have been validated by all of them.
+    ///
+    /// Messages inserted before message records were kept, i.e., restored
+    /// from the unversioned layout, or whose records have been evicted due to
+    /// the configured limits (see [`LimitPolicy`]), are reported as
+    /// [`Error::UnknownMessage`].
+    pub fn message_quorum_validated(
+        &self,
+        local_seq: usize,
+        quorum: usize,
+    ) -> Result<bool, Error<I>> {
+        if local_seq >= self.local_seq {
+            return Err(Error::UnknownMessage(local_seq));
+        }
+
+        // Records are only trimmed once all recipients have validated them,
+        // unless they have never been kept or have been evicted:
+        if local_seq < self.messages_known_from {
+            return Err(Error::UnknownMessage(local_seq));
+        } else if local_seq < self.messages_offset {
+            return Ok(true);
+        }
+
+        let record = &self.messages[local_seq - self.messages_offset];
+
+        let (validated, total) = self.message_validated_count(local_seq, record);
+        Ok(validated >= std::cmp::min(quorum, total))
+    }
+
+    /// Check whether the message with local sequence number `local_seq` has
+    /// been validated by all of its recipients.
+    pub fn message_fully_validated(&self, local_seq: usize) -> Result<bool, Error<I>> {
+        self.message_quorum_validated(local_seq, usize::MAX)
     }
 
     pub fn device_validated_event(
         &self,
-        device: &DeviceId,
+        device: &I,
         event_local_seq: usize,
-    ) -> Result<bool, Error> {
-        let chain = self.chains.get(device).ok_or(Error::UnknownDevice)?;
+    ) -> Result<bool, Error<I>> {
+        let chain = self
+            .chains
+            .get(device)
+            .ok_or_else(|| Error::UnknownDevice(device.clone()))?;
         Ok(event_local_seq < chain.validated_local_seq)
     }
 
+    /// Whether the given peer owes us a validation payload with every
+    /// message. This is the case as soon as it has sent us a valid
+    /// validation payload, which implies that it holds a non-empty pairwise
+    /// chain with us (chains are never trimmed past the last validated
+    /// entry).
+    pub fn expects_validation_payload(&self, device: &I) -> bool {
+        self.chains
+            .get(device)
+            .map(|chain| chain.validated_local_seq > 0)
+            .unwrap_or(false)
+    }
+
     pub fn validate_chain(
         &mut self,
-        validation_sender: impl std::borrow::Borrow<DeviceId>,
-        validation_payload: Option<(usize, impl std::borrow::Borrow<Hash>)>,
-    ) -> Result<(), Error> {
-        log::trace!(
-            "validate_chain(validation_sender: {:?}, validation_payload: {:?})",
+        validation_sender: impl std::borrow::Borrow<I>,
+        validation_payload: Option<(usize, impl std::borrow::Borrow<Hash<D>>)>,
+    ) -> Result<(), Error<I>> {
+        self.validate_chain_inner(
+            validation_sender.borrow(),
+            validation_payload
+                .as_ref()
+                .map(|(seq, hash)| (*seq, hash.borrow())),
+            false,
+        )
+        .map(|_trimmed| ())
+    }
+
+    pub fn validate_trim_chain(
+        &mut self,
+        validation_sender: impl std::borrow::Borrow<I>,
+        validation_payload: Option<(usize, impl std::borrow::Borrow<Hash<D>>)>,
+    ) -> Result<usize, Error<I>> {
+        self.validate_chain_inner(
             validation_sender.borrow(),
             validation_payload
                 .as_ref()
                 .map(|(seq, hash)| (*seq, hash.borrow())),
+            true,
+        )
+    }
+
+    fn validate_chain_inner(
+        &mut self,
+        validation_sender: &I,
+        validation_payload: Option<(usize, &Hash<D>)>,
+        trim: bool,
+    ) -> Result<usize, Error<I>> {
+        log::trace!(
+            "validate_chain(validation_sender: {:?}, validation_payload: {:?}, trim: {})",
+            validation_sender,
+            validation_payload,
+            trim,
         );
 
+        let result = self
+            .check_validation_payload(validation_sender, validation_payload)
+            .map(|validated| match validated {
+                Some(validated) => {
+                    self.apply_validation_payload(validation_sender, validated, trim)
+                }
+                None => 0,
+            });
+        self.observe_result(result)
+    }
+
+    /// Check a validation payload sent by `validation_sender` against our
+    /// pairwise chain with it, without modifying any state. Returns the
+    /// validated entry, if any.
+    fn check_validation_payload(
+        &self,
+        validation_sender: &I,
+        validation_payload: Option<(usize, &Hash<D>)>,
+    ) -> Result<Option<ValidatedEntry>, Error<I>> {
         // We must never send a validation payload to ourselves and
         // hence can never use a loopback-message to trim any hash
         // chains:
-        if *validation_sender.borrow() == self.own_device {
-            assert!(validation_payload.is_none());
-            return Ok(());
+        if *validation_sender == self.own_device {
+            if validation_payload.is_some() {
+                log::debug!(
+                    "validate_chain: invariant violated - validation payload on loopback message"
+                );
+                return Err(Error::LoopbackValidationPayload);
+            }
+
+            return Ok(None);
         }
 
-        // TODO: error if validation payload is none unexpectedly (we should've
-        // received a validation payload but didn't)
         let (seq, hash) = match validation_payload {
             None => {
-                return Ok(());
+                // A peer which has sent us a validation payload before must
+                // still have a non-empty pairwise chain with us, and thus
+                // should have sent a validation payload:
+                if self.config.strict_validation_payloads
+                    && self.expects_validation_payload(validation_sender)
+                {
+                    log::debug!(
+                        "validate_chain: missing validation payload from {:?}",
+                        validation_sender,
+                    );
+                    return Err(Error::MissingValidationPayload(validation_sender.clone()));
+                }
+
+                return Ok(None);
             }
             Some((seq, hash)) => (seq, hash),
         };
 
         // If this validation payload comes from a sender we haven't interacted
         // with, an invariant has been violated:
-        //let (ref pairwise_chain_offset, ref mut validated_local_seq, ref pairwise_chain) = self
-        let pairwise_chain = self
-            .chains
-            .get_mut(validation_sender.borrow())
-            .ok_or_else(|| {
-                log::debug!(
-                    "validate_chain: invariant violated - validation payload \
-                     from unknown sender ({:?})",
-                    validation_sender.borrow(),
-                );
-                Error::InvariantViolated
-            })?;
+        let pairwise_chain = self.chains.get(validation_sender).ok_or_else(|| {
+            log::debug!(
+                "validate_chain: invariant violated - validation payload \
+                 from unknown sender ({:?})",
+                validation_sender,
+            );
+            Error::UnknownValidationSender(validation_sender.clone())
+        })?;
+
+        // Entries dropped due to memory limits can't be validated, except for
+        // the checkpoint of compacted entries:
+        if seq < pairwise_chain.offset {
+            match &pairwise_chain.checkpoint {
+                Some((checkpoint_seq, entry)) if *checkpoint_seq == seq => {
+                    if entry.digest != *hash {
+                        log::debug!(
+                            "validate_chain: invariant violated - validation payload \
+                             sent by {:?} features incorrect hash for checkpoint {}",
+                            validation_sender,
+                            seq,
+                        );
+                        return Err(Error::ForkDetected(Box::new(self.fork_evidence(
+                            validation_sender,
+                            seq,
+                            hash,
+                        ))));
+                    }
+
+                    return Ok(Some(ValidatedEntry {
+                        local_seq: entry.local_seq,
+                        index: None,
+                    }));
+                }
+                _ if pairwise_chain
+                    .unverifiable_from
+                    .map(|from| seq >= from)
+                    .unwrap_or(false) =>
+                {
+                    log::debug!(
+                        "validate_chain: validation payload sent by {:?} refers \
+                         to dropped sequence number {}, ignoring",
+                        validation_sender,
+                        seq,
+                    );
+                    return Ok(None);
+                }
+                _ => (),
+            }
+        }
 
         // If this refers to a sequence number we don't know yet, or have
         // already trimmed, the sender or server has violated an invariant:
@@ -293,86 +979,108 @@ impl MessageChains {
                 "validate_chain: invariant violated - validation payload \
                  sent by {:?} refers to invalid sequence number {}. Valid \
                  sequence numbers are within [{}; {})",
-                validation_sender.borrow(),
+                validation_sender,
                 seq,
                 pairwise_chain.offset,
                 pairwise_chain.offset + pairwise_chain.chain.len()
             );
-            return Err(Error::InvariantViolated);
+            return Err(Error::InvalidValidationSeq {
+                sender: validation_sender.clone(),
+                seq,
+                valid: pairwise_chain.offset..(pairwise_chain.offset + pairwise_chain.chain.len()),
+            });
         }
 
         // The referenced sequence number is in the range of locally kept
         // sequence number for the sender, thus check whether the hashes match
         // at this entry:
-        println!(
+        let index = seq - pairwise_chain.offset;
+        log::trace!(
             "{:?}: Validating {}, {:?} vs {:?}",
             self.own_device,
             seq,
-            &pairwise_chain.chain[seq - pairwise_chain.offset],
-            hash.borrow(),
+            &pairwise_chain.chain[index],
+            hash,
         );
-        if pairwise_chain.chain[seq - pairwise_chain.offset].digest != *hash.borrow() {
+        if pairwise_chain.chain[index].digest != *hash {
             log::debug!(
                 "validate_chain: invariant violated - validation payload \
                  sent by {:?} features incorrect hash for sequence number {}: \
                  expected {:?} vs. actual {:?}",
-                validation_sender.borrow(),
+                validation_sender,
                 seq,
-                pairwise_chain.chain[seq - pairwise_chain.offset],
-                hash.borrow(),
+                pairwise_chain.chain[index],
+                hash,
             );
-            return Err(Error::InvariantViolated);
+            return Err(Error::ForkDetected(Box::new(self.fork_evidence(
+                validation_sender,
+                seq,
+                hash,
+            ))));
         }
 
-        // The hashes match. Hence update the validated local sequence
-        // number (points to the first non-validated local sequence
-        // number).
-        pairwise_chain.validated_local_seq = std::cmp::max(
-            pairwise_chain.validated_local_seq,
-            pairwise_chain.chain[seq - pairwise_chain.offset].local_seq,
-        ) + 1;
-
         // All checks passed, this validation payload is valid in the context of
         // the local chain:
-        Ok(())
+        Ok(Some(ValidatedEntry {
+            local_seq: pairwise_chain.chain[index].local_seq,
+            index: Some(index),
+        }))
     }
 
-    pub fn validate_trim_chain(
+    /// Apply a validation payload which has passed
+    /// [`MessageChains::check_validation_payload`]. If `trim` is set, all
+    /// entries preceding the validated one are dropped. Returns the number
+    /// of trimmed entries.
+    fn apply_validation_payload(
         &mut self,
-        validation_sender: impl std::borrow::Borrow<DeviceId>,
-        validation_payload: Option<(usize, impl std::borrow::Borrow<Hash>)>,
-    ) -> Result<usize, Error> {
-        // First, validate whether this validation payload should be
-        // accepted. This also validates that, if this is a
-        // loopback-message from our own device, we must never have a
-        // validation payload and hence never trim any chains below:
-        self.validate_chain(
-            validation_sender.borrow(),
-            validation_payload
-                .as_ref()
-                .map(|(seq, hash)| (*seq, hash.borrow())),
-        )?;
-
-        // Trim the hash-chains:
-        if let Some((seq, _hash)) = validation_payload {
-            let pairwise_chain = self.chains.get_mut(validation_sender.borrow()).unwrap();
-
-            // All checks passed, we can trim the chain up to (but excluding) the
-            // referenced sequence number:
-            let mut trimmed = 0;
-            while pairwise_chain.offset < seq {
-                trimmed += 1;
-                pairwise_chain.offset += 1;
-                pairwise_chain.chain.pop_front();
-            }
+        validation_sender: &I,
+        validated: ValidatedEntry,
+        trim: bool,
+    ) -> usize {
+        let pairwise_chain = &self.chains[validation_sender];
 
-            Ok(trimmed)
-        } else {
-            Ok(0)
+        // The hashes match. Hence update the validated local sequence
+        // number (points to the first non-validated local sequence
+        // number).
+        let previous = pairwise_chain.validated_local_seq;
+        let validated_local_seq = std::cmp::max(previous, validated.local_seq) + 1;
+        self.apply(Operation::SetValidated {
+            device: validation_sender.clone(),
+            validated_local_seq,
+        });
+        if validated_local_seq > previous {
+            self.emit(|| Event::PeerValidated {
+                device: validation_sender.clone(),
+                local_seq: validated_local_seq - 1,
+            });
         }
+
+        // We can trim the chain up to (but excluding) the referenced sequence
+        // number. The sender won't refer to any dropped entries anymore:
+        let trimmed = match validated.index {
+            Some(index) if trim => {
+                self.apply(Operation::TrimChain {
+                    device: validation_sender.clone(),
+                    count: index,
+                });
+                if index > 0 {
+                    self.emit(|| Event::ChainTrimmed {
+                        device: validation_sender.clone(),
+                        count: index,
+                    });
+                }
+                index
+            }
+            _ => 0,
+        };
+
+        // This may have completed the validation of some messages:
+        self.trim_messages();
+
+        trimmed
     }
 
-    pub fn validation_payload(&self, recipient: &DeviceId) -> Option<(usize, Hash)> {
+    pub fn validation_payload(&self, recipient: &I) -> Option<(usize, Hash<D>)> {
         let recipient_chain = self.chains.get(recipient)?;
         let hash = &recipient_chain.chain.back()?.digest;
         Some((
@@ -384,15 +1092,15 @@ impl MessageChains {
 
 #[cfg(test)]
 mod test {
-    use super::{DeviceId, Hash};
+    use super::{DeviceId, DeviceIdentifier, DigestAlgorithm, DigestVersion, Hash};
 
-    struct TestDeviceState {
-        pub id: DeviceId,
-        pub chains: super::MessageChains,
+    struct TestDeviceState<D: DigestAlgorithm = sha2::Sha256, I: DeviceIdentifier = DeviceId> {
+        pub id: I,
+        pub chains: super::MessageChains<D, I>,
     }
 
-    impl TestDeviceState {
-        pub fn new(device_id: DeviceId) -> TestDeviceState {
+    impl<D: DigestAlgorithm, I: DeviceIdentifier> TestDeviceState<D, I> {
+        pub fn new(device_id: I) -> TestDeviceState<D, I> {
             TestDeviceState {
                 id: device_id.clone(),
                 chains: super::MessageChains::new(device_id.clone()),
@@ -400,9 +1108,23 @@ mod test {
         }
     }
 
-    fn two_devices_base() -> (TestDeviceState, TestDeviceState) {
-        let mut dev_a = TestDeviceState::new("0".into());
-        let mut dev_b = TestDeviceState::new("1".into());
+    /// Restore a JSON dump, without any epoch recorded before.
+    fn from_json<D: DigestAlgorithm, I: DeviceIdentifier>(
+        dump: &str,
+    ) -> Result<super::MessageChains<D, I>, super::DumpError> {
+        super::MessageChains::from_dump(&mut serde_json::Deserializer::from_str(dump), &mut 0)
+    }
+
+    fn two_devices_base<D: DigestAlgorithm>() -> (TestDeviceState<D>, TestDeviceState<D>) {
+        two_devices_base_with_ids("0".into(), "1".into())
+    }
+
+    fn two_devices_base_with_ids<D: DigestAlgorithm, I: DeviceIdentifier>(
+        device_id_a: I,
+        device_id_b: I,
+    ) -> (TestDeviceState<D, I>, TestDeviceState<D, I>) {
+        let mut dev_a = TestDeviceState::new(device_id_a);
+        let mut dev_b = TestDeviceState::new(device_id_b);
 
         // For most exchanged messages, we can use the same recipients list:
         let mut recipients_a_b = [&dev_a.id, &dev_b.id];
@@ -414,26 +1136,27 @@ mod test {
         assert!(dev_a.chains.validation_payload(&dev_b.id).is_none());
         dev_a
             .chains
-            .send_message(message_a_b_0, recipients_a_b.iter().map(|r| *r));
+            .send_message(message_a_b_0, recipients_a_b.iter().copied())
+            .unwrap();
 
         // Bob receives the message.
         dev_b
             .chains
-            .validate_trim_chain(&dev_b.id, None::<(usize, &Hash)>)
+            .validate_trim_chain(&dev_b.id, None::<(usize, &Hash<D>)>)
             .unwrap();
         dev_b
             .chains
-            .insert_message(&dev_a.id, message_a_b_0, recipients_a_b.iter().map(|r| *r))
+            .insert_message(&dev_a.id, message_a_b_0, recipients_a_b.iter().copied())
             .unwrap();
 
         // Alice also needs to receive her own message:
         dev_b
             .chains
-            .validate_trim_chain(&dev_a.id, None::<(usize, &Hash)>)
+            .validate_trim_chain(&dev_a.id, None::<(usize, &Hash<D>)>)
             .unwrap();
         dev_a
             .chains
-            .insert_message(&dev_a.id, message_a_b_0, recipients_a_b.iter().map(|r| *r))
+            .insert_message(&dev_a.id, message_a_b_0, recipients_a_b.iter().copied())
             .unwrap();
 
         // Let's have Bob reply to Alice's message. He should have a validation
@@ -443,17 +1166,18 @@ mod test {
         assert!(message_b_a_0_vp.0 == 0); // validation payload refers to message 0
         dev_b
             .chains
-            .send_message(message_b_a_0, recipients_a_b.iter().map(|r| *r));
+            .send_message(message_b_a_0, recipients_a_b.iter().copied())
+            .unwrap();
 
         // Bob receives his own message.
         let trimmed = dev_b
             .chains
-            .validate_trim_chain(&dev_b.id, None::<(usize, &Hash)>)
+            .validate_trim_chain(&dev_b.id, None::<(usize, &Hash<D>)>)
             .unwrap();
         assert!(trimmed == 0);
         dev_b
             .chains
-            .insert_message(&dev_b.id, message_b_a_0, recipients_a_b.iter().map(|r| *r))
+            .insert_message(&dev_b.id, message_b_a_0, recipients_a_b.iter().copied())
             .unwrap();
 
         // Alice receives Bob's reply, along with the validation
@@ -466,7 +1190,7 @@ mod test {
         assert!(trimmed == 0);
         dev_a
             .chains
-            .insert_message(&dev_b.id, message_b_a_0, recipients_a_b.iter().map(|r| *r))
+            .insert_message(&dev_b.id, message_b_a_0, recipients_a_b.iter().copied())
             .unwrap();
 
         // Alice answers Bob's message:
@@ -475,17 +1199,18 @@ mod test {
         assert!(message_a_b_1_vp.0 == 1); // validation payload refers to message 1
         dev_a
             .chains
-            .send_message(message_a_b_1, recipients_a_b.iter().map(|r| *r));
+            .send_message(message_a_b_1, recipients_a_b.iter().copied())
+            .unwrap();
 
         // Alice receives her own message:
         let trimmed = dev_a
             .chains
-            .validate_trim_chain(&dev_a.id, None::<(usize, &Hash)>)
+            .validate_trim_chain(&dev_a.id, None::<(usize, &Hash<D>)>)
             .unwrap();
         assert!(trimmed == 0);
         dev_a
             .chains
-            .insert_message(&dev_a.id, message_a_b_1, recipients_a_b.iter().map(|r| *r))
+            .insert_message(&dev_a.id, message_a_b_1, recipients_a_b.iter().copied())
             .unwrap();
 
         // Bob validates and receives Alice's message (this should trim the
@@ -497,7 +1222,7 @@ mod test {
         assert!(trimmed == 1);
         dev_b
             .chains
-            .insert_message(&dev_a.id, message_a_b_1, recipients_a_b.iter().map(|r| *r))
+            .insert_message(&dev_a.id, message_a_b_1, recipients_a_b.iter().copied())
             .unwrap();
 
         (dev_a, dev_b)
@@ -505,44 +1230,372 @@ mod test {
 
     #[test]
     fn test_two_devices_base() {
-        two_devices_base();
+        two_devices_base::<sha2::Sha256>();
     }
 
     #[test]
-    fn test_two_devices_dropped_message() {
-        let (mut dev_a, mut dev_b) = two_devices_base();
+    fn test_two_devices_base_digest_algorithms() {
+        two_devices_base::<sha2::Sha512_256>();
+        two_devices_base::<blake3::Hasher>();
+    }
 
-        // All messages are intended to be received by both recipients:
-        let mut recipients_a_b = [&dev_a.id, &dev_b.id];
-        recipients_a_b.sort();
+    #[test]
+    fn test_two_devices_base_public_key_ids() {
+        let (dev_a, _dev_b) =
+            two_devices_base_with_ids::<sha2::Sha256, [u8; 32]>([0xA1; 32], [0xB0; 32]);
+
+        // State with non-string device identifiers must be serializable as
+        // JSON as well:
+        let dump = serde_json::to_string(&dev_a.chains).unwrap();
+        let restored: super::Sha256MessageChains<[u8; 32]> = from_json(&dump).unwrap();
+        assert!(
+            restored.validation_payload(&[0xB0; 32])
+                == dev_a.chains.validation_payload(&[0xB0; 32])
+        );
+    }
 
-        // Alice sends two messages (concurrently) to Bob, but the server does
-        // not deliver the first one to Bob
+    #[test]
+    fn test_dump_digest_algorithm_mismatch() {
+        let (dev_a, _dev_b) = two_devices_base::<sha2::Sha256>();
+        let dump = serde_json::to_string(&dev_a.chains).unwrap();
+
+        // The state can be restored with the same digest algorithm, but not
+        // with any other:
+        assert!(from_json::<sha2::Sha256, DeviceId>(&dump).is_ok());
+        assert!(from_json::<sha2::Sha512_256, DeviceId>(&dump).is_err());
+        assert!(from_json::<blake3::Hasher, DeviceId>(&dump).is_err());
+    }
 
-        // message 3 for Alice
-        let message_1 = "Hey Bob, please ignore the contents of the next message:".as_bytes();
-        let message_1_vp = dev_a.chains.validation_payload(&dev_b.id).unwrap();
-        assert!(message_1_vp.0 == 2); // validation payload refers to message 2
-        dev_a
-            .chains
-            .send_message(message_1, recipients_a_b.iter().map(|r| *r));
+    /// Dumps of Alice and Bob in every layout which has been released, after
+    /// exchanging two messages. Alice has sent a third one ("How are you?"),
+    /// which neither of them has received yet.
+    const DUMP_CORPUS: [(&str, &str, &str); 8] = [
+        (
+            "baseline",
+            include_str!("../testdata/dumps/baseline_alice.json"),
+            include_str!("../testdata/dumps/baseline_bob.json"),
+        ),
+        (
+            "schema_v1",
+            include_str!("../testdata/dumps/schema_v1_alice.json"),
+            include_str!("../testdata/dumps/schema_v1_bob.json"),
+        ),
+        (
+            "schema_v2",
+            include_str!("../testdata/dumps/schema_v2_alice.json"),
+            include_str!("../testdata/dumps/schema_v2_bob.json"),
+        ),
+        (
+            "schema_v3",
+            include_str!("../testdata/dumps/schema_v3_alice.json"),
+            include_str!("../testdata/dumps/schema_v3_bob.json"),
+        ),
+        (
+            "schema_v4",
+            include_str!("../testdata/dumps/schema_v4_alice.json"),
+            include_str!("../testdata/dumps/schema_v4_bob.json"),
+        ),
+        (
+            "schema_v5",
+            include_str!("../testdata/dumps/schema_v5_alice.json"),
+            include_str!("../testdata/dumps/schema_v5_bob.json"),
+        ),
+        (
+            "schema_v6",
+            include_str!("../testdata/dumps/schema_v6_alice.json"),
+            include_str!("../testdata/dumps/schema_v6_bob.json"),
+        ),
+        (
+            "schema_v7",
+            include_str!("../testdata/dumps/schema_v7_alice.json"),
+            include_str!("../testdata/dumps/schema_v7_bob.json"),
+        ),
+    ];
+
+    /// Binary dumps of the same state, in every schema version which has
+    /// been released.
+    const BINARY_DUMP_CORPUS: [(&str, &[u8], &[u8]); 7] = [
+        (
+            "schema_v1",
+            include_bytes!("../testdata/dumps/schema_v1_alice.cbor"),
+            include_bytes!("../testdata/dumps/schema_v1_bob.cbor"),
+        ),
+        (
+            "schema_v2",
+            include_bytes!("../testdata/dumps/schema_v2_alice.cbor"),
+            include_bytes!("../testdata/dumps/schema_v2_bob.cbor"),
+        ),
+        (
+            "schema_v3",
+            include_bytes!("../testdata/dumps/schema_v3_alice.cbor"),
+            include_bytes!("../testdata/dumps/schema_v3_bob.cbor"),
+        ),
+        (
+            "schema_v4",
+            include_bytes!("../testdata/dumps/schema_v4_alice.cbor"),
+            include_bytes!("../testdata/dumps/schema_v4_bob.cbor"),
+        ),
+        (
+            "schema_v5",
+            include_bytes!("../testdata/dumps/schema_v5_alice.cbor"),
+            include_bytes!("../testdata/dumps/schema_v5_bob.cbor"),
+        ),
+        (
+            "schema_v6",
+            include_bytes!("../testdata/dumps/schema_v6_alice.cbor"),
+            include_bytes!("../testdata/dumps/schema_v6_bob.cbor"),
+        ),
+        (
+            "schema_v7",
+            include_bytes!("../testdata/dumps/schema_v7_alice.cbor"),
+            include_bytes!("../testdata/dumps/schema_v7_bob.cbor"),
+        ),
+    ];
+
+    /// Continue the exchange of the corpus dumps, after restoring them.
+    fn continue_corpus_exchange(
+        mut dev_a: super::Sha256MessageChains,
+        mut dev_b: super::Sha256MessageChains,
+    ) {
+        let alice: DeviceId = "alice".into();
+        let bob: DeviceId = "bob".into();
+        let recipients = [&alice, &bob];
 
-        let message_2 = "We're no longer friends.".as_bytes(); // message 4 for Alice, 3 for Bob
-        let message_2_vp = dev_a.chains.validation_payload(&dev_b.id).unwrap();
-        assert!(message_1_vp.0 == 2); // validation payload refers to message 2
+        // Alice's pending message is delivered to both of them, after which
+        // they validate each other's chains:
+        let message_2 = "How are you?".as_bytes();
         dev_a
-            .chains
-            .send_message(message_2, recipients_a_b.iter().map(|r| *r));
-
-        // Alice receives both messages in order:
-        let trimmed = dev_a
-            .chains
-            .validate_trim_chain(&dev_a.id, None::<(usize, &Hash)>)
+            .insert_message(&alice, message_2, recipients.iter().copied())
             .unwrap();
-        assert!(trimmed == 0);
-        dev_a
-            .chains
-            .insert_message(&dev_a.id, message_1, recipients_a_b.iter().map(|r| *r))
+        dev_b
+            .insert_message(&alice, message_2, recipients.iter().copied())
+            .unwrap();
+        let payload_b = dev_b.validation_payload(&alice).unwrap();
+        dev_a.validate_trim_chain(&bob, Some(payload_b)).unwrap();
+
+        // Subsequent messages use the restored digest version:
+        let message_3 = "Fine, thanks!".as_bytes();
+        dev_b
+            .send_message(message_3, recipients.iter().copied())
+            .unwrap();
+        dev_b
+            .insert_message(&bob, message_3, recipients.iter().copied())
+            .unwrap();
+        let payload_a = dev_a.validation_payload(&bob).unwrap();
+        dev_a
+            .insert_message(&bob, message_3, recipients.iter().copied())
+            .unwrap();
+        dev_b.validate_trim_chain(&alice, Some(payload_a)).unwrap();
+        let payload_b = dev_b.validation_payload(&alice).unwrap();
+        dev_a.validate_trim_chain(&bob, Some(payload_b)).unwrap();
+    }
+
+    #[test]
+    fn test_dump_corpus() {
+        for (name, dump_alice, dump_bob) in DUMP_CORPUS {
+            let restore = |dump: &str| -> super::Sha256MessageChains {
+                let chains: super::Sha256MessageChains = from_json(dump)
+                    .unwrap_or_else(|e| panic!("failed to restore {} dump: {}", name, e));
+
+                // Dumps are migrated to the current schema version:
+                let migrated = serde_json::to_value(&chains).unwrap();
+                assert!(migrated["schema_version"] == super::SCHEMA_VERSION);
+                super::MessageChains::from_dump(migrated, &mut 0).unwrap()
+            };
+            continue_corpus_exchange(restore(dump_alice), restore(dump_bob));
+        }
+
+        // The status of messages inserted before message records were kept
+        // is unknown, rather than assumed to be validated:
+        let (_, baseline_alice, _) = DUMP_CORPUS[0];
+        let restored: super::Sha256MessageChains = from_json(baseline_alice).unwrap();
+        assert!(restored.message_fully_validated(1) == Err(super::Error::UnknownMessage(1)));
+
+        for (name, dump_alice, dump_bob) in BINARY_DUMP_CORPUS {
+            let restore = |dump: &[u8]| {
+                super::Sha256MessageChains::from_binary(dump, &mut 0)
+                    .unwrap_or_else(|e| panic!("failed to restore binary {} dump: {}", name, e))
+            };
+            continue_corpus_exchange(restore(dump_alice), restore(dump_bob));
+        }
+
+        // State of a newer schema version is refused:
+        let mut dump =
+            serde_json::to_value(super::Sha256MessageChains::<DeviceId>::new("alice".into()))
+                .unwrap();
+        dump["schema_version"] = (super::SCHEMA_VERSION + 1).into();
+        let error = super::Sha256MessageChains::<DeviceId>::from_dump(dump, &mut 0).unwrap_err();
+        assert!(error.to_string().contains("unsupported schema version"));
+    }
+
+    #[test]
+    fn test_binary_dump() {
+        let (mut dev_a, _dev_b) = two_devices_base::<sha2::Sha256>();
+
+        // The binary dump restores the same state, and is considerably smaller
+        // than JSON, which encodes every digest byte as a number:
+        let binary = dev_a.chains.to_binary();
+        let json = serde_json::to_string(&dev_a.chains).unwrap();
+        assert!(binary.len() * 4 < json.len());
+        let restored: super::Sha256MessageChains =
+            super::Sha256MessageChains::from_binary(&binary, &mut 0).unwrap();
+        assert!(serde_json::to_string(&restored).unwrap() == json);
+
+        // As with JSON, state can't be restored with another digest algorithm:
+        assert!(matches!(
+            super::Blake3MessageChains::<DeviceId>::from_binary(&binary, &mut 0),
+            Err(super::DumpError::Decode(_))
+        ));
+        assert!(super::Sha256MessageChains::<DeviceId>::from_binary(
+            &binary[..binary.len() - 1],
+            &mut 0
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_sealed_dump() {
+        let (mut dev_a, _dev_b) = two_devices_base_with_ids::<sha2::Sha256, DeviceId>(
+            "alice-phone".into(),
+            "bob-laptop".into(),
+        );
+        let key = [7; 32];
+        let contains_peer = |bytes: &[u8]| bytes.windows(10).any(|window| window == b"bob-laptop");
+
+        // Sealed state restores the same state, without revealing any
+        // device identifiers:
+        let sealed = dev_a.chains.to_sealed(&key);
+        let restored =
+            super::Sha256MessageChains::<DeviceId>::from_sealed(&sealed, &key, &mut 0).unwrap();
+        assert!(contains_peer(&dev_a.chains.encode_binary()) && !contains_peer(&sealed));
+        assert!(
+            serde_json::to_value(&restored).unwrap()
+                == serde_json::to_value(&dev_a.chains).unwrap()
+        );
+
+        // Every dump uses a fresh nonce:
+        assert!(dev_a.chains.to_sealed(&key) != sealed);
+
+        // Tampered state and other keys are rejected:
+        for i in [0, 4, 30, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 1;
+            assert!(
+                super::Sha256MessageChains::<DeviceId>::from_sealed(&tampered, &key, &mut 0)
+                    .is_err()
+            );
+        }
+        assert!(
+            super::Sha256MessageChains::<DeviceId>::from_sealed(&sealed, &[8; 32], &mut 0)
+                .unwrap_err()
+                == super::DumpError::Unauthenticated
+        );
+        assert!(matches!(
+            super::Sha256MessageChains::<DeviceId>::from_sealed(&sealed[..20], &key, &mut 0),
+            Err(super::DumpError::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn test_epoch_rollback() {
+        use super::EpochCounter;
+
+        let (mut dev_a, _dev_b) = two_devices_base::<sha2::Sha256>();
+        let recipients_a_b: [DeviceId; 2] = [dev_a.id.clone(), "1".into()];
+        let mut counter = 0;
+
+        // Alice dumps her state, which starts a new epoch, and records its
+        // epoch once the dump has been persisted:
+        let old_dump = dev_a.chains.to_dump(serde_json::value::Serializer).unwrap();
+        assert!(dev_a.chains.epoch() == 1);
+        counter.advance(dev_a.chains.epoch());
+
+        // She persists her state again after sending a message, but crashes
+        // before recording its epoch. The state is newer than recorded, so
+        // it is accepted and the counter catches up:
+        dev_a
+            .chains
+            .send_message("Hi Bob!".as_bytes(), recipients_a_b.iter())
+            .unwrap();
+        let dump = dev_a.chains.to_binary();
+        let restored = super::Sha256MessageChains::<DeviceId>::from_binary(&dump, &mut counter);
+        assert!(restored.unwrap().epoch() == 2);
+        assert!(counter == 2);
+
+        // Restoring the older state is detected as a rollback, in any format:
+        assert!(
+            super::Sha256MessageChains::<DeviceId>::from_dump(old_dump, &mut counter).unwrap_err()
+                == super::DumpError::Rollback {
+                    epoch: 1,
+                    latest: 2
+                }
+        );
+
+        // Every dump starts a new epoch, whichever format it uses:
+        let sealed = dev_a.chains.to_sealed(&[7; 32]);
+        assert!(super::Sha256MessageChains::<DeviceId>::from_sealed(
+            &sealed,
+            &[7; 32],
+            &mut counter
+        )
+        .is_ok());
+        assert!(
+            super::Sha256MessageChains::<DeviceId>::from_binary(&dump, &mut counter).unwrap_err()
+                == super::DumpError::Rollback {
+                    epoch: 2,
+                    latest: 3
+                }
+        );
+
+        // State dumped before epochs were kept predates all of them:
+        assert!(matches!(
+            from_json::<sha2::Sha256, DeviceId>(DUMP_CORPUS[3].1),
+            Ok(restored) if restored.epoch() == 0
+        ));
+        assert!(super::Sha256MessageChains::<DeviceId>::from_dump(
+            &mut serde_json::Deserializer::from_str(DUMP_CORPUS[3].1),
+            &mut counter
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_two_devices_dropped_message() {
+        let (mut dev_a, mut dev_b) = two_devices_base::<sha2::Sha256>();
+
+        // All messages are intended to be received by both recipients:
+        let mut recipients_a_b = [&dev_a.id, &dev_b.id];
+        recipients_a_b.sort();
+
+        // Alice sends two messages (concurrently) to Bob, but the server does
+        // not deliver the first one to Bob
+
+        // message 3 for Alice
+        let message_1 = "Hey Bob, please ignore the contents of the next message:".as_bytes();
+        let message_1_vp = dev_a.chains.validation_payload(&dev_b.id).unwrap();
+        assert!(message_1_vp.0 == 2); // validation payload refers to message 2
+        dev_a
+            .chains
+            .send_message(message_1, recipients_a_b.iter().copied())
+            .unwrap();
+
+        let message_2 = "We're no longer friends.".as_bytes(); // message 4 for Alice, 3 for Bob
+        let message_2_vp = dev_a.chains.validation_payload(&dev_b.id).unwrap();
+        assert!(message_1_vp.0 == 2); // validation payload refers to message 2
+        dev_a
+            .chains
+            .send_message(message_2, recipients_a_b.iter().copied())
+            .unwrap();
+
+        // Alice receives both messages in order:
+        let trimmed = dev_a
+            .chains
+            .validate_trim_chain(&dev_a.id, None::<(usize, &Hash)>)
+            .unwrap();
+        assert!(trimmed == 0);
+        dev_a
+            .chains
+            .insert_message(&dev_a.id, message_1, recipients_a_b.iter().copied())
             .unwrap();
         let trimmed = dev_a
             .chains
@@ -551,7 +1604,7 @@ mod test {
         assert!(trimmed == 0);
         dev_a
             .chains
-            .insert_message(&dev_a.id, message_2, recipients_a_b.iter().map(|r| *r))
+            .insert_message(&dev_a.id, message_2, recipients_a_b.iter().copied())
             .unwrap();
 
         // Bob recieves only the second message. He can't yet detect that
@@ -566,7 +1619,7 @@ mod test {
         assert!(trimmed == 1);
         dev_b
             .chains
-            .insert_message(&dev_a.id, message_2, recipients_a_b.iter().map(|r| *r))
+            .insert_message(&dev_a.id, message_2, recipients_a_b.iter().copied())
             .unwrap();
 
         // Now, Bob send's Alice a message (message 4 for Bob, 5 for Alice)
@@ -575,7 +1628,8 @@ mod test {
         assert!(message_3_vp.0 == 3); // validation payload refers to message 3 (from Bob's perspective)
         dev_b
             .chains
-            .send_message(message_3, recipients_a_b.iter().map(|r| *r));
+            .send_message(message_3, recipients_a_b.iter().copied())
+            .unwrap();
 
         // Bob recieves his own message back:
         let trimmed = dev_b
@@ -585,17 +1639,2051 @@ mod test {
         assert!(trimmed == 0);
         dev_b
             .chains
-            .insert_message(&dev_b.id, message_3, recipients_a_b.iter().map(|r| *r))
+            .insert_message(&dev_b.id, message_3, recipients_a_b.iter().copied())
             .unwrap();
 
         // Alice recieves Bob's message and should be able to realize that
         // there's something going on: Bob's validation payload doesn't make
         // sense from Alice's point of view:
+        let evidence = match dev_a
+            .chains
+            .validate_trim_chain(&dev_b.id, Some((message_3_vp.0, &message_3_vp.1)))
+        {
+            Err(super::Error::ForkDetected(evidence)) => evidence,
+            res => panic!("Expected a detected fork, got {:?}", res),
+        };
+
+        // The evidence is based on the last entry Bob has validated (message
+        // 0), and can be verified by a third party given all subsequent
+        // messages in Alice's chain with Bob:
+        assert!(evidence.sender == dev_b.id && evidence.receiver == dev_a.id);
+        assert!(evidence.seq == 3 && evidence.base.as_ref().unwrap().0 == 0);
+        assert!(evidence.claimed_digest[..] == message_3_vp.1[..]);
+        let chain_messages = [
+            "Hey Alice, how are you?".as_bytes(),
+            "I'm good, thanks for asking!".as_bytes(),
+            message_1,
+        ];
+        assert!(evidence.verify::<sha2::Sha256>(chain_messages));
+        assert!(!evidence.verify::<sha2::Sha256>([
+            chain_messages[0],
+            chain_messages[1],
+            message_2
+        ]));
+        assert!(!evidence.verify::<blake3::Hasher>(chain_messages));
+
+        // The evidence is transferable:
+        let serialized = serde_json::to_string(&evidence).unwrap();
+        let deserialized: super::ForkEvidence<DeviceId> =
+            serde_json::from_str(&serialized).unwrap();
+        assert!(deserialized.verify::<sha2::Sha256>(chain_messages));
+
+        // Malformed evidence is refused rather than hashed:
+        let mut malformed = deserialized.clone();
+        malformed.entries[0].counters = Some(vec![0]);
+        assert!(!malformed.verify::<sha2::Sha256>(chain_messages));
+    }
+
+    #[test]
+    fn test_two_devices_message_validated() {
+        let (dev_a, dev_b) = two_devices_base::<sha2::Sha256>();
+
+        // Bob has validated Alice's message 0 through the validation payload
+        // sent along with his reply, but nothing thereafter:
+        assert!(dev_a.chains.message_fully_validated(0).unwrap());
+        assert!(!dev_a.chains.message_fully_validated(1).unwrap());
+        assert!(!dev_a.chains.message_quorum_validated(2, 1).unwrap());
+        assert!(dev_a.chains.message_fully_validated(3) == Err(super::Error::UnknownMessage(3)));
+
+        // Alice's reply validated messages 0 and 1 for Bob:
+        assert!(dev_b.chains.message_fully_validated(0).unwrap());
+        assert!(dev_b.chains.message_quorum_validated(1, 1).unwrap());
+        assert!(!dev_b.chains.message_fully_validated(2).unwrap());
+    }
+
+    #[test]
+    fn test_three_devices_message_quorum() {
+        let mut dev_a: TestDeviceState = TestDeviceState::new("0".into());
+        let mut dev_b: TestDeviceState = TestDeviceState::new("1".into());
+        let mut dev_c: TestDeviceState = TestDeviceState::new("2".into());
+
+        let recipients_a_b_c: [DeviceId; 3] =
+            [dev_a.id.clone(), dev_b.id.clone(), dev_c.id.clone()];
+
+        // Alice sends a message to both Bob and Charlie, and everyone
+        // receives it:
+        let message_0 = "Hi all!".as_bytes();
+        dev_a
+            .chains
+            .send_message(message_0, recipients_a_b_c.iter())
+            .unwrap();
+        for dev in [&mut dev_a, &mut dev_b, &mut dev_c] {
+            let local_seq = dev
+                .chains
+                .insert_message(&recipients_a_b_c[0], message_0, recipients_a_b_c.iter())
+                .unwrap();
+            assert!(local_seq == 0);
+        }
+        assert!(!dev_a.chains.message_quorum_validated(0, 1).unwrap());
+
+        // Bob replies with a validation payload, which validates the message
+        // for a quorum of one, but not yet for all recipients:
+        let message_b_vp = dev_b.chains.validation_payload(&dev_a.id).unwrap();
+        dev_a
+            .chains
+            .validate_trim_chain(&dev_b.id, Some((message_b_vp.0, &message_b_vp.1)))
+            .unwrap();
+        assert!(dev_a.chains.message_quorum_validated(0, 1).unwrap());
+        assert!(!dev_a.chains.message_quorum_validated(0, 2).unwrap());
+        assert!(!dev_a.chains.message_fully_validated(0).unwrap());
+
+        // Once Charlie has validated the message as well, it is validated by
+        // all of its recipients:
+        let message_c_vp = dev_c.chains.validation_payload(&dev_a.id).unwrap();
+        dev_a
+            .chains
+            .validate_trim_chain(&dev_c.id, Some((message_c_vp.0, &message_c_vp.1)))
+            .unwrap();
+        assert!(dev_a.chains.message_quorum_validated(0, 2).unwrap());
+        assert!(dev_a.chains.message_fully_validated(0).unwrap());
+    }
+
+    #[test]
+    fn test_two_devices_strict_missing_validation_payload() {
+        let (mut dev_a, mut dev_b) = two_devices_base::<sha2::Sha256>();
+
+        // Without strict validation, a missing validation payload is
+        // accepted even though Bob has sent one before:
+        dev_a
+            .chains
+            .validate_trim_chain(&dev_b.id, None::<(usize, &Hash)>)
+            .unwrap();
+
+        // With strict validation, Alice must reject Bob's message, as it must
+        // have carried a validation payload. This does not apply to her own
+        // messages:
+        dev_a.chains.config_mut().strict_validation_payloads = true;
+        assert!(
+            dev_a
+                .chains
+                .validate_trim_chain(&dev_b.id, None::<(usize, &Hash)>)
+                == Err(super::Error::MissingValidationPayload(dev_b.id.clone()))
+        );
+        dev_a
+            .chains
+            .validate_trim_chain(&dev_a.id, None::<(usize, &Hash)>)
+            .unwrap();
+
+        // Similarly, Bob has seen a validation payload from Alice before:
+        dev_b.chains.config_mut().strict_validation_payloads = true;
+        assert!(
+            dev_b
+                .chains
+                .validate_trim_chain(&dev_a.id, None::<(usize, &Hash)>)
+                == Err(super::Error::MissingValidationPayload(dev_a.id.clone()))
+        );
+
+        // A device which has not sent any validation payload yet does not owe
+        // one:
+        let mut dev_c: TestDeviceState = TestDeviceState::new("2".into());
+        dev_c.chains.config_mut().strict_validation_payloads = true;
+        dev_c
+            .chains
+            .validate_trim_chain(&dev_a.id, None::<(usize, &Hash)>)
+            .unwrap();
+    }
+
+    fn three_devices_gossip(tamper: bool) -> Result<(), super::Error> {
+        let mut dev_a: TestDeviceState = TestDeviceState::new("0".into());
+        let mut dev_b: TestDeviceState = TestDeviceState::new("1".into());
+        let mut dev_c: TestDeviceState = TestDeviceState::new("2".into());
+        for dev in [&mut dev_a, &mut dev_b, &mut dev_c] {
+            dev.chains.config_mut().gossip = true;
+        }
+
+        let recipients_a_b_c: [DeviceId; 3] =
+            [dev_a.id.clone(), dev_b.id.clone(), dev_c.id.clone()];
+        let recipients_b_c: [DeviceId; 2] = [dev_b.id.clone(), dev_c.id.clone()];
+
+        // Bob sends a message to Charlie only, which the server may tamper
+        // with. Alice is unaware of this message:
+        let message_b_c = "Don't tell Alice.".as_bytes();
+        dev_b
+            .chains
+            .send_message(message_b_c, recipients_b_c.iter())
+            .unwrap();
+        dev_b
+            .chains
+            .insert_message(&dev_b.id, message_b_c, recipients_b_c.iter())
+            .unwrap();
+        dev_c
+            .chains
+            .insert_message(
+                &dev_b.id,
+                if tamper {
+                    "Tell Alice.".as_bytes()
+                } else {
+                    message_b_c
+                },
+                recipients_b_c.iter(),
+            )
+            .unwrap();
+
+        // Alice sends a message to everyone:
+        let message_a = "Hi all!".as_bytes();
+        dev_a
+            .chains
+            .send_message(message_a, recipients_a_b_c.iter())
+            .unwrap();
+        for dev in [&mut dev_a, &mut dev_b, &mut dev_c] {
+            dev.chains
+                .insert_message(&recipients_a_b_c[0], message_a, recipients_a_b_c.iter())
+                .unwrap();
+        }
+
+        // Both Bob and Charlie gossip about their pairwise chain to Alice,
+        // referencing Alice's message:
+        let gossip_b = dev_b.chains.gossip_payload(&dev_a.id);
+        assert!(gossip_b.len() == 1 && gossip_b[0].third_party == dev_c.id);
+        let gossip_c = dev_c.chains.gossip_payload(&dev_a.id);
+        assert!(gossip_c.len() == 1 && gossip_c[0].third_party == dev_b.id);
+
+        dev_a.chains.validate_gossip(&dev_b.id, gossip_b)?;
+        dev_a.chains.validate_gossip(&dev_c.id, gossip_c)
+    }
+
+    #[test]
+    fn test_three_devices_gossip() {
+        three_devices_gossip(false).unwrap();
+    }
+
+    #[test]
+    fn test_three_devices_gossip_third_party_fork() {
+        // Alice detects that Bob and Charlie's chains have forked, even though
+        // she never received the message in question:
+        assert!(
+            three_devices_gossip(true)
+                == Err(super::Error::ThirdPartyForkDetected("1".into(), "2".into()))
+        );
+    }
+
+    #[test]
+    fn test_three_devices_gossip_conflicting_claims() {
+        let mut dev_a: TestDeviceState = TestDeviceState::new("0".into());
+        let mut dev_b: TestDeviceState = TestDeviceState::new("1".into());
+        let mut dev_c: TestDeviceState = TestDeviceState::new("2".into());
+        for dev in [&mut dev_a, &mut dev_b, &mut dev_c] {
+            dev.chains.config_mut().gossip = true;
+        }
+
+        let recipients_a_b_c: [DeviceId; 3] =
+            [dev_a.id.clone(), dev_b.id.clone(), dev_c.id.clone()];
+        let message_a = "Hi all!".as_bytes();
+        dev_a
+            .chains
+            .send_message(message_a, recipients_a_b_c.iter())
+            .unwrap();
+        for dev in [&mut dev_a, &mut dev_b, &mut dev_c] {
+            dev.chains
+                .insert_message(&recipients_a_b_c[0], message_a, recipients_a_b_c.iter())
+                .unwrap();
+        }
+
+        // Bob may repeat his claim about Alice's message:
+        let gossip_b = dev_b.chains.gossip_payload(&dev_a.id);
+        assert!(gossip_b.len() == 1 && gossip_b[0].third_party == dev_c.id);
+
+        // Claims are only stored once all of them have been checked, which
+        // includes the claims preceding them in the same call:
+        let dump_a = serde_json::to_string(&dev_a.chains).unwrap();
+        let mut invalid_seq = gossip_b[0].clone();
+        invalid_seq.seq = 1;
         assert!(
             dev_a
                 .chains
-                .validate_trim_chain(&dev_b.id, Some((message_3_vp.0, &message_3_vp.1)))
-                == Err(super::Error::InvariantViolated)
+                .validate_gossip(&dev_b.id, [gossip_b[0].clone(), invalid_seq])
+                == Err(super::Error::InvalidGossipSeq {
+                    sender: dev_b.id.clone(),
+                    seq: 1,
+                    end: 1,
+                })
+        );
+        let mut conflicting = gossip_b[0].clone();
+        conflicting.third_party_digest[0] ^= 0xff;
+        assert!(matches!(
+            dev_a
+                .chains
+                .validate_gossip(&dev_b.id, [gossip_b[0].clone(), conflicting]),
+            Err(super::Error::ConflictingGossipClaims { .. })
+        ));
+        assert!(serde_json::to_string(&dev_a.chains).unwrap() == dump_a);
+
+        dev_a
+            .chains
+            .validate_gossip(&dev_b.id, gossip_b.clone())
+            .unwrap();
+        assert!(serde_json::to_string(&dev_a.chains).unwrap() != dump_a);
+        dev_a
+            .chains
+            .validate_gossip(&dev_b.id, gossip_b.clone())
+            .unwrap();
+
+        // But he can't claim a different digest for it later on:
+        let mut conflicting = gossip_b;
+        conflicting[0].third_party_digest[0] ^= 0xff;
+        assert!(
+            dev_a.chains.validate_gossip(&dev_b.id, conflicting)
+                == Err(super::Error::ConflictingGossipClaims {
+                    claimer: dev_b.id.clone(),
+                    third_party: dev_c.id.clone(),
+                    local_seq: 0,
+                })
+        );
+    }
+
+    #[test]
+    fn test_digest_version_legacy_dump() {
+        let mut dev_a: TestDeviceState = TestDeviceState::new("0".into());
+        let mut dev_b: TestDeviceState = TestDeviceState::new("1".into());
+        dev_a.chains.config_mut().digest_version = DigestVersion::V1;
+        dev_b.chains.config_mut().digest_version = DigestVersion::V1;
+
+        let recipients_a_b = [&dev_a.id, &dev_b.id];
+
+        // Alice sends two messages, but only receives the first one back
+        // before her state is dumped:
+        let message_0 = "Hi Bob!".as_bytes();
+        let message_1 = "Are you there?".as_bytes();
+        dev_a
+            .chains
+            .send_message(message_0, recipients_a_b.iter().copied())
+            .unwrap();
+        dev_a
+            .chains
+            .insert_message(&dev_a.id, message_0, recipients_a_b.iter().copied())
+            .unwrap();
+        dev_a
+            .chains
+            .send_message(message_1, recipients_a_b.iter().copied())
+            .unwrap();
+
+        // Convert the dumped state into the unversioned layout released before
+        // digests were versioned:
+        let state = serde_json::to_value(&dev_a.chains).unwrap()["state"].take();
+        let dump = serde_json::json!({
+            "own_device": state["own_device"],
+            "pending_messages": state["pending_messages"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|pending| pending["digest"].clone())
+                .collect::<Vec<_>>(),
+            "chains": state["chains"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|chain| (chain["device"].as_str().unwrap().to_string(), serde_json::json!({
+                    "offset": chain["offset"],
+                    "validated_local_seq": chain["validated_local_seq"],
+                    "chain": chain["entries"]
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .map(|entry| serde_json::json!({
+                            "local_seq": entry["local_seq"],
+                            "digest": entry["digest"],
+                        }))
+                        .collect::<Vec<_>>(),
+                })))
+                .collect::<serde_json::Map<_, _>>(),
+            "local_seq": state["local_seq"],
+        });
+
+        // Restored legacy state keeps using V1 digests, recognizes the
+        // pending message and stays consistent with Bob. The status of the
+        // first message is unknown, as no message records were kept:
+        dev_a.chains = super::MessageChains::from_dump(dump, &mut 0).unwrap();
+        assert!(dev_a.chains.config().digest_version == DigestVersion::V1);
+        assert!(dev_a.chains.message_fully_validated(0) == Err(super::Error::UnknownMessage(0)));
+        dev_a
+            .chains
+            .insert_message(&dev_a.id, message_1, recipients_a_b.iter().copied())
+            .unwrap();
+
+        for message in [message_0, message_1] {
+            dev_b
+                .chains
+                .insert_message(&dev_a.id, message, recipients_a_b.iter().copied())
+                .unwrap();
+        }
+        let message_b_vp = dev_b.chains.validation_payload(&dev_a.id).unwrap();
+        dev_a
+            .chains
+            .validate_trim_chain(&dev_b.id, Some((message_b_vp.0, &message_b_vp.1)))
+            .unwrap();
+
+        // Once both devices have switched to V2, new entries use the new
+        // format while the chain remains consistent:
+        dev_a.chains.config_mut().digest_version = DigestVersion::V2;
+        dev_b.chains.config_mut().digest_version = DigestVersion::V2;
+        let message_2 = "Switching to V2.".as_bytes();
+        dev_b
+            .chains
+            .send_message(message_2, recipients_a_b.iter().copied())
+            .unwrap();
+        for chains in [&mut dev_a.chains, &mut dev_b.chains] {
+            chains
+                .insert_message(&dev_b.id, message_2, recipients_a_b.iter().copied())
+                .unwrap();
+        }
+        let message_a_vp = dev_a.chains.validation_payload(&dev_b.id).unwrap();
+        assert!(message_a_vp.0 == 2);
+        dev_b
+            .chains
+            .validate_trim_chain(&dev_a.id, Some((message_a_vp.0, &message_a_vp.1)))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_digest_version_negotiation() {
+        let mut dev_a: TestDeviceState = TestDeviceState::new("0".into());
+        let mut dev_b: TestDeviceState = TestDeviceState::new("1".into());
+
+        // Alice has been upgraded to V3 while rolling it out, Bob still only
+        // supports V2:
+        dev_a.chains.config_mut().default_peer_digest_version = Some(DigestVersion::V2);
+        dev_b.chains.config_mut().digest_version = DigestVersion::V2;
+
+        let recipients_a_b = [&dev_a.id, &dev_b.id];
+
+        // Alice doesn't know which versions Bob supports, and falls back to
+        // the rollout default:
+        let message_0 = "Hi Bob!".as_bytes();
+        let version_0 = dev_a
+            .chains
+            .send_message(message_0, recipients_a_b.iter().copied())
+            .unwrap();
+        assert!(version_0 == DigestVersion::V2);
+        for chains in [&mut dev_a.chains, &mut dev_b.chains] {
+            chains
+                .insert_versioned_message(
+                    &dev_a.id,
+                    message_0,
+                    recipients_a_b.iter().copied(),
+                    version_0,
+                )
+                .unwrap();
+        }
+
+        // Once Bob has been upgraded and advertises V3, Alice switches to it:
+        dev_b.chains.config_mut().digest_version = DigestVersion::V3;
+        dev_a
+            .chains
+            .set_peer_digest_version(&dev_b.id, dev_b.chains.config().digest_version);
+        let message_1 = "Are you there?".as_bytes();
+        let version_1 = dev_a
+            .chains
+            .send_message(message_1, recipients_a_b.iter().copied())
+            .unwrap();
+        assert!(version_1 == DigestVersion::V3);
+        for chains in [&mut dev_a.chains, &mut dev_b.chains] {
+            chains
+                .insert_versioned_message(
+                    &dev_a.id,
+                    message_1,
+                    recipients_a_b.iter().copied(),
+                    version_1,
+                )
+                .unwrap();
+        }
+
+        // Bob has learned that Alice supports V3 from her message. The chain
+        // is consistent across the switch:
+        assert!(dev_b.chains.peer_digest_version(&dev_a.id) == DigestVersion::V3);
+        let message_b_vp = dev_b.chains.validation_payload(&dev_a.id).unwrap();
+        assert!(message_b_vp.0 == 1);
+        dev_a
+            .chains
+            .validate_trim_chain(&dev_b.id, Some((message_b_vp.0, &message_b_vp.1)))
+            .unwrap();
+
+        // Bob's message must be inserted with the version he used:
+        let message_2 = "Yes!".as_bytes();
+        let version_2 = dev_b
+            .chains
+            .send_message(message_2, recipients_a_b.iter().copied())
+            .unwrap();
+        assert!(version_2 == DigestVersion::V3);
+        assert!(
+            dev_b.chains.insert_versioned_message(
+                &dev_b.id,
+                message_2,
+                recipients_a_b.iter().copied(),
+                version_2,
+            ) == Ok(2)
+        );
+        dev_a
+            .chains
+            .insert_versioned_message(
+                &dev_b.id,
+                message_2,
+                recipients_a_b.iter().copied(),
+                DigestVersion::V2,
+            )
+            .unwrap();
+        let message_a_vp = dev_a.chains.validation_payload(&dev_b.id).unwrap();
+        assert!(matches!(
+            dev_b
+                .chains
+                .validate_chain(&dev_a.id, Some((message_a_vp.0, &message_a_vp.1))),
+            Err(super::Error::ForkDetected(_))
+        ));
+    }
+
+    #[test]
+    fn test_canonical_digest_message_unambiguous() {
+        // Without length prefixes, recipient IDs and message contents can be
+        // shifted into one another:
+        let recipients_0 = ["a".to_string()];
+        let recipients_1 = ["amessage".to_string()];
+        let (message_0, message_1) = ("messagehi".as_bytes(), "hi".as_bytes());
+
+        assert!(
+            super::digest_message::<sha2::Sha256, DeviceId, _>(&mut recipients_0.iter(), message_0)
+                == super::digest_message::<sha2::Sha256, DeviceId, _>(
+                    &mut recipients_1.iter(),
+                    message_1
+                )
+        );
+        assert!(
+            super::canonical_digest_message::<sha2::Sha256, DeviceId, _>(
+                &mut recipients_0.iter(),
+                message_0
+            ) != super::canonical_digest_message::<sha2::Sha256, DeviceId, _>(
+                &mut recipients_1.iter(),
+                message_1
+            )
+        );
+    }
+
+    #[test]
+    fn test_error_context() {
+        use super::Error;
+        use serde_json::json;
+
+        let cases: Vec<(Error, &str, serde_json::Value)> = vec![
+            (
+                Error::TooFewRecipients,
+                "message has no recipients",
+                json!({ "code": "too_few_recipients" }),
+            ),
+            (
+                Error::UnknownDevice("1".into()),
+                "unknown device \"1\"",
+                json!({ "code": "unknown_device", "details": "1" }),
+            ),
+            (
+                Error::ThirdPartyForkDetected("1".into(), "2".into()),
+                "fork detected between \"1\" and \"2\"",
+                json!({ "code": "third_party_fork_detected", "details": ["1", "2"] }),
+            ),
+            (
+                Error::InvalidValidationSeq {
+                    sender: "1".into(),
+                    seq: 5,
+                    valid: 2..4,
+                },
+                "validation payload from \"1\" refers to sequence number 5, \
+                 expected within [2; 4)",
+                json!({
+                    "code": "invalid_validation_seq",
+                    "details": { "sender": "1", "seq": 5, "valid": { "start": 2, "end": 4 } },
+                }),
+            ),
+            (
+                Error::InvalidGossipSeq {
+                    sender: "1".into(),
+                    seq: 7,
+                    end: 3,
+                },
+                "gossip claim by \"1\" refers to sequence number 7, expected below 3",
+                json!({
+                    "code": "invalid_gossip_seq",
+                    "details": { "sender": "1", "seq": 7, "end": 3 },
+                }),
+            ),
+            (
+                Error::InvalidCounters {
+                    sender: "1".into(),
+                    recipients: 2,
+                    counters: 1,
+                    version: DigestVersion::V3,
+                },
+                "message from \"1\" carries 1 counters for 2 recipients with \
+                 digest version V3",
+                json!({
+                    "code": "invalid_counters",
+                    "details": { "sender": "1", "recipients": 2, "counters": 1, "version": 3 },
+                }),
+            ),
+            (
+                Error::OwnMessageInvalidReordered { pending_id: None },
+                "own message received without any pending message",
+                json!({
+                    "code": "own_message_invalid_reordered",
+                    "details": { "pending_id": null },
+                }),
+            ),
+            (
+                Error::ChainLimitExceeded {
+                    device: None,
+                    limit: 3,
+                },
+                "chains exceed their total length limit of 3",
+                json!({
+                    "code": "chain_limit_exceeded",
+                    "details": { "device": null, "limit": 3 },
+                }),
+            ),
+            (
+                Error::MessageRecordLimitExceeded { limit: 3 },
+                "message records exceed their limit of 3",
+                json!({
+                    "code": "message_record_limit_exceeded",
+                    "details": { "limit": 3 },
+                }),
+            ),
+            (
+                Error::RetireOwnDevice,
+                "own device can't be retired",
+                json!({ "code": "retire_own_device" }),
+            ),
+            (
+                Error::PeerNotReset("2".into()),
+                "peer \"2\" has not reset its chain with us",
+                json!({ "code": "peer_not_reset", "details": "2" }),
+            ),
+        ];
+        for (error, display, serialized) in cases {
+            assert!(error.to_string() == display, "{}", error);
+            assert!(
+                serde_json::to_value(&error).unwrap() == serialized,
+                "{}",
+                error
+            );
+            // Errors can be passed back, e.g., to record violations:
+            assert!(serde_json::from_value::<Error>(serialized).unwrap() == error);
+        }
+
+        let (mut dev_a, dev_b) = two_devices_base::<sha2::Sha256>();
+        let (seq, digest) = dev_a.chains.validation_payload(&dev_b.id).unwrap();
+
+        // A validation payload on a loopback message is refused, rather than
+        // asserted against:
+        assert!(
+            dev_a.chains.validate_chain(&dev_a.id, Some((seq, &digest)))
+                == Err(Error::LoopbackValidationPayload)
         );
+
+        // Fork evidence is serialized in full, and its digests are displayed
+        // in hex:
+        let mut forked_digest = digest;
+        forked_digest[0] ^= 0xff;
+        let error = dev_a
+            .chains
+            .validate_chain(&dev_b.id, Some((seq, &forked_digest)))
+            .unwrap_err();
+        assert!(error.to_string().starts_with(&format!(
+            "fork detected with \"1\" at sequence number {}: expected digest {}",
+            seq,
+            digest
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>()
+        )));
+        let serialized = serde_json::to_value(&error).unwrap();
+        assert!(serialized["code"] == "fork_detected");
+        assert!(serialized["details"]["sender"] == "1");
+        assert!(serialized["details"]["claimed_digest"] == json!(forked_digest.to_vec()));
+
+        let error: Box<dyn std::error::Error> = Box::new(error);
+        assert!(error.source().is_none());
+    }
+
+    #[test]
+    fn test_receive_atomic() {
+        let (mut dev_a, mut dev_b) = two_devices_base::<sha2::Sha256>();
+        let recipients_a_b: [DeviceId; 2] = [dev_a.id.clone(), dev_b.id.clone()];
+        let recipients_b_a: [DeviceId; 2] = [dev_b.id.clone(), dev_a.id.clone()];
+
+        // Bob sends a message to Alice, and receives it himself:
+        let message = "See you later!".as_bytes();
+        let message_vp = dev_b.chains.validation_payload(&dev_a.id).unwrap();
+        assert!(message_vp.0 == 2);
+        dev_b
+            .chains
+            .send_message(message, recipients_a_b.iter())
+            .unwrap();
+        let envelope = |validation_payload, recipients| super::Envelope {
+            sender: &recipients_a_b[1],
+            message,
+            recipients,
+            validation_payload,
+            digest_version: None,
+            counters: None,
+            server_seq: None,
+        };
+
+        // A loopback message must never carry a validation payload:
+        let own_envelope = super::Envelope {
+            sender: &dev_b.id,
+            ..envelope(Some(message_vp), &recipients_a_b)
+        };
+        let dump_b = serde_json::to_string(&dev_b.chains).unwrap();
+        assert!(
+            dev_b.chains.receive(own_envelope.clone())
+                == Err(super::Error::LoopbackValidationPayload)
+        );
+        assert!(serde_json::to_string(&dev_b.chains).unwrap() == dump_b);
+        let outcome = dev_b
+            .chains
+            .receive(super::Envelope {
+                validation_payload: None,
+                ..own_envelope
+            })
+            .unwrap()
+            .unwrap();
+        assert!(outcome.local_seq == 3 && outcome.trimmed == 0);
+
+        // If either the validation payload or the message is rejected, Alice's
+        // state remains unchanged:
+        let dump_a = serde_json::to_string(&dev_a.chains).unwrap();
+        let mut tampered_digest = message_vp.1;
+        tampered_digest[0] ^= 1;
+        assert!(matches!(
+            dev_a.chains.receive(envelope(
+                Some((message_vp.0, tampered_digest)),
+                &recipients_a_b
+            )),
+            Err(super::Error::ForkDetected(_))
+        ));
+        assert!(serde_json::to_string(&dev_a.chains).unwrap() == dump_a);
+        assert!(
+            dev_a
+                .chains
+                .receive(envelope(Some(message_vp), &recipients_b_a))
+                == Err(super::Error::InvalidRecipientsOrder {
+                    previous: dev_b.id.clone(),
+                    next: dev_a.id.clone(),
+                })
+        );
+        assert!(serde_json::to_string(&dev_a.chains).unwrap() == dump_a);
+
+        // Otherwise, the validation payload is applied and the message
+        // inserted:
+        let outcome = dev_a
+            .chains
+            .receive(envelope(Some(message_vp), &recipients_a_b))
+            .unwrap()
+            .unwrap();
+        assert!(
+            outcome
+                == super::ReceiveOutcome {
+                    local_seq: 3,
+                    trimmed: 2,
+                    digest_version: DigestVersion::V3,
+                }
+        );
+        assert!(dev_a.chains.message_fully_validated(2).unwrap());
+        assert!(dev_a.chains.validation_payload(&dev_b.id).unwrap().0 == 3);
+    }
+
+    #[test]
+    fn test_receive_duplicate() {
+        let mut dev_a: TestDeviceState = TestDeviceState::new("0".into());
+        let mut dev_b: TestDeviceState = TestDeviceState::new("1".into());
+        let recipients_a_b: [DeviceId; 2] = [dev_a.id.clone(), dev_b.id.clone()];
+
+        let message_0 = "Hi Bob!".as_bytes();
+        let outgoing_0 = dev_a
+            .chains
+            .send_counted_message(message_0, recipients_a_b.iter())
+            .unwrap();
+        let envelope = super::Envelope {
+            sender: &dev_a.id,
+            message: message_0,
+            recipients: &recipients_a_b,
+            validation_payload: None,
+            digest_version: Some(outgoing_0.digest_version),
+            counters: outgoing_0.counters.as_deref(),
+            server_seq: Some(17),
+        };
+        assert!(
+            dev_b
+                .chains
+                .receive(envelope.clone())
+                .unwrap()
+                .unwrap()
+                .local_seq
+                == 0
+        );
+
+        // The server redelivers the message, e.g., after a reconnect. By
+        // default, this is rejected:
+        let dump_b = serde_json::to_string(&dev_b.chains).unwrap();
+        let duplicate = dev_b.chains.receive(envelope.clone()).unwrap_err();
+        assert!(matches!(
+            duplicate,
+            super::Error::DuplicateMessage { ref sender, .. } if *sender == dev_a.id
+        ));
+        assert!(duplicate.to_string().starts_with("duplicate message from"));
+        assert!(serde_json::to_string(&dev_b.chains).unwrap() == dump_b);
+
+        // Alternatively, the message can be ignored, leaving the state
+        // unchanged as well:
+        dev_b.chains.config_mut().duplicate_policy = super::DuplicatePolicy::Ignore;
+        let dump_b = serde_json::to_string(&dev_b.chains).unwrap();
+        assert!(dev_b.chains.receive(envelope.clone()) == Ok(None));
+        assert!(serde_json::to_string(&dev_b.chains).unwrap() == dump_b);
+
+        // A message replayed with a fresh server sequence number is still
+        // recognized by its sender counter, which is covered by its digest:
+        let replayed_envelope = super::Envelope {
+            server_seq: Some(18),
+            ..envelope.clone()
+        };
+        assert!(dev_b.chains.receive(replayed_envelope) == Ok(None));
+
+        // The next message is accepted:
+        let message_1 = "Are you there?".as_bytes();
+        let outgoing_1 = dev_a
+            .chains
+            .send_counted_message(message_1, recipients_a_b.iter())
+            .unwrap();
+        let outcome = dev_b
+            .chains
+            .receive(super::Envelope {
+                message: message_1,
+                counters: outgoing_1.counters.as_deref(),
+                server_seq: Some(19),
+                ..envelope
+            })
+            .unwrap()
+            .unwrap();
+        assert!(outcome.local_seq == 1);
+    }
+
+    #[test]
+    fn test_two_devices_dropped_message_counters() {
+        let (mut dev_a, mut dev_b) = two_devices_base::<sha2::Sha256>();
+        let recipients_a_b: [DeviceId; 2] = [dev_a.id.clone(), dev_b.id.clone()];
+
+        // Alice sends two messages concurrently, including her per-pair
+        // counters:
+        let message_1 = "Hey Bob, please ignore the contents of the next message:".as_bytes();
+        let message_2 = "We're no longer friends.".as_bytes();
+        let outgoing_1 = dev_a
+            .chains
+            .send_counted_message(message_1, recipients_a_b.iter())
+            .unwrap();
+        let outgoing_2 = dev_a
+            .chains
+            .send_counted_message(message_2, recipients_a_b.iter())
+            .unwrap();
+        assert!(outgoing_1.counters == Some(vec![0, 0]));
+        assert!(outgoing_2.counters == Some(vec![1, 1]));
+
+        fn envelope<'a>(
+            recipients: &'a [DeviceId],
+            message: &'a [u8],
+            outgoing: &'a super::OutgoingMessage,
+        ) -> super::Envelope<'a, sha2::Sha256, DeviceId> {
+            super::Envelope {
+                sender: &recipients[0],
+                message,
+                recipients,
+                validation_payload: None,
+                digest_version: Some(outgoing.digest_version),
+                counters: outgoing.counters.as_deref(),
+                server_seq: None,
+            }
+        }
+
+        // The server can't crash Alice by changing the number of counters of
+        // her own message:
+        let dump_a = serde_json::to_string(&dev_a.chains).unwrap();
+        let truncated_outgoing_1 = super::OutgoingMessage {
+            counters: Some(vec![0]),
+            ..outgoing_1.clone()
+        };
+        assert!(
+            dev_a
+                .chains
+                .receive(envelope(&recipients_a_b, message_1, &truncated_outgoing_1))
+                == Err(super::Error::InvalidCounters {
+                    sender: dev_a.id.clone(),
+                    recipients: 2,
+                    counters: 1,
+                    version: outgoing_1.digest_version,
+                })
+        );
+        assert!(serde_json::to_string(&dev_a.chains).unwrap() == dump_a);
+
+        // Alice receives both of her messages:
+        dev_a
+            .chains
+            .receive(envelope(&recipients_a_b, message_1, &outgoing_1))
+            .unwrap();
+        dev_a
+            .chains
+            .receive(envelope(&recipients_a_b, message_2, &outgoing_2))
+            .unwrap();
+
+        // The server drops the first message for Bob, which he notices as
+        // soon as he receives the second one:
+        let dump_b = serde_json::to_string(&dev_b.chains).unwrap();
+        let gap = dev_b
+            .chains
+            .receive(envelope(&recipients_a_b, message_2, &outgoing_2))
+            .unwrap_err();
+        assert!(
+            gap == super::Error::CounterGap {
+                sender: dev_a.id.clone(),
+                expected: 0,
+                received: 1,
+            }
+        );
+        assert!(
+            serde_json::to_value(&gap).unwrap()
+                == serde_json::json!({
+                    "code": "counter_gap",
+                    "details": { "sender": dev_a.id, "expected": 0, "received": 1 },
+                })
+        );
+        assert!(serde_json::to_string(&dev_b.chains).unwrap() == dump_b);
+
+        // The counters are covered by the digests, and hence can't be
+        // changed by the server either:
+        let tampered_outgoing_2 = super::OutgoingMessage {
+            counters: Some(vec![1, 0]),
+            ..outgoing_2.clone()
+        };
+        dev_b
+            .chains
+            .receive(envelope(&recipients_a_b, message_2, &tampered_outgoing_2))
+            .unwrap();
+        let message_b_vp = dev_b.chains.validation_payload(&dev_a.id).unwrap();
+        assert!(matches!(
+            dev_a
+                .chains
+                .validate_chain(&dev_b.id, Some((message_b_vp.0, &message_b_vp.1))),
+            Err(super::Error::ForkDetected(_))
+        ));
+
+        // Nor by attaching counters to a message which hasn't been counted:
+        let message_3 = "No counters this time.".as_bytes();
+        let outgoing_3 = super::OutgoingMessage {
+            digest_version: dev_a
+                .chains
+                .send_message(message_3, recipients_a_b.iter())
+                .unwrap(),
+            counters: Some(vec![2, 2]),
+        };
+        assert!(
+            dev_a
+                .chains
+                .receive(envelope(&recipients_a_b, message_3, &outgoing_3))
+                == Err(super::Error::OwnMessageInvalidReordered {
+                    pending_id: Some(4),
+                })
+        );
+    }
+
+    #[test]
+    fn test_observer_events() {
+        use super::Event;
+
+        let (mut dev_a, mut dev_b) = two_devices_base::<sha2::Sha256>();
+        let recipients_a_b: [DeviceId; 2] = [dev_a.id.clone(), dev_b.id.clone()];
+        let events = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let observed = events.clone();
+        dev_a
+            .chains
+            .set_observer(move |event: &Event<DeviceId>| observed.borrow_mut().push(event.clone()));
+
+        // Bob replies, validating all messages Alice has sent so far:
+        let message_b = "Great!".as_bytes();
+        let (seq, digest) = dev_b.chains.validation_payload(&dev_a.id).unwrap();
+        dev_b
+            .chains
+            .send_message(message_b, recipients_a_b.iter())
+            .unwrap();
+        dev_b
+            .chains
+            .insert_message(&dev_b.id, message_b, recipients_a_b.iter())
+            .unwrap();
+        dev_a
+            .chains
+            .validate_trim_chain(&dev_b.id, Some((seq, &digest)))
+            .unwrap();
+        dev_a
+            .chains
+            .insert_message(&dev_b.id, message_b, recipients_a_b.iter())
+            .unwrap();
+
+        // Alice's next message is received back from the server:
+        let message_a = "See you!".as_bytes();
+        dev_a
+            .chains
+            .send_message(message_a, recipients_a_b.iter())
+            .unwrap();
+        dev_a
+            .chains
+            .insert_message(&dev_a.id, message_a, recipients_a_b.iter())
+            .unwrap();
+
+        // A tampered validation payload is reported as well:
+        let mut tampered_digest = digest;
+        tampered_digest[0] ^= 1;
+        let error = dev_a
+            .chains
+            .validate_chain(&dev_b.id, Some((seq, &tampered_digest)))
+            .unwrap_err();
+
+        let events = events.borrow();
+        assert!(
+            events[..]
+                == [
+                    Event::PeerValidated {
+                        device: dev_b.id.clone(),
+                        local_seq: 2,
+                    },
+                    Event::ChainTrimmed {
+                        device: dev_b.id.clone(),
+                        count: 2,
+                    },
+                    Event::MessageInserted {
+                        sender: dev_b.id.clone(),
+                        local_seq: 3,
+                    },
+                    Event::PendingConfirmed { id: 2 },
+                    Event::MessageInserted {
+                        sender: dev_a.id.clone(),
+                        local_seq: 4,
+                    },
+                    Event::InvariantViolated { error },
+                ]
+        );
+        assert!(
+            serde_json::to_value(&events[1]).unwrap()
+                == serde_json::json!({"event": "chain_trimmed", "device": "1", "count": 2})
+        );
+    }
+
+    #[test]
+    fn test_message_status() {
+        use super::{RecipientStatus, ValidationStatus};
+
+        let (mut dev_a, mut dev_b) = two_devices_base::<sha2::Sha256>();
+        let recipients_a_b: [DeviceId; 2] = [dev_a.id.clone(), dev_b.id.clone()];
+        let status = |device: &DeviceId, status| {
+            vec![RecipientStatus {
+                device: device.clone(),
+                status,
+            }]
+        };
+
+        // Bob has validated Alice's first message, whose record has been
+        // dropped, but not her last one:
+        assert!(dev_a.chains.message_status(0) == Err(super::Error::UnknownMessage(0)));
+        assert!(dev_a.chains.message_fully_validated(0) == Ok(true));
+        assert!(
+            dev_a.chains.message_status(2).unwrap().recipients
+                == status(&dev_b.id, ValidationStatus::Pending)
+        );
+        assert!(dev_a.chains.message_status(3) == Err(super::Error::UnknownMessage(3)));
+        assert!(
+            dev_a
+                .chains
+                .oldest_unvalidated_messages()
+                .into_iter()
+                .collect::<Vec<_>>()
+                == [(dev_b.id.clone(), 1)]
+        );
+
+        // Bob's reply validates all of them, which drops their records along
+        // with their recipients:
+        let message_b = "Great!".as_bytes();
+        let (seq, digest) = dev_b.chains.validation_payload(&dev_a.id).unwrap();
+        dev_b
+            .chains
+            .send_message(message_b, recipients_a_b.iter())
+            .unwrap();
+        dev_a
+            .chains
+            .validate_trim_chain(&dev_b.id, Some((seq, &digest)))
+            .unwrap();
+        dev_a
+            .chains
+            .insert_message(&dev_b.id, message_b, recipients_a_b.iter())
+            .unwrap();
+        assert!(dev_a.chains.memory_usage().message_records == 1);
+        assert!(dev_a.chains.message_status(2) == Err(super::Error::UnknownMessage(2)));
+        assert!(dev_a.chains.message_fully_validated(2) == Ok(true));
+        assert!(
+            dev_a
+                .chains
+                .oldest_unvalidated_messages()
+                .into_iter()
+                .collect::<Vec<_>>()
+                == [(dev_b.id.clone(), 3)]
+        );
+
+        // Records survive a dump, and retired recipients will never validate
+        // any messages:
+        let mut restored: super::Sha256MessageChains =
+            super::MessageChains::from_dump(serde_json::to_value(&dev_a.chains).unwrap(), &mut 0)
+                .unwrap();
+        let recipients_a_b_c: [DeviceId; 3] = [dev_a.id.clone(), dev_b.id.clone(), "2".into()];
+        restored
+            .insert_message(&dev_b.id, message_b, recipients_a_b_c.iter())
+            .unwrap();
+        restored.retire_device(&dev_b.id).unwrap();
+        assert!(restored.message_status(3) == Err(super::Error::UnknownMessage(3)));
+        assert!(
+            restored.message_status(4).unwrap().recipients
+                == [
+                    RecipientStatus {
+                        device: dev_b.id.clone(),
+                        status: ValidationStatus::Retired,
+                    },
+                    RecipientStatus {
+                        device: "2".into(),
+                        status: ValidationStatus::Pending,
+                    },
+                ]
+        );
+        assert!(
+            restored
+                .oldest_unvalidated_messages()
+                .into_iter()
+                .collect::<Vec<_>>()
+                == [("2".into(), 4)]
+        );
+    }
+
+    #[test]
+    fn test_suspect_messages() {
+        let (mut dev_a, dev_b) = two_devices_base::<sha2::Sha256>();
+        let recipients_a_b = vec![dev_a.id.clone(), dev_b.id.clone()];
+
+        // Bob claims a different digest for Alice's last message, so the
+        // server must have altered it or one of the messages before:
+        let (seq, digest) = dev_b.chains.validation_payload(&dev_a.id).unwrap();
+        let mut tampered_digest = digest;
+        tampered_digest[0] ^= 1;
+        assert!(matches!(
+            dev_a
+                .chains
+                .validate_chain(&dev_b.id, Some((seq, &tampered_digest))),
+            Err(super::Error::ForkDetected(_))
+        ));
+
+        // Only Alice's first message has been validated by Bob before:
+        let report = dev_a.chains.suspect_messages(&dev_b.id).unwrap();
+        assert!(report.device == dev_b.id && report.last_validated_local_seq == Some(0));
+        assert!(report.messages.iter().map(|m| m.local_seq).eq([1, 2]));
+        assert!(report
+            .messages
+            .iter()
+            .all(|m| m.recipients == recipients_a_b && m.digest.is_some()));
+        assert!(report.messages[1].seq == Some(seq));
+        assert!(report.messages[1].digest.as_deref() == Some(&digest[..]));
+
+        assert!(
+            dev_a.chains.suspect_messages(&"2".into())
+                == Err(super::Error::UnknownDevice("2".into()))
+        );
+    }
+
+    #[test]
+    fn test_peer_quarantine() {
+        use super::PeerHealth;
+
+        let (mut dev_a, dev_b) = two_devices_base::<sha2::Sha256>();
+        let recipients_a_b: [DeviceId; 2] = [dev_a.id.clone(), dev_b.id.clone()];
+        let (seq, digest) = dev_b.chains.validation_payload(&dev_a.id).unwrap();
+        assert!(dev_a.chains.peer_health(&dev_b.id) == PeerHealth::Healthy);
+
+        // A violation leaves Bob healthy until Alice records it, which makes
+        // him suspicious without refusing any traffic yet:
+        let invalid_seq = dev_a
+            .chains
+            .validate_chain(&dev_b.id, Some((seq + 10, &digest)))
+            .unwrap_err();
+        assert!(matches!(
+            invalid_seq,
+            super::Error::InvalidValidationSeq { .. }
+        ));
+        assert!(dev_a.chains.peer_health(&dev_b.id) == PeerHealth::Healthy);
+        assert!(dev_a.chains.record_violation(&invalid_seq));
+        assert!(dev_a.chains.peer_health(&dev_b.id) == PeerHealth::Suspicious);
+        dev_a
+            .chains
+            .send_message("Still there?".as_bytes(), recipients_a_b.iter())
+            .unwrap();
+
+        // Even a fork counts as a single violation:
+        let fork = dev_a
+            .chains
+            .validate_chain(&dev_b.id, Some((seq, &Hash::<sha2::Sha256>::default())))
+            .unwrap_err();
+        assert!(matches!(fork, super::Error::ForkDetected(_)));
+        assert!(dev_a.chains.record_violation(&fork));
+        assert!(dev_a.chains.peer_health(&dev_b.id) == PeerHealth::Suspicious);
+
+        // Gaps in the counters may be caused by the server, and forks between
+        // third parties are blamed on the forked pair rather than the device
+        // gossiping about them:
+        let gap = super::Error::CounterGap {
+            sender: dev_b.id.clone(),
+            expected: 0,
+            received: 1,
+        };
+        assert!(!dev_a.chains.record_violation(&gap));
+        assert!(!dev_a
+            .chains
+            .record_violation(&super::Error::UnknownMessage(0)));
+        assert!(dev_a
+            .chains
+            .record_violation(&super::Error::ThirdPartyForkDetected(
+                "2".into(),
+                "3".into()
+            )));
+        assert!(dev_a.chains.peer_health(&"2".into()) == PeerHealth::Suspicious);
+        assert!(dev_a.chains.peer_health(&"3".into()) == PeerHealth::Suspicious);
+        assert!(dev_a.chains.peer_health(&dev_b.id) == PeerHealth::Suspicious);
+
+        // The third violation quarantines Bob:
+        assert!(dev_a.chains.record_violation(&invalid_seq));
+        assert!(dev_a.chains.peer_health(&dev_b.id) == PeerHealth::Quarantined);
+        let quarantined = super::Error::PeerQuarantined(dev_b.id.clone());
+        assert!(
+            dev_a
+                .chains
+                .send_message("Hello?".as_bytes(), recipients_a_b.iter())
+                == Err(quarantined.clone())
+        );
+        assert!(
+            dev_a
+                .chains
+                .insert_message(&dev_b.id, "Hi!".as_bytes(), recipients_a_b.iter())
+                == Err(quarantined.clone())
+        );
+        assert!(
+            dev_a.chains.insert_message(
+                &dev_a.id,
+                "Still there?".as_bytes(),
+                recipients_a_b.iter()
+            ) == Err(quarantined)
+        );
+
+        // The quarantine survives a dump, until Bob is recovered explicitly,
+        // which forgets his violations:
+        let mut restored: super::Sha256MessageChains =
+            super::MessageChains::from_dump(serde_json::to_value(&dev_a.chains).unwrap(), &mut 0)
+                .unwrap();
+        assert!(restored.peer_health(&dev_b.id) == PeerHealth::Quarantined);
+        assert!(restored.recover_peer(&dev_b.id));
+        assert!(!restored.recover_peer(&dev_b.id));
+        restored
+            .insert_message(&dev_a.id, "Still there?".as_bytes(), recipients_a_b.iter())
+            .unwrap();
+        restored.record_violation(&fork);
+        assert!(restored.peer_health(&dev_b.id) == PeerHealth::Suspicious);
+
+        // A lower threshold quarantines peers sooner, but never after a
+        // single violation:
+        restored.config_mut().quarantine_threshold = Some(0);
+        restored.record_violation(&fork);
+        assert!(restored.peer_health(&dev_b.id) == PeerHealth::Quarantined);
+
+        // The violations of other peers have survived the dump as well:
+        assert!(restored.peer_health(&"2".into()) == PeerHealth::Suspicious);
+        restored.record_violation(&super::Error::ThirdPartyForkDetected(
+            "2".into(),
+            "3".into(),
+        ));
+        assert!(restored.peer_health(&"2".into()) == PeerHealth::Quarantined);
+    }
+
+    #[test]
+    fn test_pending_message_timeouts() {
+        let mut dev_a: TestDeviceState = TestDeviceState::new("0".into());
+        let mut dev_b: TestDeviceState = TestDeviceState::new("1".into());
+        let recipients_a_b: [DeviceId; 2] = [dev_a.id.clone(), dev_b.id.clone()];
+
+        // Alice sends three messages at different ticks:
+        let messages = ["Hi Bob!", "Are you there?", "Hello?"].map(str::as_bytes);
+        for (tick, message) in messages.iter().enumerate() {
+            dev_a.chains.set_tick(tick as u64);
+            dev_a
+                .chains
+                .send_message(message, recipients_a_b.iter())
+                .unwrap();
+        }
+
+        // None of them has been received back yet. The last message never
+        // reached the server, and can be cancelled:
+        dev_a.chains.set_tick(10);
+        let overdue: Vec<u64> = dev_a
+            .chains
+            .overdue_pending_messages(9)
+            .iter()
+            .map(|pending| pending.id)
+            .collect();
+        assert!(overdue == [0, 1]);
+        assert!(
+            dev_a.chains.cancel_pending_message(1) == Err(super::Error::InvalidPendingOperation(1))
+        );
+        dev_a.chains.cancel_pending_message(2).unwrap();
+        assert!(
+            dev_a.chains.cancel_pending_message(2) == Err(super::Error::UnknownPendingMessage(2))
+        );
+
+        // The server has dropped the first message, and Alice retries the
+        // second one:
+        assert!(
+            dev_a.chains.declare_pending_message_lost(1)
+                == Err(super::Error::InvalidPendingOperation(1))
+        );
+        dev_a.chains.declare_pending_message_lost(0).unwrap();
+        dev_a.chains.retry_pending_message(1).unwrap();
+        assert!(dev_a.chains.overdue_pending_messages(5).is_empty());
+
+        // The second message is received back, and the hash-chain over
+        // Alice's pending messages remains intact for subsequent messages:
+        for chains in [&mut dev_a.chains, &mut dev_b.chains] {
+            chains
+                .insert_message(&dev_a.id, messages[1], recipients_a_b.iter())
+                .unwrap();
+        }
+        dev_a
+            .chains
+            .send_message(messages[2], recipients_a_b.iter())
+            .unwrap();
+        assert!(dev_a.chains.pending_messages()[0].id == 2);
+        for chains in [&mut dev_a.chains, &mut dev_b.chains] {
+            chains
+                .insert_message(&dev_a.id, messages[2], recipients_a_b.iter())
+                .unwrap();
+        }
+        assert!(dev_a.chains.pending_messages().is_empty());
+
+        // Cancelling a counted message rolls back its counters:
+        let outgoing = dev_a
+            .chains
+            .send_counted_message(messages[0], recipients_a_b.iter())
+            .unwrap();
+        dev_a.chains.cancel_pending_message(3).unwrap();
+        assert!(
+            dev_a
+                .chains
+                .send_counted_message(messages[0], recipients_a_b.iter())
+                .unwrap()
+                == outgoing
+        );
+
+        // Counters of a lost message are not rolled back. Bob hence notices
+        // the gap once he receives the next counted message:
+        let lost = dev_a.chains.pending_messages()[0].id;
+        dev_a.chains.declare_pending_message_lost(lost).unwrap();
+        let next = dev_a
+            .chains
+            .send_counted_message(messages[1], recipients_a_b.iter())
+            .unwrap();
+        assert!(next.counters == Some(vec![1, 1]));
+        let envelope = super::Envelope {
+            sender: &dev_a.id,
+            message: messages[1],
+            recipients: &recipients_a_b,
+            validation_payload: None,
+            digest_version: Some(next.digest_version),
+            counters: next.counters.as_deref(),
+            server_seq: None,
+        };
+        assert!(
+            dev_b.chains.receive(envelope.clone())
+                == Err(super::Error::CounterGap {
+                    sender: dev_a.id.clone(),
+                    expected: 0,
+                    received: 1,
+                })
+        );
+
+        // Counted messages must list their sender as a recipient, whose
+        // pairwise chain tracks the received counter:
+        assert!(
+            dev_b.chains.receive(super::Envelope {
+                recipients: &recipients_a_b[1..],
+                counters: Some(&[1]),
+                ..envelope
+            }) == Err(super::Error::InvalidCounters {
+                sender: dev_a.id.clone(),
+                recipients: 1,
+                counters: 1,
+                version: next.digest_version,
+            })
+        );
+    }
+
+    #[test]
+    fn test_chain_limits() {
+        let mut dev_a: TestDeviceState = TestDeviceState::new("0".into());
+        let mut dev_b: TestDeviceState = TestDeviceState::new("1".into());
+        let recipients_a_b: [DeviceId; 2] = [dev_a.id.clone(), dev_b.id.clone()];
+        dev_b.chains.config_mut().max_chain_len = Some(2);
+
+        // Alice sends four messages to Bob, who never replies. Alice
+        // records her validation payload after each message:
+        let messages = ["Hi Bob!", "Are you there?", "Hello?", "Bob?"].map(str::as_bytes);
+        let mut validation_payloads = Vec::new();
+        for message in messages {
+            dev_a
+                .chains
+                .send_message(message, recipients_a_b.iter())
+                .unwrap();
+            dev_a
+                .chains
+                .insert_message(&dev_a.id, message, recipients_a_b.iter())
+                .unwrap();
+            validation_payloads.push(dev_a.chains.validation_payload(&dev_b.id).unwrap());
+        }
+
+        // By default, Bob rejects messages once his chain with Alice is
+        // full:
+        for message in &messages[..2] {
+            dev_b
+                .chains
+                .insert_message(&dev_a.id, message, recipients_a_b.iter())
+                .unwrap();
+        }
+        assert!(
+            dev_b
+                .chains
+                .insert_message(&dev_a.id, messages[2], recipients_a_b.iter())
+                == Err(super::Error::ChainLimitExceeded {
+                    device: Some(dev_a.id.clone()),
+                    limit: 2,
+                })
+        );
+
+        // Alternatively, he compacts the oldest entries into a checkpoint:
+        dev_b.chains.config_mut().limit_policy = super::LimitPolicy::Compact;
+        for message in &messages[2..] {
+            dev_b
+                .chains
+                .insert_message(&dev_a.id, message, recipients_a_b.iter())
+                .unwrap();
+        }
+        let usage = dev_b.chains.memory_usage();
+        assert!(usage.devices.len() == 1 && usage.devices[0].device == dev_a.id);
+        assert!(usage.devices[0].chain_entries == 2 && usage.chain_entries == 2);
+        assert!(usage.pending_messages == 0);
+
+        // The records of the oldest messages are dropped as well, so their
+        // status is no longer known:
+        assert!(usage.message_records == 2);
+        assert!(dev_b.chains.message_fully_validated(0) == Err(super::Error::UnknownMessage(0)));
+        assert!(dev_b.chains.message_fully_validated(1) == Err(super::Error::UnknownMessage(1)));
+
+        // Validation payloads referring to dropped entries can't be checked
+        // and are ignored, except for the checkpoint:
+        let (seq_0, digest_0) = validation_payloads[0];
+        dev_b
+            .chains
+            .validate_chain(&dev_a.id, Some((seq_0, &digest_0)))
+            .unwrap();
+        assert!(!dev_b.chains.device_validated_event(&dev_a.id, 0).unwrap());
+
+        let (seq_1, digest_1) = validation_payloads[1];
+        let mut tampered_digest_1 = digest_1;
+        tampered_digest_1[0] ^= 1;
+        assert!(matches!(
+            dev_b
+                .chains
+                .validate_chain(&dev_a.id, Some((seq_1, &tampered_digest_1))),
+            Err(super::Error::ForkDetected(_))
+        ));
+        dev_b
+            .chains
+            .validate_chain(&dev_a.id, Some((seq_1, &digest_1)))
+            .unwrap();
+        assert!(dev_b.chains.device_validated_event(&dev_a.id, 1).unwrap());
+
+        // Later entries are still validated as usual:
+        let (seq_3, digest_3) = validation_payloads[3];
+        let trimmed = dev_b
+            .chains
+            .validate_trim_chain(&dev_a.id, Some((seq_3, &digest_3)))
+            .unwrap();
+        assert!(trimmed == 1);
+        assert!(dev_b.chains.message_fully_validated(3).unwrap());
+    }
+
+    #[test]
+    fn test_message_record_limits() {
+        let mut dev_a: TestDeviceState = TestDeviceState::new("0".into());
+        let mut dev_b: TestDeviceState = TestDeviceState::new("1".into());
+        let recipients_a_b: [DeviceId; 2] = [dev_a.id.clone(), dev_b.id.clone()];
+        let recipients_a_b_c: [DeviceId; 3] = [dev_a.id.clone(), dev_b.id.clone(), "2".into()];
+        dev_b.chains.config_mut().max_total_chain_len = Some(3);
+
+        // Alice sends a message to Bob and Carol, followed by messages to Bob
+        // only, which Bob validates as they arrive. Carol never replies, so
+        // the records of all messages since her last one are kept:
+        let messages = ["Hi all!", "Hi Bob!", "Are you there?", "Bob?"].map(str::as_bytes);
+        for (index, message) in messages.into_iter().enumerate() {
+            let recipients = match index {
+                0 => &recipients_a_b_c[..],
+                _ => &recipients_a_b[..],
+            };
+            dev_a
+                .chains
+                .send_message(message, recipients.iter())
+                .unwrap();
+            dev_a
+                .chains
+                .insert_message(&dev_a.id, message, recipients.iter())
+                .unwrap();
+            let (seq, digest) = dev_a.chains.validation_payload(&dev_b.id).unwrap();
+
+            let inserted = dev_b
+                .chains
+                .insert_message(&dev_a.id, message, recipients.iter());
+            if index < 3 {
+                inserted.unwrap();
+                dev_b
+                    .chains
+                    .validate_trim_chain(&dev_a.id, Some((seq, &digest)))
+                    .unwrap();
+            } else {
+                // The chains themselves hold few entries, but Bob rejects
+                // further messages rather than keeping more records:
+                assert!(dev_b.chains.memory_usage().chain_entries == 2);
+                assert!(inserted == Err(super::Error::MessageRecordLimitExceeded { limit: 3 }));
+            }
+        }
+
+        // Alternatively, he evicts the oldest record:
+        dev_b.chains.config_mut().limit_policy = super::LimitPolicy::Evict;
+        dev_b
+            .chains
+            .insert_message(&dev_a.id, messages[3], recipients_a_b.iter())
+            .unwrap();
+        assert!(dev_b.chains.memory_usage().message_records == 3);
+        assert!(dev_b.chains.message_status(0) == Err(super::Error::UnknownMessage(0)));
+        assert!(dev_b.chains.message_fully_validated(0) == Err(super::Error::UnknownMessage(0)));
+        assert!(dev_b.chains.message_status(1).is_ok());
+    }
+
+    #[test]
+    fn test_retire_device() {
+        let mut dev_a: TestDeviceState = TestDeviceState::new("0".into());
+        let mut dev_b: TestDeviceState = TestDeviceState::new("1".into());
+        let mut dev_c: TestDeviceState = TestDeviceState::new("2".into());
+        let recipients_a_b_c: [DeviceId; 3] =
+            [dev_a.id.clone(), dev_b.id.clone(), dev_c.id.clone()];
+
+        // Alice sends two messages to Bob and Charlie. Only Bob validates
+        // them, while Charlie only validates the first one:
+        let messages = ["Hi all!", "Anyone there?"].map(str::as_bytes);
+        for message in messages {
+            dev_a
+                .chains
+                .send_message(message, recipients_a_b_c.iter())
+                .unwrap();
+            for dev in [&mut dev_a, &mut dev_b, &mut dev_c] {
+                dev.chains
+                    .insert_message(&recipients_a_b_c[0], message, recipients_a_b_c.iter())
+                    .unwrap();
+            }
+
+            if message == messages[0] {
+                let message_c_vp = dev_c.chains.validation_payload(&dev_a.id).unwrap();
+                dev_a
+                    .chains
+                    .validate_trim_chain(&dev_c.id, Some((message_c_vp.0, &message_c_vp.1)))
+                    .unwrap();
+            }
+        }
+        let message_b_vp = dev_b.chains.validation_payload(&dev_a.id).unwrap();
+        dev_a
+            .chains
+            .validate_trim_chain(&dev_b.id, Some((message_b_vp.0, &message_b_vp.1)))
+            .unwrap();
+        assert!(!dev_a.chains.message_fully_validated(1).unwrap());
+        let farewell = "Bye Charlie!".as_bytes();
+        dev_a
+            .chains
+            .send_message(farewell, recipients_a_b_c.iter())
+            .unwrap();
+
+        // Charlie is removed as a contact. The report lists the message he
+        // has never validated, which no longer requires his validation:
+        let report = dev_a.chains.retire_device(&dev_c.id).unwrap();
+        assert!(report.device == dev_c.id);
+        assert!(report.last_validated_local_seq == Some(0));
+        assert!(report.unvalidated_local_seqs == [1]);
+        assert!(dev_a.chains.message_fully_validated(1).unwrap());
+        assert!(dev_a.chains.memory_usage().devices.len() == 1);
+
+        // Messages to Charlie are refused until he is re-added:
+        let message = "Charlie, are you still there?".as_bytes();
+        assert!(
+            dev_a
+                .chains
+                .insert_message(&dev_b.id, message, recipients_a_b_c.iter())
+                == Err(super::Error::RetiredRecipient(dev_c.id.clone()))
+        );
+        assert!(
+            dev_a.chains.send_message(message, recipients_a_b_c.iter())
+                == Err(super::Error::RetiredRecipient(dev_c.id.clone()))
+        );
+
+        // Alice's message sent before is still received back, without
+        // starting a new chain with Charlie:
+        assert!(
+            dev_a
+                .chains
+                .insert_message(&dev_a.id, farewell, recipients_a_b_c.iter())
+                == Ok(2)
+        );
+        assert!(dev_a.chains.pending_messages().is_empty());
+        assert!(dev_a.chains.memory_usage().devices.len() == 1);
+
+        // Charlie must start over with Alice as well before he can be
+        // re-added:
+        assert!(
+            dev_a
+                .chains
+                .unretire_device(&dev_c.id, dev_c.chains.validation_payload(&dev_a.id))
+                == Err(super::Error::PeerNotReset(dev_c.id.clone()))
+        );
+        dev_c.chains.retire_device(&dev_a.id).unwrap();
+        assert!(
+            dev_a
+                .chains
+                .unretire_device(&dev_c.id, dev_c.chains.validation_payload(&dev_a.id))
+                == Ok(true)
+        );
+        assert!(
+            dev_a
+                .chains
+                .insert_message(&dev_b.id, message, recipients_a_b_c.iter())
+                == Ok(3)
+        );
+    }
+
+    #[test]
+    fn test_cancel_pending_after_unretire() {
+        let (mut dev_a, mut dev_b) = two_devices_base::<sha2::Sha256>();
+        let recipients_a_b: [DeviceId; 2] = [dev_a.id.clone(), dev_b.id.clone()];
+        dev_a.chains.config_mut().digest_version = super::DigestVersion::V3;
+
+        // Alice counts a message for Bob, who is then retired and re-added
+        // before she receives it back:
+        let counted = dev_a
+            .chains
+            .send_counted_message("Counted".as_bytes(), recipients_a_b.iter())
+            .unwrap();
+        assert!(counted.counters == Some(vec![0, 0]));
+        let pending_id = dev_a.chains.pending_messages()[0].id;
+        dev_a.chains.retire_device(&dev_b.id).unwrap();
+        dev_b.chains.retire_device(&dev_a.id).unwrap();
+        dev_a
+            .chains
+            .unretire_device(&dev_b.id, None::<(usize, &Hash)>)
+            .unwrap();
+        dev_b
+            .chains
+            .unretire_device(&dev_a.id, None::<(usize, &Hash)>)
+            .unwrap();
+
+        // Bob's next message starts a fresh chain, whose counter doesn't
+        // include the pending message:
+        let message = "Hi again".as_bytes();
+        dev_b
+            .chains
+            .send_message(message, recipients_a_b.iter())
+            .unwrap();
+        dev_a
+            .chains
+            .insert_message(&dev_b.id, message, recipients_a_b.iter())
+            .unwrap();
+
+        // Cancelling it only rolls back the counters which include it:
+        dev_a.chains.cancel_pending_message(pending_id).unwrap();
+        let counted = dev_a
+            .chains
+            .send_counted_message("Counted again".as_bytes(), recipients_a_b.iter())
+            .unwrap();
+        assert!(counted.counters == Some(vec![0, 0]));
+    }
+
+    #[test]
+    fn test_retire_unretire_exchange() {
+        use super::PeerHealth;
+
+        let (mut dev_a, mut dev_b) = two_devices_base::<sha2::Sha256>();
+        let recipients_a_b: [DeviceId; 2] = [dev_a.id.clone(), dev_b.id.clone()];
+
+        // Bob has misbehaved, but retiring him forgets about it along with
+        // his chain:
+        let (seq, digest) = dev_b.chains.validation_payload(&dev_a.id).unwrap();
+        let error = dev_a
+            .chains
+            .validate_chain(&dev_b.id, Some((seq + 10, &digest)))
+            .unwrap_err();
+        assert!(dev_a.chains.record_violation(&error));
+        assert!(dev_a.chains.peer_health(&dev_b.id) == PeerHealth::Suspicious);
+        dev_a.chains.retire_device(&dev_b.id).unwrap();
+        assert!(dev_a.chains.peer_health(&dev_b.id) == PeerHealth::Healthy);
+
+        // Bob still has his chain with Alice, which her fresh one wouldn't
+        // match:
+        assert!(
+            dev_a
+                .chains
+                .unretire_device(&dev_b.id, dev_b.chains.validation_payload(&dev_a.id))
+                == Err(super::Error::PeerNotReset(dev_b.id.clone()))
+        );
+        assert!(dev_a.chains.is_retired(&dev_b.id));
+
+        // Once both have started over, they can be re-added on either side:
+        dev_b.chains.retire_device(&dev_a.id).unwrap();
+        assert!(
+            dev_b
+                .chains
+                .unretire_device(&dev_a.id, dev_a.chains.validation_payload(&dev_b.id))
+                == Ok(true)
+        );
+        assert!(
+            dev_a
+                .chains
+                .unretire_device(&dev_b.id, dev_b.chains.validation_payload(&dev_a.id))
+                == Ok(true)
+        );
+        assert!(
+            dev_a
+                .chains
+                .unretire_device(&dev_b.id, None::<(usize, &Hash)>)
+                == Ok(false)
+        );
+
+        // The fresh chains match, so messages are validated and trimmed as
+        // before:
+        let mut trimmed = 0;
+        for round in 0..4 {
+            let (sender, receiver) = if round % 2 == 0 {
+                (&mut dev_a, &mut dev_b)
+            } else {
+                (&mut dev_b, &mut dev_a)
+            };
+            let message = format!("Message {}", round);
+            let validation_payload = sender.chains.validation_payload(&receiver.id);
+            sender
+                .chains
+                .send_message(message.as_bytes(), recipients_a_b.iter())
+                .unwrap();
+            sender
+                .chains
+                .validate_trim_chain(&sender.id, None::<(usize, &Hash)>)
+                .unwrap();
+            sender
+                .chains
+                .insert_message(&sender.id, message.as_bytes(), recipients_a_b.iter())
+                .unwrap();
+
+            trimmed += receiver
+                .chains
+                .validate_trim_chain(&sender.id, validation_payload)
+                .unwrap();
+            receiver
+                .chains
+                .insert_message(&sender.id, message.as_bytes(), recipients_a_b.iter())
+                .unwrap();
+        }
+        assert!(trimmed > 0);
+        assert!(dev_a.chains.peer_health(&dev_b.id) == PeerHealth::Healthy);
+        assert!(dev_b.chains.peer_health(&dev_a.id) == PeerHealth::Healthy);
+    }
+
+    #[test]
+    fn test_membership_oracle() {
+        let mut dev_a: TestDeviceState = TestDeviceState::new("0".into());
+        let recipients: [DeviceId; 3] = ["0".into(), "1".into(), "fabricated".into()];
+
+        // Alice only knows about Bob:
+        let known_devices: std::collections::HashSet<DeviceId> = ["1".into()].into();
+        dev_a.chains.set_membership_oracle(known_devices);
+
+        // A message naming an unknown recipient is refused, without
+        // allocating a chain for any of its recipients:
+        let message = "Hi all!".as_bytes();
+        assert!(
+            dev_a
+                .chains
+                .insert_message(&recipients[1], message, recipients.iter())
+                == Err(super::Error::UnknownRecipient("fabricated".into()))
+        );
+        assert!(dev_a.chains.memory_usage().devices.is_empty());
+
+        // Neither are messages sent to it, whether they are counted or not,
+        // nor its advertised digest version recorded:
+        let unknown = Err(super::Error::UnknownRecipient("fabricated".into()));
+        assert!(
+            dev_a
+                .chains
+                .send_message(message, recipients.iter())
+                .map(|_| ())
+                == unknown
+        );
+        for version in [super::DigestVersion::V2, super::DigestVersion::V3] {
+            dev_a.chains.config_mut().digest_version = version;
+            assert!(
+                dev_a
+                    .chains
+                    .send_counted_message(message, recipients.iter())
+                    .map(|_| ())
+                    == unknown
+            );
+        }
+        assert!(dev_a.chains.pending_messages().is_empty());
+        dev_a
+            .chains
+            .set_peer_digest_version(&recipients[2], super::DigestVersion::V3);
+        assert!(dev_a.chains.memory_usage().devices.is_empty());
+        dev_a.chains.config_mut().digest_version = super::DigestVersion::default();
+
+        dev_a
+            .chains
+            .insert_message(&recipients[1], message, recipients[..2].iter())
+            .unwrap();
+
+        // The oracle may also be backed by application data. Our own message
+        // sent before Bob became unknown is still received back:
+        dev_a
+            .chains
+            .send_message(message, recipients[..2].iter())
+            .unwrap();
+        dev_a
+            .chains
+            .set_membership_oracle(|device: &DeviceId| device.len() > 1);
+        dev_a
+            .chains
+            .insert_message(&recipients[0], message, recipients[..2].iter())
+            .unwrap();
+        assert!(
+            dev_a
+                .chains
+                .insert_message(&recipients[1], message, recipients.iter())
+                == Err(super::Error::UnknownRecipient("1".into()))
+        );
+        dev_a.chains.clear_membership_oracle();
+        dev_a
+            .chains
+            .insert_message(&recipients[1], message, recipients.iter())
+            .unwrap();
+    }
+
+    /// Let Alice, whose state is persisted, exchange messages with Bob and
+    /// Charlie, committing after every call.
+    fn persisted_exchange(dev_a: &mut super::Sha256MessageChains) {
+        let mut dev_b = super::Sha256MessageChains::<DeviceId>::new("1".into());
+        let [alice, bob, charlie]: [DeviceId; 3] = ["0".into(), "1".into(), "2".into()];
+        let recipients_a_b = [alice.clone(), bob.clone()];
+        let recipients_a_b_c = [alice.clone(), bob.clone(), charlie.clone()];
+
+        // Changes to the configuration are persisted as well:
+        dev_a.config_mut().max_chain_len = Some(3);
+        dev_a.config_mut().limit_policy = super::LimitPolicy::Compact;
+        dev_a.commit().unwrap();
+
+        for round in 0..6 {
+            dev_a.set_tick(round);
+            let message = format!("Alice {}", round);
+            let outgoing = dev_a
+                .send_counted_message(message.as_bytes(), recipients_a_b.iter())
+                .unwrap();
+            dev_a.commit().unwrap();
+            for chains in [&mut *dev_a, &mut dev_b] {
+                let envelope = super::Envelope {
+                    sender: &alice,
+                    message: message.as_bytes(),
+                    recipients: &recipients_a_b,
+                    validation_payload: None,
+                    digest_version: Some(outgoing.digest_version),
+                    counters: outgoing.counters.as_deref(),
+                    server_seq: Some(2 * round),
+                };
+                chains.receive(envelope).unwrap();
+            }
+            dev_a.commit().unwrap();
+
+            // Bob's replies are shared with Charlie, whose chain with Alice
+            // exceeds its limit and is compacted:
+            let message = format!("Bob {}", round);
+            let validation_payload = dev_b.validation_payload(&alice);
+            dev_b
+                .send_message(message.as_bytes(), recipients_a_b_c.iter())
+                .unwrap();
+            dev_b
+                .insert_message(&bob, message.as_bytes(), recipients_a_b_c.iter())
+                .unwrap();
+            dev_a
+                .receive(super::Envelope {
+                    sender: &bob,
+                    message: message.as_bytes(),
+                    recipients: &recipients_a_b_c,
+                    validation_payload,
+                    digest_version: None,
+                    counters: None,
+                    server_seq: Some(2 * round + 1),
+                })
+                .unwrap();
+            dev_a.commit().unwrap();
+        }
+
+        for message in ["Lost", "Retried", "Cancelled"] {
+            dev_a
+                .send_counted_message(message.as_bytes(), recipients_a_b.iter())
+                .unwrap();
+        }
+        let pending: Vec<u64> = dev_a.pending_messages().iter().map(|p| p.id).collect();
+        dev_a.set_tick(10);
+        dev_a.declare_pending_message_lost(pending[0]).unwrap();
+        dev_a.retry_pending_message(pending[1]).unwrap();
+        dev_a.cancel_pending_message(pending[2]).unwrap();
+        dev_a.commit().unwrap();
+
+        dev_a.retire_device(&charlie).unwrap();
+        dev_a.commit().unwrap();
+        dev_a
+            .unretire_device(&charlie, None::<(usize, &Hash)>)
+            .unwrap();
+        dev_a.commit().unwrap();
+    }
+
+    #[test]
+    fn test_store_recovery() {
+        let store = super::MemoryStore::with_snapshot_interval(4);
+        let mut dev_a = super::Sha256MessageChains::<DeviceId>::new("0".into());
+        dev_a.attach_store(store.clone()).unwrap();
+        persisted_exchange(&mut dev_a);
+
+        // Snapshots have been taken periodically, rather than logging all
+        // operations:
+        assert!(store.logged_batches() < 4);
+
+        // Operations which have not been committed are lost in a crash:
+        let committed = serde_json::to_value(&dev_a).unwrap();
+        dev_a.set_tick(100);
+        let recovered =
+            super::Sha256MessageChains::<DeviceId>::recover(store.clone(), &mut 0).unwrap();
+        assert!(serde_json::to_value(&recovered).unwrap() == committed);
+
+        // Every commit has started a new epoch, so a store which has been
+        // rolled back is detected:
+        let mut counter = recovered.epoch() + 1;
+        assert!(matches!(
+            super::Sha256MessageChains::<DeviceId>::recover(store, &mut counter),
+            Err(super::StoreError::Dump(super::DumpError::Rollback { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_store_corrupt_log() {
+        let store = super::MemoryStore::new();
+        let mut dev_a = super::Sha256MessageChains::<DeviceId>::new("0".into());
+        dev_a.attach_store(store.clone()).unwrap();
+        persisted_exchange(&mut dev_a);
+        drop(dev_a);
+
+        // Operations which don't match the state they are replayed onto are
+        // refused, rather than creating state which was never admitted:
+        let corrupted: [super::Operation<sha2::Sha256, DeviceId>; 4] = [
+            super::Operation::AppendEntry {
+                device: "fabricated".into(),
+                local_seq: 0,
+                version: super::DigestVersion::V3,
+                digest: Default::default(),
+            },
+            super::Operation::SetSentCounter {
+                device: "fabricated".into(),
+                counter: 1,
+            },
+            super::Operation::SetPeerDigestVersion {
+                device: "fabricated".into(),
+                version: Some(super::DigestVersion::V3),
+            },
+            super::Operation::SetPendingTick { id: 1000, tick: 0 },
+        ];
+        let stored = super::ChainStore::<sha2::Sha256, DeviceId>::load(&mut store.clone())
+            .unwrap()
+            .unwrap();
+        for operation in corrupted {
+            let mut store = store.clone();
+            super::ChainStore::<sha2::Sha256, DeviceId>::snapshot(&mut store, &stored.snapshot)
+                .unwrap();
+            let mut operations = stored.operations.clone();
+            operations.push(operation);
+            super::ChainStore::append(&mut store, &operations).unwrap();
+            assert!(matches!(
+                super::Sha256MessageChains::<DeviceId>::recover(store, &mut 0),
+                Err(super::StoreError::Corrupt(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_file_store_torn_tail() {
+        use std::io::Write;
+
+        let dir = std::env::temp_dir().join(format!("messagechains-store-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let open = || super::FileStore::open(&dir).unwrap();
+        assert!(matches!(
+            super::Sha256MessageChains::<DeviceId>::recover(open(), &mut 0),
+            Err(super::StoreError::Empty)
+        ));
+
+        let mut dev_a = super::Sha256MessageChains::<DeviceId>::new("0".into());
+        dev_a.attach_store(open()).unwrap();
+        persisted_exchange(&mut dev_a);
+        let committed = serde_json::to_value(&dev_a).unwrap();
+
+        // A crash while appending the next batch leaves a torn frame behind,
+        // which is discarded:
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join("log"))
+            .unwrap()
+            .write_all(&[42, 0, 0, 0, 1, 2, 3])
+            .unwrap();
+        drop(dev_a);
+        let mut recovered =
+            super::Sha256MessageChains::<DeviceId>::recover(open(), &mut 0).unwrap();
+        assert!(serde_json::to_value(&recovered).unwrap() == committed);
+
+        // Batches appended after the recovery are kept:
+        recovered.set_tick(100);
+        recovered.commit().unwrap();
+        let committed = serde_json::to_value(&recovered).unwrap();
+        drop(recovered);
+        let recovered = super::Sha256MessageChains::<DeviceId>::recover(open(), &mut 0).unwrap();
+        assert!(serde_json::to_value(&recovered).unwrap() == committed);
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
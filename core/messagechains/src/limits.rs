@@ -0,0 +1,250 @@
+use std::borrow::Borrow;
+use std::mem::size_of;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ChainEntry, DeviceIdentifier, DeviceState, DigestAlgorithm, Error, MessageChains, Operation,
+};
+
+/// How to handle inserting a message which would exceed
+/// [`crate::Config::max_chain_len`] or [`crate::Config::max_total_chain_len`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LimitPolicy {
+    /// Reject the message with [`Error::ChainLimitExceeded`], or
+    /// [`Error::MessageRecordLimitExceeded`].
+    #[default]
+    Reject,
+    /// Drop the oldest entries, starting with the chain holding the oldest
+    /// entry overall. Validation payloads referring to dropped entries are
+    /// ignored, as they can no longer be checked. The oldest message records
+    /// are dropped as well, after which the validation status of their
+    /// messages is unknown.
+    Evict,
+    /// Like [`LimitPolicy::Evict`], but keep the digest of the last dropped
+    /// entry of each chain as a checkpoint, which can still be validated
+    /// and serves as the base of [`crate::ForkEvidence`].
+    Compact,
+}
+
+/// Approximate memory used for the pairwise chain with a single device.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeviceMemoryUsage<I: DeviceIdentifier> {
+    pub device: I,
+    pub chain_entries: usize,
+    pub bytes: usize,
+}
+
+/// Approximate memory used by a [`MessageChains`] instance.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MemoryUsage<I: DeviceIdentifier> {
+    /// Per-device usage, sorted by device.
+    pub devices: Vec<DeviceMemoryUsage<I>>,
+    pub chain_entries: usize,
+    pub pending_messages: usize,
+    pub message_records: usize,
+    /// Total bytes, including the pending messages and message records.
+    pub bytes: usize,
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> MessageChains<D, I> {
+    fn total_chain_len(&self) -> usize {
+        self.chains.values().map(|chain| chain.chain.len()).sum()
+    }
+
+    /// The number of message records which may be kept, given `new_chains`
+    /// pairwise chains besides the existing ones. This is the number of
+    /// entries all chains may hold together.
+    fn max_message_records(&self, new_chains: usize) -> Option<usize> {
+        let chains = self.chains.len() + new_chains;
+        let per_chain = self
+            .config
+            .max_chain_len
+            .map(|max| std::cmp::max(max, 1).saturating_mul(chains));
+        match (per_chain, self.config.max_total_chain_len) {
+            (Some(per_chain), Some(total)) => Some(std::cmp::min(per_chain, total)),
+            (per_chain, total) => per_chain.or(total),
+        }
+    }
+
+    /// Check whether inserting a message with the given recipients would
+    /// exceed the configured limits, if those are not to be enforced by
+    /// dropping entries.
+    pub(crate) fn check_limits<BD: Borrow<I>>(&self, recipients: &[BD]) -> Result<(), Error<I>> {
+        if self.config.limit_policy != LimitPolicy::Reject {
+            return Ok(());
+        }
+
+        let mut new_entries = 0;
+        let mut new_chains = 0;
+        for r in recipients
+            .iter()
+            .map(Borrow::<I>::borrow)
+            .filter(|r| **r != self.own_device && !self.retired.contains(*r))
+        {
+            new_chains += !self.chains.contains_key(r) as usize;
+            let chain_len = self
+                .chains
+                .get(r)
+                .map(|chain| chain.chain.len())
+                .unwrap_or(0);
+            if let Some(max) = self.config.max_chain_len.filter(|max| chain_len >= *max) {
+                log::debug!("check_limits: chain with {:?} is full", r);
+                return Err(Error::ChainLimitExceeded {
+                    device: Some(r.clone()),
+                    limit: max,
+                });
+            }
+            new_entries += 1;
+        }
+
+        if let Some(max) = self
+            .config
+            .max_total_chain_len
+            .filter(|max| self.total_chain_len() + new_entries > *max)
+        {
+            log::debug!("check_limits: total chain length limit exceeded");
+            return Err(Error::ChainLimitExceeded {
+                device: None,
+                limit: max,
+            });
+        }
+
+        if let Some(max) = self
+            .max_message_records(new_chains)
+            .filter(|max| self.messages.len() >= *max)
+        {
+            log::debug!("check_limits: message record limit exceeded");
+            return Err(Error::MessageRecordLimitExceeded { limit: max });
+        }
+
+        Ok(())
+    }
+
+    /// Drop entries to satisfy the configured limits, according to the
+    /// [`LimitPolicy`]. The newest entry of each chain is always kept, such
+    /// that a validation payload can be sent.
+    pub(crate) fn enforce_limits(&mut self) {
+        let compact = match self.config.limit_policy {
+            LimitPolicy::Reject => return,
+            LimitPolicy::Evict => false,
+            LimitPolicy::Compact => true,
+        };
+
+        if let Some(max) = self.config.max_chain_len {
+            let excess: Vec<_> = self
+                .chains
+                .iter()
+                .map(|(device, chain)| {
+                    (
+                        device,
+                        chain.chain.len().saturating_sub(std::cmp::max(max, 1)),
+                    )
+                })
+                .filter(|(_, excess)| *excess > 0)
+                .map(|(device, excess)| (device.clone(), excess))
+                .collect();
+            for (device, count) in excess {
+                log::debug!(
+                    "enforce_limits: dropping {} entries for {:?}",
+                    count,
+                    device
+                );
+                self.apply(Operation::DropEntries {
+                    device,
+                    count,
+                    compact,
+                });
+            }
+        }
+
+        if let Some(max) = self.config.max_total_chain_len {
+            let mut total = self.total_chain_len();
+            while total > max {
+                // Drop from the chain holding the oldest entry:
+                let oldest = self
+                    .chains
+                    .iter()
+                    .filter(|(_, chain)| chain.chain.len() > 1)
+                    .min_by_key(|(_, chain)| chain.chain[0].local_seq);
+                let device = match oldest {
+                    Some((device, _)) => device.clone(),
+                    None => break,
+                };
+
+                log::debug!("enforce_limits: dropping oldest entry for {:?}", device);
+                self.apply(Operation::DropEntries {
+                    device,
+                    count: 1,
+                    compact,
+                });
+                total -= 1;
+            }
+        }
+
+        let excess = self
+            .max_message_records(0)
+            .map(|max| self.messages.len().saturating_sub(max))
+            .unwrap_or(0);
+        if excess > 0 {
+            log::debug!("enforce_limits: dropping {} message records", excess);
+            self.apply(Operation::EvictMessages { count: excess });
+        }
+    }
+
+    /// Report the approximate memory used for all pairwise chains, pending
+    /// messages and message records.
+    pub fn memory_usage(&self) -> MemoryUsage<I> {
+        let mut devices: Vec<DeviceMemoryUsage<I>> = self
+            .chains
+            .iter()
+            .map(|(device, chain)| DeviceMemoryUsage {
+                device: device.clone(),
+                chain_entries: chain.chain.len(),
+                bytes: size_of::<DeviceState<D>>()
+                    + device.canonical_bytes().len()
+                    + chain.chain.len() * size_of::<ChainEntry<D>>(),
+            })
+            .collect();
+        devices.sort_by(|a, b| a.device.cmp(&b.device));
+
+        let pending_bytes = self.pending_messages.len() * size_of::<crate::PendingMessage<D, I>>();
+        let records_bytes: usize = self
+            .messages
+            .iter()
+            .map(|record| {
+                size_of::<crate::MessageRecord<I>>()
+                    + record
+                        .recipients
+                        .iter()
+                        .map(|r| size_of::<I>() + r.canonical_bytes().len())
+                        .sum::<usize>()
+            })
+            .sum();
+
+        MemoryUsage {
+            chain_entries: devices.iter().map(|usage| usage.chain_entries).sum(),
+            pending_messages: self.pending_messages.len() - 1,
+            message_records: self.messages.len(),
+            bytes: devices.iter().map(|usage| usage.bytes).sum::<usize>()
+                + pending_bytes
+                + records_bytes,
+            devices,
+        }
+    }
+}
+
+pub(crate) fn drop_entries<D: DigestAlgorithm>(
+    chain: &mut DeviceState<D>,
+    count: usize,
+    compact: bool,
+) {
+    chain.unverifiable_from.get_or_insert(chain.offset);
+
+    let last_dropped = chain.chain.drain(..count).last().unwrap();
+    chain.offset += count;
+
+    if compact {
+        chain.checkpoint = Some((chain.offset - 1, last_dropped));
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fmt::Debug;
+
+use crate::{DeviceIdentifier, DigestAlgorithm, MessageChains};
+
+/// Decides which devices may be recipients of inserted messages, such that
+/// neither a malicious sender nor the server can make us allocate pairwise
+/// chains for fabricated device identifiers.
+///
+/// This is implemented for sets of device identifiers, and for closures,
+/// which may draw on the contacts and linked devices known to the
+/// application.
+pub trait MembershipOracle<I> {
+    fn is_known(&self, device: &I) -> bool;
+}
+
+impl<I: DeviceIdentifier> MembershipOracle<I> for HashSet<I> {
+    fn is_known(&self, device: &I) -> bool {
+        self.contains(device)
+    }
+}
+
+impl<I: DeviceIdentifier> MembershipOracle<I> for BTreeSet<I> {
+    fn is_known(&self, device: &I) -> bool {
+        self.contains(device)
+    }
+}
+
+impl<I, F: Fn(&I) -> bool> MembershipOracle<I> for F {
+    fn is_known(&self, device: &I) -> bool {
+        self(device)
+    }
+}
+
+/// Optional [`MembershipOracle`] of a [`MessageChains`] instance. It is not
+/// part of the serialized state and must be set again after restoring it.
+pub(crate) struct Membership<I>(Option<Box<dyn MembershipOracle<I>>>);
+
+impl<I> Default for Membership<I> {
+    fn default() -> Self {
+        Membership(None)
+    }
+}
+
+impl<I> Debug for Membership<I> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(_) => write!(f, "Membership(Some(..))"),
+            None => write!(f, "Membership(None)"),
+        }
+    }
+}
+
+impl<I> Membership<I> {
+    /// Whether `device` is known, which is the case for all devices if no
+    /// oracle has been set.
+    pub fn is_known(&self, device: &I) -> bool {
+        self.0
+            .as_ref()
+            .map(|oracle| oracle.is_known(device))
+            .unwrap_or(true)
+    }
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> MessageChains<D, I> {
+    /// Only accept messages whose recipients are all known to the given
+    /// oracle (besides our own device). Messages naming any other recipient
+    /// are refused with [`crate::Error::UnknownRecipient`].
+    pub fn set_membership_oracle(&mut self, oracle: impl MembershipOracle<I> + 'static) {
+        self.membership = Membership(Some(Box::new(oracle)));
+    }
+
+    /// Accept messages to any recipients again.
+    pub fn clear_membership_oracle(&mut self) {
+        self.membership = Membership(None);
+    }
+}
@@ -0,0 +1,116 @@
+use std::fmt::Debug;
+
+use serde::Serialize;
+
+use crate::{DeviceIdentifier, DigestAlgorithm, Error, MessageChains, PeerHealth};
+
+/// Lifecycle events of a [`MessageChains`] instance, reported to its
+/// [`Observer`].
+///
+/// Events serialize with their variant name in snake case as `event`, along
+/// with their fields.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(bound = "", tag = "event", rename_all = "snake_case")]
+pub enum Event<I: DeviceIdentifier> {
+    /// A message has been inserted and assigned `local_seq`.
+    MessageInserted { sender: I, local_seq: usize },
+    /// `count` entries have been trimmed from the pairwise chain with
+    /// `device` after a validation.
+    ChainTrimmed { device: I, count: usize },
+    /// `device` has validated all messages shared with it up to and
+    /// including `local_seq`.
+    PeerValidated { device: I, local_seq: usize },
+    /// Our pending message `id` has been received back from the server.
+    PendingConfirmed { id: u64 },
+    /// A message or validation payload has been refused as it shows that
+    /// the server or a peer misbehaves, as described by `error`.
+    InvariantViolated { error: Error<I> },
+    /// The health of `device` has changed, after a violation it has been
+    /// involved in has been recorded or it has been recovered.
+    PeerHealthChanged { device: I, health: PeerHealth },
+}
+
+/// Receives the [`Event`]s of a [`MessageChains`] instance, e.g., to show
+/// which messages have been verified or to count violations.
+///
+/// This is implemented for closures.
+pub trait Observer<I: DeviceIdentifier> {
+    fn observe(&mut self, event: &Event<I>);
+}
+
+impl<I: DeviceIdentifier, F: FnMut(&Event<I>)> Observer<I> for F {
+    fn observe(&mut self, event: &Event<I>) {
+        self(event)
+    }
+}
+
+/// Optional [`Observer`] of a [`MessageChains`] instance. It is not part of
+/// the serialized state and must be set again after restoring it.
+pub(crate) struct Observation<I: DeviceIdentifier>(Option<Box<dyn Observer<I>>>);
+
+impl<I: DeviceIdentifier> Default for Observation<I> {
+    fn default() -> Self {
+        Observation(None)
+    }
+}
+
+impl<I: DeviceIdentifier> Debug for Observation<I> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(_) => write!(f, "Observation(Some(..))"),
+            None => write!(f, "Observation(None)"),
+        }
+    }
+}
+
+impl<I: DeviceIdentifier> Error<I> {
+    /// Whether the error shows that the server or a peer misbehaves, rather
+    /// than the caller.
+    fn is_violation(&self) -> bool {
+        matches!(
+            self,
+            Error::OwnMessageInvalidReordered { .. }
+                | Error::InvalidValidationSeq { .. }
+                | Error::MissingValidationPayload(_)
+                | Error::InvalidGossipThirdParty { .. }
+                | Error::InvalidGossipSeq { .. }
+                | Error::UnsharedGossipMessage { .. }
+                | Error::ConflictingGossipClaims { .. }
+                | Error::ForkDetected(_)
+                | Error::ThirdPartyForkDetected(_, _)
+                | Error::CounterGap { .. }
+                | Error::InvalidCounters { .. }
+        )
+    }
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> MessageChains<D, I> {
+    /// Report all subsequent [`Event`]s to `observer`.
+    pub fn set_observer(&mut self, observer: impl Observer<I> + 'static) {
+        self.observer = Observation(Some(Box::new(observer)));
+    }
+
+    pub fn clear_observer(&mut self) {
+        self.observer = Observation(None);
+    }
+
+    /// Report an event, which is only constructed if an observer is set.
+    pub(crate) fn emit(&mut self, event: impl FnOnce() -> Event<I>) {
+        if let Some(observer) = &mut self.observer.0 {
+            observer.observe(&event());
+        }
+    }
+
+    /// Report the error of `result` if it is an invariant violation, and
+    /// pass on `result`.
+    pub(crate) fn observe_result<T>(&mut self, result: Result<T, Error<I>>) -> Result<T, Error<I>> {
+        if let Err(error) = &result {
+            if error.is_violation() {
+                self.emit(|| Event::InvariantViolated {
+                    error: error.clone(),
+                });
+            }
+        }
+        result
+    }
+}
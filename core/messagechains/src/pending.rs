@@ -0,0 +1,148 @@
+use crate::{
+    DeviceIdentifier, DigestAlgorithm, DigestVersion, Error, Hash, MessageChains, Operation,
+};
+
+/// A message we have sent, but not yet received back from the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingMessageInfo {
+    /// Identifier of the pending message. Messages are numbered in the order
+    /// they have been sent, starting at 0.
+    pub id: u64,
+    /// Tick at which the message was sent or last retried.
+    pub tick: u64,
+    pub digest_version: DigestVersion,
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> MessageChains<D, I> {
+    /// Set the current tick, which is recorded for every message sent. This
+    /// may be a timestamp or a logical clock, and never moves backwards.
+    pub fn set_tick(&mut self, tick: u64) {
+        if tick > self.tick {
+            self.apply(Operation::SetTick { tick });
+        }
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub(crate) fn push_pending(
+        &mut self,
+        version: DigestVersion,
+        digest: Hash<D>,
+        counted_recipients: Option<Vec<I>>,
+    ) {
+        self.apply(Operation::PushPending {
+            version,
+            digest,
+            tick: self.tick,
+            counted_recipients,
+        });
+    }
+
+    /// All messages we have sent, but not yet received back from the server,
+    /// in the order they have been sent.
+    pub fn pending_messages(&self) -> Vec<PendingMessageInfo> {
+        // The first entry is the base of the hash-chain over pending
+        // messages, which has already been received:
+        self.pending_messages
+            .iter()
+            .skip(1)
+            .zip(self.pending_offset..)
+            .map(|(pending, id)| PendingMessageInfo {
+                id,
+                tick: pending.tick,
+                digest_version: pending.version,
+            })
+            .collect()
+    }
+
+    /// Pending messages which have been sent (or retried) at least `timeout`
+    /// ticks ago. These may have been dropped by the server.
+    pub fn overdue_pending_messages(&self, timeout: u64) -> Vec<PendingMessageInfo> {
+        self.pending_messages()
+            .into_iter()
+            .filter(|pending| self.tick.saturating_sub(pending.tick) >= timeout)
+            .collect()
+    }
+
+    pub(crate) fn pending_index(&self, id: u64) -> Result<usize, Error<I>> {
+        id.checked_sub(self.pending_offset)
+            .map(|index| index as usize + 1)
+            .filter(|index| *index < self.pending_messages.len())
+            .ok_or(Error::UnknownPendingMessage(id))
+    }
+
+    /// Record that the pending message `id` has been sent to the server
+    /// again, resetting its tick. The message must be resent unchanged.
+    pub fn retry_pending_message(&mut self, id: u64) -> Result<(), Error<I>> {
+        self.pending_index(id)?;
+        self.apply(Operation::SetPendingTick {
+            id,
+            tick: self.tick,
+        });
+        Ok(())
+    }
+
+    /// Declare the oldest pending message `id` as lost, such that the next
+    /// pending message can be received back from the server. The hash-chain
+    /// over pending messages continues from the lost message.
+    ///
+    /// Only the oldest pending message can be declared lost, as the server
+    /// must return messages in the order they have been sent.
+    ///
+    /// Per-pair counters incremented for the message are not rolled back, as
+    /// the server may still have delivered it to some of its recipients.
+    /// Recipients which haven't received it refuse the next counted message
+    /// with [`Error::CounterGap`], which they may choose to accept after the
+    /// loss has been confirmed out of band.
+    pub fn declare_pending_message_lost(&mut self, id: u64) -> Result<(), Error<I>> {
+        if self.pending_index(id)? != 1 {
+            return Err(Error::InvalidPendingOperation(id));
+        }
+
+        log::debug!("declare_pending_message_lost: pending message {}", id);
+        self.apply(Operation::PopPending);
+
+        Ok(())
+    }
+
+    /// Cancel the most recently sent pending message `id`, which has never
+    /// reached the server. Per-pair counters incremented for it are rolled
+    /// back, such that the next counted message reuses them.
+    ///
+    /// Only the most recently sent message can be cancelled, as subsequent
+    /// pending messages are chained onto it.
+    pub fn cancel_pending_message(&mut self, id: u64) -> Result<(), Error<I>> {
+        if self.pending_index(id)? != self.pending_messages.len() - 1 {
+            return Err(Error::InvalidPendingOperation(id));
+        }
+
+        let pending = self.pending_messages.back().unwrap();
+        let rolled_back: Vec<_> = pending
+            .counted_recipients
+            .iter()
+            .flatten()
+            .filter_map(|r| {
+                let counter = if *r == self.own_device {
+                    self.own_counter
+                } else {
+                    self.chains.get(r)?.sent_counter
+                };
+                // The chain may have been started over since the message was
+                // counted, e.g., if the recipient was retired and re-added:
+                Some(Operation::SetSentCounter {
+                    device: r.clone(),
+                    counter: counter.checked_sub(1)?,
+                })
+            })
+            .collect();
+
+        self.apply(Operation::CancelPending);
+        for operation in rolled_back {
+            self.apply(operation);
+        }
+
+        Ok(())
+    }
+}
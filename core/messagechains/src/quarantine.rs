@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{DeviceIdentifier, DigestAlgorithm, Error, Event, MessageChains, Operation};
+
+/// Number of violations after which a peer is quarantined, unless
+/// configured otherwise through [`crate::Config::quarantine_threshold`].
+pub const DEFAULT_QUARANTINE_THRESHOLD: usize = 3;
+
+/// Health of a peer, escalated whenever an invariant violation it has been
+/// involved in is recorded through [`MessageChains::record_violation`].
+///
+/// A peer becomes [`PeerHealth::Suspicious`] after its first violation, and
+/// [`PeerHealth::Quarantined`] once its violations reach the configured
+/// threshold. It stays quarantined until it is explicitly recovered through
+/// [`MessageChains::recover_peer`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum PeerHealth {
+    #[default]
+    Healthy,
+    Suspicious,
+    /// Messages sent to or received from the peer are refused with
+    /// [`Error::PeerQuarantined`].
+    Quarantined,
+}
+
+impl<I: DeviceIdentifier> Error<I> {
+    /// Peers to blame for this error if it is an invariant violation. Gaps
+    /// in the counters are left out, as the server may have dropped messages.
+    fn offenders(&self) -> Vec<&I> {
+        match self {
+            Error::InvalidValidationSeq { sender, .. }
+            | Error::MissingValidationPayload(sender)
+            | Error::InvalidGossipThirdParty { sender, .. }
+            | Error::InvalidGossipSeq { sender, .. }
+            | Error::UnsharedGossipMessage { sender, .. }
+            | Error::InvalidCounters { sender, .. } => vec![sender],
+            Error::ConflictingGossipClaims { claimer, .. } => vec![claimer],
+            Error::ForkDetected(evidence) => vec![&evidence.sender],
+            // Either device of the pair may have lied about their chain, not
+            // the device gossiping about it:
+            Error::ThirdPartyForkDetected(a, b) => vec![a, b],
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> MessageChains<D, I> {
+    pub fn peer_health(&self, device: &I) -> PeerHealth {
+        self.peer_health.get(device).copied().unwrap_or_default()
+    }
+
+    /// Mark a peer as healthy again, e.g., after the user has verified it
+    /// out of band, which lifts its quarantine and forgets its violations.
+    /// Returns whether it had been suspicious or quarantined.
+    ///
+    /// This does not resolve a fork of the pairwise chain with the peer, so
+    /// subsequent validation payloads may be refused again.
+    pub fn recover_peer(&mut self, device: &I) -> bool {
+        if self.peer_health(device) == PeerHealth::Healthy {
+            return false;
+        }
+
+        self.set_peer_health(device, PeerHealth::Healthy);
+        true
+    }
+
+    fn set_peer_health(&mut self, device: &I, health: PeerHealth) {
+        self.apply(Operation::SetPeerHealth {
+            device: device.clone(),
+            health,
+        });
+        self.emit(|| Event::PeerHealthChanged {
+            device: device.clone(),
+            health,
+        });
+    }
+
+    /// Record an error returned by any other method, such as
+    /// [`MessageChains::receive`], escalating the health of every peer to
+    /// blame for it if it is an invariant violation. Returns whether any
+    /// peer has been blamed.
+    ///
+    /// Failing calls leave the state unchanged, so violations are only
+    /// counted once the application records them here.
+    pub fn record_violation(&mut self, error: &Error<I>) -> bool {
+        let threshold = self
+            .config
+            .quarantine_threshold
+            .unwrap_or(DEFAULT_QUARANTINE_THRESHOLD)
+            .max(2);
+
+        let mut blamed = false;
+        for peer in error.offenders() {
+            // Our own messages can only be tampered with by the server:
+            if *peer == self.own_device {
+                continue;
+            }
+            blamed = true;
+            if self.peer_health(peer) == PeerHealth::Quarantined {
+                continue;
+            }
+
+            self.apply(Operation::RecordViolation {
+                device: peer.clone(),
+            });
+            let health = if self.peer_violations[peer] >= threshold {
+                PeerHealth::Quarantined
+            } else {
+                PeerHealth::Suspicious
+            };
+            if health != self.peer_health(peer) {
+                log::debug!("record_violation: {:?} is now {:?}", peer, health);
+                self.set_peer_health(peer, health);
+            }
+        }
+
+        blamed
+    }
+
+    /// Refuse traffic involving any quarantined device.
+    pub(crate) fn check_quarantine<'a>(
+        &self,
+        devices: impl IntoIterator<Item = &'a I>,
+    ) -> Result<(), Error<I>>
+    where
+        I: 'a,
+    {
+        for device in devices {
+            if self.peer_health(device) == PeerHealth::Quarantined {
+                log::debug!("Quarantined peer: {:?}", device);
+                return Err(Error::PeerQuarantined(device.clone()));
+            }
+        }
+
+        Ok(())
+    }
+}
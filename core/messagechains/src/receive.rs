@@ -0,0 +1,175 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    DeviceIdentifier, DigestAlgorithm, DigestVersion, Error, Hash, MessageChains, Operation,
+};
+
+/// A message received from the server, along with the validation payload
+/// its sender has attached for us.
+#[derive(Debug, Clone)]
+pub struct Envelope<'a, D: DigestAlgorithm, I: DeviceIdentifier> {
+    pub sender: &'a I,
+    pub message: &'a [u8],
+    /// Sorted recipients of the message, including our own device.
+    pub recipients: &'a [I],
+    pub validation_payload: Option<(usize, Hash<D>)>,
+    /// Digest version the sender has used for this message. If `None`, the
+    /// message is assumed to use [`crate::Config::digest_version`] (see
+    /// [`MessageChains::insert_message`]).
+    pub digest_version: Option<DigestVersion>,
+    /// The sender's per-pair counters, as returned by
+    /// [`MessageChains::send_counted_message`]. If present, the counter for
+    /// our own device must match the number of counted messages previously
+    /// received from the sender, or else [`Error::CounterGap`] is returned.
+    pub counters: Option<&'a [u64]>,
+    /// Sequence number assigned to the message by the server, which must be
+    /// strictly increasing across all messages delivered to this device.
+    pub server_seq: Option<u64>,
+}
+
+/// The result of successfully receiving an [`Envelope`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceiveOutcome {
+    /// Local sequence number assigned to the message.
+    pub local_seq: usize,
+    /// Number of entries trimmed from the pairwise chain with the sender.
+    pub trimmed: usize,
+    /// Digest version of the chain entries inserted for the message.
+    pub digest_version: DigestVersion,
+}
+
+/// How to handle a message which has been delivered before, as determined by
+/// its server sequence number or sender counter (see
+/// [`MessageChains::receive`]).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DuplicatePolicy {
+    /// Return [`Error::DuplicateMessage`].
+    #[default]
+    Reject,
+    /// Leave the state unchanged and report that the message was ignored.
+    /// The server may legitimately redeliver messages, e.g., after a
+    /// reconnect.
+    Ignore,
+}
+
+/// The counter the sender has assigned to a counted message for its own
+/// device, which is strictly increasing across all its counted messages.
+fn sender_counter<I: DeviceIdentifier>(
+    sender: &I,
+    recipients: &[I],
+    counters: Option<&[u64]>,
+) -> Option<u64> {
+    let index = recipients.iter().position(|r| r == sender)?;
+    counters?.get(index).copied()
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> MessageChains<D, I> {
+    /// Receive a message: validate the attached validation payload, trim the
+    /// pairwise chain with the sender and insert the message, equivalent to
+    /// calling [`MessageChains::validate_trim_chain`] followed by
+    /// [`MessageChains::insert_versioned_message`].
+    ///
+    /// All checks are performed before any state is modified. Hence, if an
+    /// error is returned, the [`MessageChains`] are left unchanged.
+    ///
+    /// If the envelope carries a server sequence number which shows that the
+    /// message has been delivered before, it is handled according to
+    /// [`crate::Config::duplicate_policy`]. The same applies to counted
+    /// messages whose sender counter, i.e., the sender's counter for its own
+    /// device in [`Envelope::counters`], isn't above the one of the latest
+    /// message received from the sender. Unlike the server sequence number,
+    /// the sender counter is covered by the message digest, and hence can't
+    /// be changed by the server without the pairwise chains forking. Returns
+    /// `None` if the message has been ignored.
+    pub fn receive(
+        &mut self,
+        envelope: Envelope<'_, D, I>,
+    ) -> Result<Option<ReceiveOutcome>, Error<I>> {
+        let result = self.receive_inner(envelope);
+        self.observe_result(result)
+    }
+
+    fn receive_inner(
+        &mut self,
+        envelope: Envelope<'_, D, I>,
+    ) -> Result<Option<ReceiveOutcome>, Error<I>> {
+        let Envelope {
+            sender,
+            message,
+            recipients,
+            validation_payload,
+            digest_version,
+            counters,
+            server_seq,
+        } = envelope;
+
+        let sender_counter = sender_counter(sender, recipients, counters);
+        if self.is_duplicate(sender, server_seq, sender_counter) {
+            log::debug!(
+                "receive: duplicate message from {:?} (server sequence number \
+                 {:?}, sender counter {:?})",
+                sender,
+                server_seq,
+                sender_counter,
+            );
+            return match self.config.duplicate_policy {
+                DuplicatePolicy::Reject => Err(Error::DuplicateMessage {
+                    sender: sender.clone(),
+                    server_seq,
+                    sender_counter,
+                }),
+                DuplicatePolicy::Ignore => Ok(None),
+            };
+        }
+
+        let validated = self.check_validation_payload(
+            sender,
+            validation_payload
+                .as_ref()
+                .map(|(seq, digest)| (*seq, digest)),
+        )?;
+        let prepared =
+            self.prepare_insert(sender, message, recipients.iter(), digest_version, counters)?;
+        let digest_version = prepared.version;
+
+        // Nothing below may fail:
+        let trimmed = validated
+            .map(|validated| self.apply_validation_payload(sender, validated, true))
+            .unwrap_or(0);
+        let local_seq = self.apply_insert(sender, message, prepared);
+
+        if let Some(seq) = server_seq {
+            self.apply(Operation::SetLastServerSeq { seq });
+        }
+        if let Some(counter) = sender_counter {
+            self.apply(Operation::SetSenderCounter {
+                sender: sender.clone(),
+                counter,
+            });
+        }
+
+        Ok(Some(ReceiveOutcome {
+            local_seq,
+            trimmed,
+            digest_version,
+        }))
+    }
+
+    fn is_duplicate(
+        &self,
+        sender: &I,
+        server_seq: Option<u64>,
+        sender_counter: Option<u64>,
+    ) -> bool {
+        let seen_server_seq = matches!(
+            (server_seq, self.last_server_seq),
+            (Some(seq), Some(last_seq)) if seq <= last_seq
+        );
+        let seen_sender_counter = matches!(
+            (sender_counter, self.sender_counters.get(sender)),
+            (Some(counter), Some(last_counter)) if counter <= *last_counter
+        );
+
+        seen_server_seq || seen_sender_counter
+    }
+}
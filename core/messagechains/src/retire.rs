@@ -0,0 +1,96 @@
+use serde::Serialize;
+
+use crate::{DeviceIdentifier, DigestAlgorithm, Error, Hash, MessageChains, Operation};
+
+/// The final state of the pairwise chain with a retired device.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RetirementReport<I: DeviceIdentifier> {
+    pub device: I,
+    /// The latest local sequence number validated by the device, if any.
+    /// All messages up to this one have been validated.
+    pub last_validated_local_seq: Option<usize>,
+    /// Local sequence numbers of all messages shared with the device which
+    /// it has never validated.
+    pub unvalidated_local_seqs: Vec<usize>,
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> MessageChains<D, I> {
+    /// Forget about `device`, e.g., after it has been deleted or removed as
+    /// a contact, and report which messages it has (not) validated.
+    ///
+    /// Afterwards, messages listing `device` as one of their recipients are
+    /// refused with [`Error::RetiredRecipient`] until it is re-added through
+    /// [`MessageChains::unretire_device`]. Messages previously shared with
+    /// `device` no longer require its validation, and its health is reset.
+    pub fn retire_device(&mut self, device: &I) -> Result<RetirementReport<I>, Error<I>> {
+        if *device == self.own_device {
+            log::debug!("retire_device: can't retire own device");
+            return Err(Error::RetireOwnDevice);
+        }
+
+        let validated_local_seq = self
+            .chains
+            .get(device)
+            .map(|chain| chain.validated_local_seq)
+            .unwrap_or(0);
+
+        // Messages validated by all recipients have been trimmed already:
+        let unvalidated_local_seqs = self
+            .messages
+            .iter()
+            .zip(self.messages_offset..)
+            .filter(|(record, local_seq)| {
+                *local_seq >= validated_local_seq && record.recipients.binary_search(device).is_ok()
+            })
+            .map(|(_, local_seq)| local_seq)
+            .collect();
+
+        // Past violations concern the chain we're about to forget:
+        self.recover_peer(device);
+        self.apply(Operation::Retire {
+            device: device.clone(),
+        });
+
+        // This may have completed the validation of some messages:
+        self.trim_messages();
+
+        Ok(RetirementReport {
+            device: device.clone(),
+            last_validated_local_seq: validated_local_seq.checked_sub(1),
+            unvalidated_local_seqs,
+        })
+    }
+
+    /// Re-add a retired device, which starts out with a fresh pairwise
+    /// chain. Returns whether the device had been retired.
+    ///
+    /// `validation_payload` is the one the device currently sends us. Both
+    /// sides must start over for their chains to match again, so this fails
+    /// with [`Error::PeerNotReset`] unless the device has no chain with us,
+    /// i.e., it has retired us as well.
+    pub fn unretire_device(
+        &mut self,
+        device: &I,
+        validation_payload: Option<(usize, impl std::borrow::Borrow<Hash<D>>)>,
+    ) -> Result<bool, Error<I>> {
+        if !self.retired.contains(device) {
+            return Ok(false);
+        }
+        if validation_payload.is_some() {
+            log::debug!(
+                "unretire_device: {:?} has not reset its chain with us",
+                device
+            );
+            return Err(Error::PeerNotReset(device.clone()));
+        }
+
+        self.apply(Operation::Unretire {
+            device: device.clone(),
+        });
+        Ok(true)
+    }
+
+    pub fn is_retired(&self, device: &I) -> bool {
+        self.retired.contains(device)
+    }
+}
@@ -0,0 +1,556 @@
+//! Versioned schema of serialized [`MessageChains`] state.
+//!
+//! State is serialized as `{ "schema_version": N, "state": ... }`, where the
+//! layout of `state` is defined by the module `vN`, independently of the
+//! internal structures. State of older schema versions is migrated to the
+//! current one when it is deserialized, and always serialized in the current
+//! schema version.
+//!
+//! Besides any serde format, state can be dumped in a compact binary format
+//! through [`MessageChains::to_binary`]. This is CBOR in its packed form,
+//! which encodes struct fields by their index, and digests as byte strings.
+//! The order of fields is hence part of the schema as well.
+//!
+//! Any change to the layout of `state` must introduce a new schema version,
+//! along with a migration from the previous one. Old dumps must keep loading,
+//! as checked against the corpus in `testdata/dumps`.
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    ChainEntry, DeviceIdentifier, DeviceState, DigestAlgorithm, DumpError, EpochCounter,
+    MessageChains, MessageRecord, PeerHealth, PendingMessage,
+};
+
+mod v0;
+mod v1;
+mod v2;
+mod v3;
+mod v4;
+mod v5;
+mod v6;
+mod v7;
+
+/// Version of the schema in which [`MessageChains`] state is serialized.
+pub const SCHEMA_VERSION: u32 = 7;
+
+/// Serialize a digest as a byte string rather than a sequence of numbers,
+/// which is considerably more compact in binary formats. Digests can be
+/// deserialized from either.
+pub(crate) mod digest_bytes {
+    use std::fmt;
+    use std::marker::PhantomData;
+
+    use serde::de::{Error, SeqAccess, Visitor};
+    use serde::{Deserializer, Serializer};
+
+    use crate::{DigestAlgorithm, Hash};
+
+    pub fn serialize<D: DigestAlgorithm, S: Serializer>(
+        digest: &Hash<D>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(digest)
+    }
+
+    pub fn deserialize<'de, D: DigestAlgorithm, DE: Deserializer<'de>>(
+        deserializer: DE,
+    ) -> Result<Hash<D>, DE::Error> {
+        deserializer.deserialize_bytes(DigestVisitor::<D>(PhantomData))
+    }
+
+    struct DigestVisitor<D>(PhantomData<D>);
+
+    impl<'de, D: DigestAlgorithm> Visitor<'de> for DigestVisitor<D> {
+        type Value = Hash<D>;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "a {} digest", D::NAME)
+        }
+
+        fn visit_bytes<E: Error>(self, bytes: &[u8]) -> Result<Hash<D>, E> {
+            let mut digest = Hash::<D>::default();
+            if bytes.len() != digest.len() {
+                return Err(E::invalid_length(bytes.len(), &self));
+            }
+            digest.copy_from_slice(bytes);
+            Ok(digest)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Hash<D>, A::Error> {
+            let mut digest = Hash::<D>::default();
+            for (i, byte) in digest.iter_mut().enumerate() {
+                *byte = seq
+                    .next_element()?
+                    .ok_or_else(|| A::Error::invalid_length(i, &self))?;
+            }
+            if seq.next_element::<u8>()?.is_some() {
+                return Err(A::Error::invalid_length(digest.len() + 1, &self));
+            }
+            Ok(digest)
+        }
+    }
+}
+
+/// Zero-sized marker which serializes as the schema version `N`, and fails to
+/// deserialize from any other version.
+struct SchemaVersion<const N: u32>;
+
+impl<const N: u32> Serialize for SchemaVersion<N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(N)
+    }
+}
+
+impl<'de, const N: u32> Deserialize<'de> for SchemaVersion<N> {
+    fn deserialize<DE: Deserializer<'de>>(deserializer: DE) -> Result<Self, DE::Error> {
+        let version = u32::deserialize(deserializer)?;
+        if version != N {
+            return Err(DE::Error::custom(format!(
+                "schema version {}, expected {}",
+                version, N
+            )));
+        }
+
+        Ok(SchemaVersion)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(bound = "")]
+struct Dump<D: DigestAlgorithm, I: DeviceIdentifier> {
+    schema_version: SchemaVersion<SCHEMA_VERSION>,
+    state: v7::State<D, I>,
+}
+
+/// All supported layouts, newest first. State which matches none of them is
+/// reported according to its schema version.
+#[derive(Deserialize)]
+#[serde(bound = "", untagged)]
+enum AnyDump<D: DigestAlgorithm, I: DeviceIdentifier> {
+    V7 {
+        #[allow(dead_code)]
+        schema_version: SchemaVersion<7>,
+        state: v7::State<D, I>,
+    },
+    V6 {
+        #[allow(dead_code)]
+        schema_version: SchemaVersion<6>,
+        state: v6::State<D, I>,
+    },
+    V5 {
+        #[allow(dead_code)]
+        schema_version: SchemaVersion<5>,
+        state: v5::State<D, I>,
+    },
+    V4 {
+        #[allow(dead_code)]
+        schema_version: SchemaVersion<4>,
+        state: v4::State<D, I>,
+    },
+    V3 {
+        #[allow(dead_code)]
+        schema_version: SchemaVersion<3>,
+        state: v3::State<D, I>,
+    },
+    V2 {
+        #[allow(dead_code)]
+        schema_version: SchemaVersion<2>,
+        state: v2::State<D, I>,
+    },
+    V1 {
+        #[allow(dead_code)]
+        schema_version: SchemaVersion<1>,
+        state: v1::State<D, I>,
+    },
+    BinaryV1 {
+        #[allow(dead_code)]
+        schema_version: SchemaVersion<1>,
+        state: v2::BinaryV1State<D, I>,
+    },
+    Unversioned(v0::State<D, I>),
+    Invalid {
+        schema_version: Option<u32>,
+    },
+}
+
+/// Serializing the state directly does not start a new epoch, so it should
+/// only be used to inspect the state. To persist it, dump it through
+/// [`MessageChains::to_dump`] instead.
+impl<D: DigestAlgorithm, I: DeviceIdentifier> Serialize for MessageChains<D, I> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Dump {
+            schema_version: SchemaVersion,
+            state: v7::State::from(self),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Restored state which has not been checked against the epoch counter yet
+/// (see [`MessageChains::from_dump`]).
+pub(crate) struct Unchecked<D: DigestAlgorithm, I: DeviceIdentifier>(pub MessageChains<D, I>);
+
+impl<'de, D: DigestAlgorithm, I: DeviceIdentifier> Deserialize<'de> for Unchecked<D, I> {
+    fn deserialize<DE: Deserializer<'de>>(deserializer: DE) -> Result<Self, DE::Error> {
+        let state = match AnyDump::deserialize(deserializer)? {
+            AnyDump::V7 { state, .. } => state,
+            AnyDump::V6 { state, .. } => v7::State::from(state),
+            AnyDump::V5 { state, .. } => v7::State::from(v6::State::from(state)),
+            AnyDump::V4 { state, .. } => v7::State::from(v6::State::from(v5::State::from(state))),
+            AnyDump::V3 { state, .. } => {
+                v7::State::from(v6::State::from(v5::State::from(v4::State::from(state))))
+            }
+            AnyDump::V2 { state, .. } => v7::State::from(v6::State::from(v5::State::from(
+                v4::State::from(v3::State::from(state)),
+            ))),
+            AnyDump::V1 { state, .. } => v7::State::from(v6::State::from(v5::State::from(
+                v4::State::from(v3::State::from(v2::State::from(state))),
+            ))),
+            AnyDump::BinaryV1 { state, .. } => v7::State::from(v6::State::from(v5::State::from(
+                v4::State::from(v3::State::from(v2::State::from(state))),
+            ))),
+            AnyDump::Unversioned(state) => {
+                let mut state = v7::State::from(v6::State::from(v5::State::from(v4::State::from(
+                    v3::State::from(v2::State::from(v1::State::from(state))),
+                ))));
+                // No message records have been kept before:
+                state.messages_known_from = state.local_seq;
+                state
+            }
+            AnyDump::Invalid {
+                schema_version: Some(version),
+            } if version > SCHEMA_VERSION => {
+                return Err(DE::Error::custom(format!(
+                    "unsupported schema version {}, expected at most {}",
+                    version, SCHEMA_VERSION
+                )));
+            }
+            AnyDump::Invalid { schema_version } => {
+                return Err(DE::Error::custom(format!(
+                    "invalid state for schema version {}",
+                    schema_version.unwrap_or(0)
+                )));
+            }
+        };
+
+        state.restore().map(Unchecked).map_err(DE::Error::custom)
+    }
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> MessageChains<D, I> {
+    /// Dump the state in any serde format, starting a new epoch (see
+    /// [`EpochCounter`]).
+    pub fn to_dump<S: Serializer>(&mut self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.advance_epoch();
+        self.serialize(serializer)
+    }
+
+    /// Restore state dumped through [`MessageChains::to_dump`], migrating it
+    /// to the current schema version if necessary. State of an older epoch
+    /// than recorded by `counter` is rejected with [`DumpError::Rollback`].
+    pub fn from_dump<'de, DE: Deserializer<'de>>(
+        deserializer: DE,
+        counter: &mut impl EpochCounter,
+    ) -> Result<Self, DumpError> {
+        let Unchecked(chains) =
+            Unchecked::deserialize(deserializer).map_err(|e| DumpError::Decode(e.to_string()))?;
+        chains.check_epoch(counter)?;
+        Ok(chains)
+    }
+
+    /// Dump the state in a compact binary format (packed CBOR), using the
+    /// same versioned schema as any other serde format. This starts a new
+    /// epoch like [`MessageChains::to_dump`].
+    pub fn to_binary(&mut self) -> Vec<u8> {
+        self.advance_epoch();
+        self.encode_binary()
+    }
+
+    pub(crate) fn encode_binary(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        // Writing into a Vec never fails, and the state is always
+        // serializable:
+        self.serialize(&mut serde_cbor::Serializer::new(&mut bytes).packed_format())
+            .unwrap();
+        bytes
+    }
+
+    /// Restore state dumped through [`MessageChains::to_binary`], checking
+    /// its epoch like [`MessageChains::from_dump`].
+    pub fn from_binary(bytes: &[u8], counter: &mut impl EpochCounter) -> Result<Self, DumpError> {
+        let chains = Self::decode_binary(bytes)?;
+        chains.check_epoch(counter)?;
+        Ok(chains)
+    }
+
+    pub(crate) fn decode_binary(bytes: &[u8]) -> Result<Self, DumpError> {
+        serde_cbor::from_slice(bytes)
+            .map(|Unchecked(chains)| chains)
+            .map_err(|e| DumpError::Decode(e.to_string()))
+    }
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> From<&MessageChains<D, I>> for v7::State<D, I> {
+    fn from(chains: &MessageChains<D, I>) -> Self {
+        let entry = |entry: &ChainEntry<D>| v7::ChainEntry {
+            local_seq: entry.local_seq as u64,
+            version: entry.version,
+            digest: entry.digest.clone(),
+        };
+
+        let mut devices: Vec<_> = chains
+            .chains
+            .iter()
+            .map(|(device, state)| v7::Chain {
+                device: device.clone(),
+                offset: state.offset as u64,
+                validated_local_seq: state.validated_local_seq as u64,
+                entries: state.chain.iter().map(entry).collect(),
+                digest_version: state.digest_version,
+                sent_counter: state.sent_counter,
+                received_counter: state.received_counter,
+                unverifiable_from: state.unverifiable_from.map(|seq| seq as u64),
+                checkpoint: state
+                    .checkpoint
+                    .as_ref()
+                    .map(|(seq, checkpoint)| v7::Checkpoint {
+                        seq: *seq as u64,
+                        entry: entry(checkpoint),
+                    }),
+            })
+            .collect();
+        // Sort for deterministic output:
+        devices.sort_by(|a, b| a.device.cmp(&b.device));
+
+        let mut gossip_claims: Vec<_> = chains
+            .gossip_claims
+            .iter()
+            .map(|(pair, claims)| v7::GossipPair {
+                pair: pair.clone(),
+                claims: claims
+                    .iter()
+                    .map(|(local_seq, record)| v7::GossipClaim {
+                        local_seq: *local_seq as u64,
+                        claimer: record.claimer.clone(),
+                        third_party_seq: record.third_party_seq as u64,
+                        digest: record.digest.clone(),
+                    })
+                    .collect(),
+            })
+            .collect();
+        gossip_claims.sort_by(|a, b| a.pair.cmp(&b.pair));
+
+        let mut sender_counters: Vec<_> = chains
+            .sender_counters
+            .iter()
+            .map(|(device, counter)| (device.clone(), *counter))
+            .collect();
+        sender_counters.sort();
+
+        let mut peer_health: Vec<_> = chains
+            .peer_health
+            .iter()
+            .map(|(device, health)| (device.clone(), *health))
+            .collect();
+        peer_health.sort();
+
+        let mut peer_violations: Vec<_> = chains
+            .peer_violations
+            .iter()
+            .map(|(device, violations)| (device.clone(), *violations as u64))
+            .collect();
+        peer_violations.sort();
+
+        let config = &chains.config;
+        v7::State {
+            algorithm: D::NAME.to_string(),
+            own_device: chains.own_device.clone(),
+            config: v7::Config {
+                strict_validation_payloads: config.strict_validation_payloads,
+                gossip: config.gossip,
+                digest_version: config.digest_version,
+                default_peer_digest_version: config.default_peer_digest_version,
+                duplicate_policy: config.duplicate_policy,
+                max_chain_len: config.max_chain_len.map(|max| max as u64),
+                max_total_chain_len: config.max_total_chain_len.map(|max| max as u64),
+                limit_policy: config.limit_policy,
+                quarantine_threshold: config
+                    .quarantine_threshold
+                    .map(|threshold| threshold as u64),
+            },
+            pending_offset: chains.pending_offset,
+            pending_messages: chains
+                .pending_messages
+                .iter()
+                .map(|pending| v7::PendingMessage {
+                    version: pending.version,
+                    digest: pending.digest.clone(),
+                    tick: pending.tick,
+                    counted_recipients: pending.counted_recipients.clone(),
+                })
+                .collect(),
+            tick: chains.tick,
+            local_seq: chains.local_seq as u64,
+            chains: devices,
+            messages_offset: chains.messages_offset as u64,
+            messages_known_from: chains.messages_known_from as u64,
+            messages: chains
+                .messages
+                .iter()
+                .map(|record| v7::MessageRecord {
+                    recipients: record.recipients.clone(),
+                    counters: record.counters.clone(),
+                })
+                .collect(),
+            gossip_claims,
+            last_server_seq: chains.last_server_seq,
+            sender_counters,
+            own_counter: chains.own_counter,
+            retired: chains.retired.iter().cloned().collect(),
+            epoch: chains.epoch,
+            peer_health,
+            peer_violations,
+        }
+    }
+}
+
+/// Convert a serialized integer back into a `usize`, which may be narrower on
+/// the platform restoring the state.
+fn to_usize(value: u64) -> Result<usize, String> {
+    usize::try_from(value).map_err(|_| format!("value {} exceeds the platform's usize", value))
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> v7::State<D, I> {
+    fn restore(self) -> Result<MessageChains<D, I>, String> {
+        if self.algorithm != D::NAME {
+            return Err(format!(
+                "state uses digest algorithm {:?}, expected {:?}",
+                self.algorithm,
+                D::NAME
+            ));
+        }
+
+        // The hash-chain over pending messages always has a base entry:
+        if self.pending_messages.is_empty() {
+            return Err("state has no pending message base entry".to_string());
+        }
+
+        let entry = |entry: v7::ChainEntry<D>| -> Result<ChainEntry<D>, String> {
+            Ok(ChainEntry {
+                local_seq: to_usize(entry.local_seq)?,
+                version: entry.version,
+                digest: entry.digest,
+            })
+        };
+
+        let mut chains = HashMap::new();
+        for chain in self.chains {
+            let state = DeviceState {
+                offset: to_usize(chain.offset)?,
+                validated_local_seq: to_usize(chain.validated_local_seq)?,
+                chain: chain
+                    .entries
+                    .into_iter()
+                    .map(entry)
+                    .collect::<Result<VecDeque<_>, _>>()?,
+                digest_version: chain.digest_version,
+                sent_counter: chain.sent_counter,
+                received_counter: chain.received_counter,
+                unverifiable_from: chain.unverifiable_from.map(to_usize).transpose()?,
+                checkpoint: match chain.checkpoint {
+                    Some(checkpoint) => Some((to_usize(checkpoint.seq)?, entry(checkpoint.entry)?)),
+                    None => None,
+                },
+            };
+            if chains.insert(chain.device, state).is_some() {
+                return Err("state has duplicate chains for a device".to_string());
+            }
+        }
+
+        let mut gossip_claims = HashMap::new();
+        for pair in self.gossip_claims {
+            let claims = pair
+                .claims
+                .into_iter()
+                .map(|claim| {
+                    Ok((
+                        to_usize(claim.local_seq)?,
+                        crate::gossip::GossipRecord {
+                            claimer: claim.claimer,
+                            third_party_seq: to_usize(claim.third_party_seq)?,
+                            digest: claim.digest,
+                        },
+                    ))
+                })
+                .collect::<Result<_, String>>()?;
+            gossip_claims.insert(pair.pair, claims);
+        }
+
+        if self.messages_known_from > self.messages_offset {
+            return Err("state has unknown messages past its message records".to_string());
+        }
+
+        let config = self.config;
+        Ok(MessageChains {
+            own_device: self.own_device,
+            config: crate::Config {
+                strict_validation_payloads: config.strict_validation_payloads,
+                gossip: config.gossip,
+                digest_version: config.digest_version,
+                default_peer_digest_version: config.default_peer_digest_version,
+                duplicate_policy: config.duplicate_policy,
+                max_chain_len: config.max_chain_len.map(to_usize).transpose()?,
+                max_total_chain_len: config.max_total_chain_len.map(to_usize).transpose()?,
+                limit_policy: config.limit_policy,
+                quarantine_threshold: config.quarantine_threshold.map(to_usize).transpose()?,
+            },
+            pending_messages: self
+                .pending_messages
+                .into_iter()
+                .map(|pending| PendingMessage {
+                    version: pending.version,
+                    digest: pending.digest,
+                    tick: pending.tick,
+                    counted_recipients: pending.counted_recipients,
+                })
+                .collect(),
+            pending_offset: self.pending_offset,
+            tick: self.tick,
+            chains,
+            local_seq: to_usize(self.local_seq)?,
+            messages_offset: to_usize(self.messages_offset)?,
+            messages_known_from: to_usize(self.messages_known_from)?,
+            messages: self
+                .messages
+                .into_iter()
+                .map(|record| MessageRecord {
+                    recipients: record.recipients,
+                    counters: record.counters,
+                })
+                .collect(),
+            gossip_claims,
+            last_server_seq: self.last_server_seq,
+            sender_counters: self.sender_counters.into_iter().collect(),
+            own_counter: self.own_counter,
+            retired: self.retired.into_iter().collect::<BTreeSet<_>>(),
+            peer_health: self
+                .peer_health
+                .into_iter()
+                .filter(|(_, health)| *health != PeerHealth::Healthy)
+                .collect(),
+            peer_violations: self
+                .peer_violations
+                .into_iter()
+                .map(|(device, violations)| Ok((device, to_usize(violations)?)))
+                .collect::<Result<_, String>>()?,
+            epoch: self.epoch,
+            membership: Default::default(),
+            observer: Default::default(),
+            store: Default::default(),
+        })
+    }
+}
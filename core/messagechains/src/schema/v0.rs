@@ -0,0 +1,104 @@
+//! The unversioned layout released before schemas were versioned, which
+//! serialized the internal structures directly. It only supported SHA-256
+//! digests, string device identifiers and [`DigestVersion::V1`], and kept no
+//! message records.
+
+use std::collections::{HashMap, VecDeque};
+
+use serde::Deserialize;
+
+use super::v1;
+use crate::{DeviceIdentifier, DigestAlgorithm, DigestVersion, DuplicatePolicy, Hash, LimitPolicy};
+
+#[derive(Deserialize)]
+#[serde(bound = "")]
+pub(super) struct State<D: DigestAlgorithm, I: DeviceIdentifier> {
+    own_device: I,
+    pending_messages: VecDeque<Hash<D>>,
+    chains: HashMap<I, DeviceState<D>>,
+    local_seq: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(bound = "")]
+struct ChainEntry<D: DigestAlgorithm> {
+    local_seq: usize,
+    digest: Hash<D>,
+}
+
+#[derive(Deserialize)]
+#[serde(bound = "")]
+struct DeviceState<D: DigestAlgorithm> {
+    offset: usize,
+    validated_local_seq: usize,
+    chain: Vec<ChainEntry<D>>,
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> From<State<D, I>> for v1::State<D, I> {
+    fn from(state: State<D, I>) -> Self {
+        let mut chains: Vec<_> = state
+            .chains
+            .into_iter()
+            .map(|(device, chain)| v1::Chain {
+                device,
+                offset: chain.offset as u64,
+                validated_local_seq: chain.validated_local_seq as u64,
+                entries: chain
+                    .chain
+                    .into_iter()
+                    .map(|entry| v1::ChainEntry {
+                        local_seq: entry.local_seq as u64,
+                        version: DigestVersion::V1,
+                        digest: entry.digest,
+                    })
+                    .collect(),
+                digest_version: None,
+                sent_counter: 0,
+                received_counter: 0,
+                unverifiable_from: None,
+                checkpoint: None,
+            })
+            .collect();
+        chains.sort_by(|a, b| a.device.cmp(&b.device));
+
+        v1::State {
+            algorithm: "sha256".to_string(),
+            own_device: state.own_device,
+            config: v1::Config {
+                strict_validation_payloads: false,
+                gossip: false,
+                digest_version: DigestVersion::V1,
+                default_peer_digest_version: None,
+                duplicate_policy: DuplicatePolicy::default(),
+                max_chain_len: None,
+                max_total_chain_len: None,
+                limit_policy: LimitPolicy::default(),
+            },
+            pending_offset: 0,
+            pending_messages: state
+                .pending_messages
+                .into_iter()
+                .map(|digest| v1::PendingMessage {
+                    version: DigestVersion::V1,
+                    digest,
+                    tick: 0,
+                    counted_recipients: None,
+                })
+                .collect(),
+            tick: 0,
+            local_seq: state.local_seq as u64,
+            chains,
+            // Without message records, the recipients of all previously
+            // inserted messages are unknown. Schema version 1 can't express
+            // this, so the migrated state is marked once it has reached the
+            // current version (see `AnyDump::Unversioned`):
+            messages_offset: state.local_seq as u64,
+            messages: Vec::new(),
+            gossip_claims: Vec::new(),
+            last_server_seq: None,
+            sender_counters: Vec::new(),
+            own_counter: 0,
+            retired: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! Schema version 1. All sequence numbers, offsets and limits are encoded as
+//! u64, independently of the platform's `usize`.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{DeviceIdentifier, DigestAlgorithm, DigestVersion, DuplicatePolicy, Hash, LimitPolicy};
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub(super) struct State<D: DigestAlgorithm, I: DeviceIdentifier> {
+    /// Name of the [`DigestAlgorithm`] of all digests.
+    pub algorithm: String,
+    pub own_device: I,
+    pub config: Config,
+    /// Identifier of the first pending message following the base entry.
+    pub pending_offset: u64,
+    /// The base entry of the hash-chain over pending messages, followed by
+    /// all pending messages.
+    pub pending_messages: Vec<PendingMessage<D, I>>,
+    pub tick: u64,
+    /// Next local sequence number.
+    pub local_seq: u64,
+    /// Pairwise chains, sorted by device.
+    pub chains: Vec<Chain<D, I>>,
+    /// Local sequence number of the first message record.
+    pub messages_offset: u64,
+    pub messages: Vec<MessageRecord<I>>,
+    /// Gossip claims about third-party pairs, sorted by pair.
+    pub gossip_claims: Vec<GossipPair<D, I>>,
+    pub last_server_seq: Option<u64>,
+    /// Counter of the latest message received from each sender, sorted by
+    /// sender.
+    pub sender_counters: Vec<(I, u64)>,
+    pub own_counter: u64,
+    pub retired: Vec<I>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct Config {
+    pub strict_validation_payloads: bool,
+    pub gossip: bool,
+    pub digest_version: DigestVersion,
+    pub default_peer_digest_version: Option<DigestVersion>,
+    pub duplicate_policy: DuplicatePolicy,
+    pub max_chain_len: Option<u64>,
+    pub max_total_chain_len: Option<u64>,
+    pub limit_policy: LimitPolicy,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub(super) struct PendingMessage<D: DigestAlgorithm, I: DeviceIdentifier> {
+    pub version: DigestVersion,
+    pub digest: Hash<D>,
+    pub tick: u64,
+    pub counted_recipients: Option<Vec<I>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub(super) struct ChainEntry<D: DigestAlgorithm> {
+    pub local_seq: u64,
+    pub version: DigestVersion,
+    pub digest: Hash<D>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub(super) struct Checkpoint<D: DigestAlgorithm> {
+    pub seq: u64,
+    pub entry: ChainEntry<D>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub(super) struct Chain<D: DigestAlgorithm, I: DeviceIdentifier> {
+    pub device: I,
+    /// Sequence number of the first entry.
+    pub offset: u64,
+    pub validated_local_seq: u64,
+    pub entries: Vec<ChainEntry<D>>,
+    pub digest_version: Option<DigestVersion>,
+    pub sent_counter: u64,
+    pub received_counter: u64,
+    pub unverifiable_from: Option<u64>,
+    pub checkpoint: Option<Checkpoint<D>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub(super) struct MessageRecord<I: DeviceIdentifier> {
+    pub recipients: Vec<I>,
+    pub counters: Option<Vec<u64>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub(super) struct GossipPair<D: DigestAlgorithm, I: DeviceIdentifier> {
+    pub pair: (I, I),
+    /// Claims sorted by local sequence number.
+    pub claims: Vec<GossipClaim<D, I>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub(super) struct GossipClaim<D: DigestAlgorithm, I: DeviceIdentifier> {
+    pub local_seq: u64,
+    pub claimer: I,
+    pub third_party_seq: u64,
+    pub digest: Hash<D>,
+}
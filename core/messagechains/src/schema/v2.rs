@@ -0,0 +1,322 @@
+//! Schema version 2, which adds the state epoch used to detect rollbacks. For
+//! the compact binary format, digests are encoded as byte strings (which JSON
+//! represents as arrays of numbers, as before) and unit enums by the names of
+//! their variants. All other structures are unchanged from version 1.
+
+use serde::{Deserialize, Serialize};
+
+use super::{digest_bytes, v1};
+use crate::{DeviceIdentifier, DigestAlgorithm, DigestVersion, DuplicatePolicy, Hash, LimitPolicy};
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub(super) struct State<D: DigestAlgorithm, I: DeviceIdentifier> {
+    /// Name of the [`DigestAlgorithm`] of all digests.
+    pub algorithm: String,
+    pub own_device: I,
+    pub config: Config,
+    /// Identifier of the first pending message following the base entry.
+    pub pending_offset: u64,
+    /// The base entry of the hash-chain over pending messages, followed by
+    /// all pending messages.
+    pub pending_messages: Vec<PendingMessage<D, I>>,
+    pub tick: u64,
+    /// Next local sequence number.
+    pub local_seq: u64,
+    /// Pairwise chains, sorted by device.
+    pub chains: Vec<Chain<D, I>>,
+    /// Local sequence number of the first message record.
+    pub messages_offset: u64,
+    pub messages: Vec<MessageRecord<I>>,
+    /// Gossip claims about third-party pairs, sorted by pair.
+    pub gossip_claims: Vec<GossipPair<D, I>>,
+    pub last_server_seq: Option<u64>,
+    /// Counter of the latest message received from each sender, sorted by
+    /// sender.
+    pub sender_counters: Vec<(I, u64)>,
+    pub own_counter: u64,
+    pub retired: Vec<I>,
+    pub epoch: u64,
+}
+
+/// Binary dumps of schema version 1 have been written with the encodings of
+/// this version, lacking only the epoch.
+#[derive(Deserialize)]
+#[serde(bound = "")]
+pub(super) struct BinaryV1State<D: DigestAlgorithm, I: DeviceIdentifier> {
+    /// Name of the [`DigestAlgorithm`] of all digests.
+    pub algorithm: String,
+    pub own_device: I,
+    pub config: Config,
+    /// Identifier of the first pending message following the base entry.
+    pub pending_offset: u64,
+    /// The base entry of the hash-chain over pending messages, followed by
+    /// all pending messages.
+    pub pending_messages: Vec<PendingMessage<D, I>>,
+    pub tick: u64,
+    /// Next local sequence number.
+    pub local_seq: u64,
+    /// Pairwise chains, sorted by device.
+    pub chains: Vec<Chain<D, I>>,
+    /// Local sequence number of the first message record.
+    pub messages_offset: u64,
+    pub messages: Vec<MessageRecord<I>>,
+    /// Gossip claims about third-party pairs, sorted by pair.
+    pub gossip_claims: Vec<GossipPair<D, I>>,
+    pub last_server_seq: Option<u64>,
+    /// Counter of the latest message received from each sender, sorted by
+    /// sender.
+    pub sender_counters: Vec<(I, u64)>,
+    pub own_counter: u64,
+    pub retired: Vec<I>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct Config {
+    pub strict_validation_payloads: bool,
+    pub gossip: bool,
+    pub digest_version: DigestVersion,
+    pub default_peer_digest_version: Option<DigestVersion>,
+    #[serde(with = "variant_name")]
+    pub duplicate_policy: DuplicatePolicy,
+    pub max_chain_len: Option<u64>,
+    pub max_total_chain_len: Option<u64>,
+    #[serde(with = "variant_name")]
+    pub limit_policy: LimitPolicy,
+}
+
+/// Unit enums which are encoded by the names of their variants. The compact
+/// binary format would otherwise encode them by index.
+pub(super) trait VariantName: Copy + PartialEq + 'static {
+    const VARIANTS: &'static [(&'static str, Self)];
+}
+
+impl VariantName for DuplicatePolicy {
+    const VARIANTS: &'static [(&'static str, Self)] = &[
+        ("Reject", DuplicatePolicy::Reject),
+        ("Ignore", DuplicatePolicy::Ignore),
+    ];
+}
+
+impl VariantName for LimitPolicy {
+    const VARIANTS: &'static [(&'static str, Self)] = &[
+        ("Reject", LimitPolicy::Reject),
+        ("Evict", LimitPolicy::Evict),
+        ("Compact", LimitPolicy::Compact),
+    ];
+}
+
+pub(super) mod variant_name {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::VariantName;
+
+    pub fn serialize<T: VariantName, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let (name, _) = T::VARIANTS.iter().find(|(_, v)| v == value).unwrap();
+        serializer.serialize_str(name)
+    }
+
+    pub fn deserialize<'de, T: VariantName, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        T::VARIANTS
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, value)| *value)
+            .ok_or_else(|| D::Error::unknown_variant(&name, &[]))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub(super) struct PendingMessage<D: DigestAlgorithm, I: DeviceIdentifier> {
+    pub version: DigestVersion,
+    #[serde(
+        serialize_with = "digest_bytes::serialize::<D, _>",
+        deserialize_with = "digest_bytes::deserialize::<D, _>"
+    )]
+    pub digest: Hash<D>,
+    pub tick: u64,
+    pub counted_recipients: Option<Vec<I>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub(super) struct ChainEntry<D: DigestAlgorithm> {
+    pub local_seq: u64,
+    pub version: DigestVersion,
+    #[serde(
+        serialize_with = "digest_bytes::serialize::<D, _>",
+        deserialize_with = "digest_bytes::deserialize::<D, _>"
+    )]
+    pub digest: Hash<D>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub(super) struct Checkpoint<D: DigestAlgorithm> {
+    pub seq: u64,
+    pub entry: ChainEntry<D>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub(super) struct Chain<D: DigestAlgorithm, I: DeviceIdentifier> {
+    pub device: I,
+    /// Sequence number of the first entry.
+    pub offset: u64,
+    pub validated_local_seq: u64,
+    pub entries: Vec<ChainEntry<D>>,
+    pub digest_version: Option<DigestVersion>,
+    pub sent_counter: u64,
+    pub received_counter: u64,
+    pub unverifiable_from: Option<u64>,
+    pub checkpoint: Option<Checkpoint<D>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub(super) struct MessageRecord<I: DeviceIdentifier> {
+    pub recipients: Vec<I>,
+    pub counters: Option<Vec<u64>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub(super) struct GossipPair<D: DigestAlgorithm, I: DeviceIdentifier> {
+    pub pair: (I, I),
+    /// Claims sorted by local sequence number.
+    pub claims: Vec<GossipClaim<D, I>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub(super) struct GossipClaim<D: DigestAlgorithm, I: DeviceIdentifier> {
+    pub local_seq: u64,
+    pub claimer: I,
+    pub third_party_seq: u64,
+    #[serde(
+        serialize_with = "digest_bytes::serialize::<D, _>",
+        deserialize_with = "digest_bytes::deserialize::<D, _>"
+    )]
+    pub digest: Hash<D>,
+}
+
+fn migrate_entry<D: DigestAlgorithm>(entry: v1::ChainEntry<D>) -> ChainEntry<D> {
+    ChainEntry {
+        local_seq: entry.local_seq,
+        version: entry.version,
+        digest: entry.digest,
+    }
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> From<v1::State<D, I>> for State<D, I> {
+    fn from(state: v1::State<D, I>) -> Self {
+        let config = state.config;
+        State {
+            algorithm: state.algorithm,
+            own_device: state.own_device,
+            config: Config {
+                strict_validation_payloads: config.strict_validation_payloads,
+                gossip: config.gossip,
+                digest_version: config.digest_version,
+                default_peer_digest_version: config.default_peer_digest_version,
+                duplicate_policy: config.duplicate_policy,
+                max_chain_len: config.max_chain_len,
+                max_total_chain_len: config.max_total_chain_len,
+                limit_policy: config.limit_policy,
+            },
+            pending_offset: state.pending_offset,
+            pending_messages: state
+                .pending_messages
+                .into_iter()
+                .map(|pending| PendingMessage {
+                    version: pending.version,
+                    digest: pending.digest,
+                    tick: pending.tick,
+                    counted_recipients: pending.counted_recipients,
+                })
+                .collect(),
+            tick: state.tick,
+            local_seq: state.local_seq,
+            chains: state
+                .chains
+                .into_iter()
+                .map(|chain| Chain {
+                    device: chain.device,
+                    offset: chain.offset,
+                    validated_local_seq: chain.validated_local_seq,
+                    entries: chain.entries.into_iter().map(migrate_entry).collect(),
+                    digest_version: chain.digest_version,
+                    sent_counter: chain.sent_counter,
+                    received_counter: chain.received_counter,
+                    unverifiable_from: chain.unverifiable_from,
+                    checkpoint: chain.checkpoint.map(|checkpoint| Checkpoint {
+                        seq: checkpoint.seq,
+                        entry: migrate_entry(checkpoint.entry),
+                    }),
+                })
+                .collect(),
+            messages_offset: state.messages_offset,
+            messages: state
+                .messages
+                .into_iter()
+                .map(|record| MessageRecord {
+                    recipients: record.recipients,
+                    counters: record.counters,
+                })
+                .collect(),
+            gossip_claims: state
+                .gossip_claims
+                .into_iter()
+                .map(|pair| GossipPair {
+                    pair: pair.pair,
+                    claims: pair
+                        .claims
+                        .into_iter()
+                        .map(|claim| GossipClaim {
+                            local_seq: claim.local_seq,
+                            claimer: claim.claimer,
+                            third_party_seq: claim.third_party_seq,
+                            digest: claim.digest,
+                        })
+                        .collect(),
+                })
+                .collect(),
+            last_server_seq: state.last_server_seq,
+            sender_counters: state.sender_counters,
+            own_counter: state.own_counter,
+            retired: state.retired,
+            // State dumped before epochs were kept predates all epochs:
+            epoch: 0,
+        }
+    }
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> From<BinaryV1State<D, I>> for State<D, I> {
+    fn from(state: BinaryV1State<D, I>) -> Self {
+        State {
+            algorithm: state.algorithm,
+            own_device: state.own_device,
+            config: state.config,
+            pending_offset: state.pending_offset,
+            pending_messages: state.pending_messages,
+            tick: state.tick,
+            local_seq: state.local_seq,
+            chains: state.chains,
+            messages_offset: state.messages_offset,
+            messages: state.messages,
+            gossip_claims: state.gossip_claims,
+            last_server_seq: state.last_server_seq,
+            sender_counters: state.sender_counters,
+            own_counter: state.own_counter,
+            retired: state.retired,
+            epoch: 0,
+        }
+    }
+}
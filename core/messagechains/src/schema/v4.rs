@@ -0,0 +1,74 @@
+//! Schema version 4, which adds the health of peers, such that quarantined
+//! peers stay quarantined after restoring the state. All other structures are
+//! unchanged from version 3.
+
+use serde::{Deserialize, Serialize};
+
+use super::v3;
+pub(super) use super::v3::{
+    Chain, ChainEntry, Checkpoint, Config, GossipClaim, GossipPair, MessageRecord, PendingMessage,
+    RecipientRun,
+};
+use crate::{DeviceIdentifier, DigestAlgorithm, PeerHealth};
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub(super) struct State<D: DigestAlgorithm, I: DeviceIdentifier> {
+    /// Name of the [`DigestAlgorithm`] of all digests.
+    pub algorithm: String,
+    pub own_device: I,
+    pub config: Config,
+    /// Identifier of the first pending message following the base entry.
+    pub pending_offset: u64,
+    /// The base entry of the hash-chain over pending messages, followed by
+    /// all pending messages.
+    pub pending_messages: Vec<PendingMessage<D, I>>,
+    pub tick: u64,
+    /// Next local sequence number.
+    pub local_seq: u64,
+    /// Pairwise chains, sorted by device.
+    pub chains: Vec<Chain<D, I>>,
+    /// Local sequence number of the first message record.
+    pub messages_offset: u64,
+    pub messages: Vec<MessageRecord<I>>,
+    /// Gossip claims about third-party pairs, sorted by pair.
+    pub gossip_claims: Vec<GossipPair<D, I>>,
+    pub last_server_seq: Option<u64>,
+    /// Counter of the latest message received from each sender, sorted by
+    /// sender.
+    pub sender_counters: Vec<(I, u64)>,
+    pub own_counter: u64,
+    pub retired: Vec<I>,
+    pub epoch: u64,
+    /// Runs of consecutive messages with the same recipients, sorted by
+    /// their first local sequence number.
+    pub recipient_history: Vec<RecipientRun<I>>,
+    /// Health of all peers which are not healthy, sorted by device.
+    pub peer_health: Vec<(I, PeerHealth)>,
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> From<v3::State<D, I>> for State<D, I> {
+    fn from(state: v3::State<D, I>) -> Self {
+        State {
+            algorithm: state.algorithm,
+            own_device: state.own_device,
+            config: state.config,
+            pending_offset: state.pending_offset,
+            pending_messages: state.pending_messages,
+            tick: state.tick,
+            local_seq: state.local_seq,
+            chains: state.chains,
+            messages_offset: state.messages_offset,
+            messages: state.messages,
+            gossip_claims: state.gossip_claims,
+            last_server_seq: state.last_server_seq,
+            sender_counters: state.sender_counters,
+            own_counter: state.own_counter,
+            retired: state.retired,
+            epoch: state.epoch,
+            recipient_history: state.recipient_history,
+            // Peers were not quarantined before:
+            peer_health: Vec::new(),
+        }
+    }
+}
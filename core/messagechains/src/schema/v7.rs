@@ -0,0 +1,112 @@
+//! Schema version 7, which adds the quarantine threshold to the configuration
+//! and the number of violations recorded for each peer which is not healthy.
+//! All other structures are unchanged from version 6.
+
+use serde::{Deserialize, Serialize};
+
+pub(super) use super::v6::{
+    Chain, ChainEntry, Checkpoint, GossipClaim, GossipPair, MessageRecord, PendingMessage,
+};
+use super::{v2, v6};
+use crate::{
+    DeviceIdentifier, DigestAlgorithm, DigestVersion, DuplicatePolicy, LimitPolicy, PeerHealth,
+};
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub(super) struct State<D: DigestAlgorithm, I: DeviceIdentifier> {
+    /// Name of the [`DigestAlgorithm`] of all digests.
+    pub algorithm: String,
+    pub own_device: I,
+    pub config: Config,
+    /// Identifier of the first pending message following the base entry.
+    pub pending_offset: u64,
+    /// The base entry of the hash-chain over pending messages, followed by
+    /// all pending messages.
+    pub pending_messages: Vec<PendingMessage<D, I>>,
+    pub tick: u64,
+    /// Next local sequence number.
+    pub local_seq: u64,
+    /// Pairwise chains, sorted by device.
+    pub chains: Vec<Chain<D, I>>,
+    /// Local sequence number of the first message record.
+    pub messages_offset: u64,
+    /// Local sequence number of the first message whose status is known,
+    /// at most `messages_offset`.
+    pub messages_known_from: u64,
+    pub messages: Vec<MessageRecord<I>>,
+    /// Gossip claims about third-party pairs, sorted by pair.
+    pub gossip_claims: Vec<GossipPair<D, I>>,
+    pub last_server_seq: Option<u64>,
+    /// Counter of the latest message received from each sender, sorted by
+    /// sender.
+    pub sender_counters: Vec<(I, u64)>,
+    pub own_counter: u64,
+    pub retired: Vec<I>,
+    pub epoch: u64,
+    /// Health of all peers which are not healthy, sorted by device.
+    pub peer_health: Vec<(I, PeerHealth)>,
+    /// Number of violations recorded for each peer which is not healthy,
+    /// sorted by device.
+    pub peer_violations: Vec<(I, u64)>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct Config {
+    pub strict_validation_payloads: bool,
+    pub gossip: bool,
+    pub digest_version: DigestVersion,
+    pub default_peer_digest_version: Option<DigestVersion>,
+    #[serde(with = "v2::variant_name")]
+    pub duplicate_policy: DuplicatePolicy,
+    pub max_chain_len: Option<u64>,
+    pub max_total_chain_len: Option<u64>,
+    #[serde(with = "v2::variant_name")]
+    pub limit_policy: LimitPolicy,
+    pub quarantine_threshold: Option<u64>,
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> From<v6::State<D, I>> for State<D, I> {
+    fn from(state: v6::State<D, I>) -> Self {
+        let config = state.config;
+        // Each peer which is not healthy has been involved in at least one
+        // violation:
+        let peer_violations = state
+            .peer_health
+            .iter()
+            .map(|(device, _)| (device.clone(), 1))
+            .collect();
+
+        State {
+            algorithm: state.algorithm,
+            own_device: state.own_device,
+            config: Config {
+                strict_validation_payloads: config.strict_validation_payloads,
+                gossip: config.gossip,
+                digest_version: config.digest_version,
+                default_peer_digest_version: config.default_peer_digest_version,
+                duplicate_policy: config.duplicate_policy,
+                max_chain_len: config.max_chain_len,
+                max_total_chain_len: config.max_total_chain_len,
+                limit_policy: config.limit_policy,
+                quarantine_threshold: None,
+            },
+            pending_offset: state.pending_offset,
+            pending_messages: state.pending_messages,
+            tick: state.tick,
+            local_seq: state.local_seq,
+            chains: state.chains,
+            messages_offset: state.messages_offset,
+            messages_known_from: state.messages_known_from,
+            messages: state.messages,
+            gossip_claims: state.gossip_claims,
+            last_server_seq: state.last_server_seq,
+            sender_counters: state.sender_counters,
+            own_counter: state.own_counter,
+            retired: state.retired,
+            epoch: state.epoch,
+            peer_health: state.peer_health,
+            peer_violations,
+        }
+    }
+}
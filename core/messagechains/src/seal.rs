@@ -0,0 +1,75 @@
+//! Dumps encrypted and authenticated with a key supplied by the application,
+//! such that state written to untrusted storage neither reveals its peers
+//! nor can be modified undetected.
+//!
+//! Sealed state consists of a format header, a random nonce and the state in
+//! its compact binary format (see [`MessageChains::to_binary`]), encrypted
+//! with XChaCha20-Poly1305. The header is authenticated as associated data.
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+use crate::{DeviceIdentifier, DigestAlgorithm, DumpError, EpochCounter, MessageChains};
+
+/// Identifies sealed state and the version of its format.
+const SEALED_HEADER: &[u8] = b"mcs\x01";
+const NONCE_LEN: usize = 24;
+
+/// A 256-bit key to seal state with, which the application should keep
+/// separately from the sealed state, e.g., in a platform key store.
+pub type SealingKey = [u8; 32];
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> MessageChains<D, I> {
+    /// Dump the state in the compact binary format, encrypted and
+    /// authenticated with `key`. A random nonce is drawn for every dump, so
+    /// the same key may be used for any number of dumps.
+    ///
+    /// Sealing does not prevent replacing the state by an older dump sealed
+    /// with the same key, which is detected through its epoch instead. Like
+    /// [`MessageChains::to_binary`], this starts a new epoch.
+    pub fn to_sealed(&mut self, key: &SealingKey) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = XChaCha20Poly1305::new(key.into())
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &self.to_binary(),
+                    aad: SEALED_HEADER,
+                },
+            )
+            // Only fails for plaintexts exceeding 256 GiB:
+            .unwrap();
+
+        [SEALED_HEADER, &nonce[..], &ciphertext].concat()
+    }
+
+    /// Restore state dumped through [`MessageChains::to_sealed`]. State
+    /// sealed with a different key, or modified in any way, is rejected with
+    /// [`DumpError::Unauthenticated`]. Its epoch is checked like by
+    /// [`MessageChains::from_dump`].
+    pub fn from_sealed(
+        sealed: &[u8],
+        key: &SealingKey,
+        counter: &mut impl EpochCounter,
+    ) -> Result<Self, DumpError> {
+        let body = sealed
+            .strip_prefix(SEALED_HEADER)
+            .filter(|body| body.len() >= NONCE_LEN)
+            .ok_or_else(|| DumpError::Decode("not sealed state".to_string()))?;
+        let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+        let mut nonce_bytes = XNonce::default();
+        nonce_bytes.copy_from_slice(nonce);
+
+        let plaintext = XChaCha20Poly1305::new(key.into())
+            .decrypt(
+                &nonce_bytes,
+                Payload {
+                    msg: ciphertext,
+                    aad: SEALED_HEADER,
+                },
+            )
+            .map_err(|_| DumpError::Unauthenticated)?;
+
+        Self::from_binary(&plaintext, counter)
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::{DeviceIdentifier, DigestAlgorithm, Error, MessageChains};
+
+/// Whether a recipient has validated a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ValidationStatus {
+    /// The recipient has validated the message.
+    Validated,
+    /// The recipient has not validated the message yet.
+    Pending,
+    /// The entry of the message has been dropped from the pairwise chain
+    /// with the recipient due to memory limits, or since the recipient has
+    /// been re-added after its retirement, before the recipient validated
+    /// it. It is only validated along with a subsequent entry.
+    Trimmed,
+    /// The recipient has been retired (see
+    /// [`MessageChains::retire_device`]), and will never validate the
+    /// message.
+    Retired,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RecipientStatus<I: DeviceIdentifier> {
+    pub device: I,
+    pub status: ValidationStatus,
+}
+
+/// Validation status of a message for each of its recipients.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MessageStatus<I: DeviceIdentifier> {
+    pub local_seq: usize,
+    /// All recipients except our own device, sorted by device.
+    pub recipients: Vec<RecipientStatus<I>>,
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> MessageChains<D, I> {
+    /// Local sequence numbers and recipients of all recorded messages
+    /// starting at `local_seq`.
+    pub(crate) fn unvalidated_records(
+        &self,
+        local_seq: usize,
+    ) -> impl Iterator<Item = (usize, &[I])> + '_ {
+        let skip = local_seq.saturating_sub(self.messages_offset);
+        self.messages
+            .iter()
+            .zip(self.messages_offset..)
+            .skip(skip)
+            .map(|(record, local_seq)| (local_seq, &record.recipients[..]))
+    }
+
+    fn validation_status(&self, device: &I, local_seq: usize) -> ValidationStatus {
+        if self.retired.contains(device) {
+            return ValidationStatus::Retired;
+        }
+
+        let chain = match self.chains.get(device) {
+            Some(chain) => chain,
+            None => return ValidationStatus::Pending,
+        };
+        if local_seq < chain.validated_local_seq {
+            return ValidationStatus::Validated;
+        }
+
+        let held = chain
+            .chain
+            .binary_search_by_key(&local_seq, |entry| entry.local_seq)
+            .is_ok()
+            || matches!(&chain.checkpoint, Some((_, entry)) if entry.local_seq == local_seq);
+        if held {
+            ValidationStatus::Pending
+        } else {
+            ValidationStatus::Trimmed
+        }
+    }
+
+    /// Report for every recipient of the message with local sequence number
+    /// `local_seq` whether it has validated the message.
+    ///
+    /// Recipients are only known while the record of the message is kept,
+    /// i.e., until all of them have validated it. Older messages are
+    /// reported as [`Error::UnknownMessage`], see
+    /// [`MessageChains::message_fully_validated`] instead.
+    pub fn message_status(&self, local_seq: usize) -> Result<MessageStatus<I>, Error<I>> {
+        let recipients = self
+            .message_recipients(local_seq)
+            .ok_or(Error::UnknownMessage(local_seq))?;
+
+        Ok(MessageStatus {
+            local_seq,
+            recipients: recipients
+                .iter()
+                .filter(|r| **r != self.own_device)
+                .map(|r| RecipientStatus {
+                    device: r.clone(),
+                    status: self.validation_status(r, local_seq),
+                })
+                .collect(),
+        })
+    }
+
+    /// The local sequence number of the oldest message each peer has not
+    /// validated yet, for all peers which have any such message.
+    pub fn oldest_unvalidated_messages(&self) -> BTreeMap<I, usize> {
+        let mut oldest = BTreeMap::new();
+        for (device, chain) in &self.chains {
+            if *device == self.own_device {
+                continue;
+            }
+
+            // Messages not validated by the device either have their records
+            // kept, or have been inserted before records were kept, in which
+            // case they may still be held in the chain:
+            let recorded = self
+                .unvalidated_records(chain.validated_local_seq)
+                .find(|(_, recipients)| recipients.binary_search(device).is_ok())
+                .map(|(local_seq, _)| local_seq);
+            let held = chain
+                .chain
+                .iter()
+                .map(|entry| entry.local_seq)
+                .find(|local_seq| *local_seq >= chain.validated_local_seq);
+            let local_seq = match (recorded, held) {
+                (Some(recorded), Some(held)) => Some(std::cmp::min(recorded, held)),
+                (recorded, held) => recorded.or(held),
+            };
+            if let Some(local_seq) = local_seq {
+                oldest.insert(device.clone(), local_seq);
+            }
+        }
+
+        oldest
+    }
+}
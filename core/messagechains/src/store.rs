@@ -0,0 +1,569 @@
+//! Incremental persistence of [`MessageChains`] state.
+//!
+//! Every modification of the state is expressed as an [`Operation`]. While a
+//! [`ChainStore`] is attached, operations are collected and written to the
+//! store as one batch by [`MessageChains::commit`], instead of dumping the
+//! whole state after every call. Stores keep a snapshot of the full state
+//! (see [`MessageChains::to_binary`]) followed by a log of all batches
+//! committed since, from which the state is rebuilt by
+//! [`MessageChains::recover`].
+
+use std::fmt::Debug;
+
+use serde::{Deserialize, Serialize};
+
+use crate::schema::digest_bytes;
+use crate::{
+    ChainEntry, Config, DeviceIdentifier, DigestAlgorithm, DigestVersion, EpochCounter, Hash,
+    MessageChains, MessageRecord, PeerHealth, PendingMessage, StoreError,
+};
+
+mod file;
+pub use file::FileStore;
+
+mod memory;
+pub use memory::MemoryStore;
+
+/// Number of appended batches after which the shipped stores ask for a new
+/// snapshot, unless configured otherwise.
+pub const DEFAULT_SNAPSHOT_INTERVAL: usize = 1024;
+
+/// A single modification of [`MessageChains`] state. Sequence numbers refer to
+/// the state the operation is applied to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub enum Operation<D: DigestAlgorithm, I: DeviceIdentifier> {
+    /// Append an entry to the pairwise chain with `device`, which must have
+    /// been started through [`Operation::AddChain`].
+    AppendEntry {
+        device: I,
+        local_seq: usize,
+        version: DigestVersion,
+        #[serde(
+            serialize_with = "digest_bytes::serialize::<D, _>",
+            deserialize_with = "digest_bytes::deserialize::<D, _>"
+        )]
+        digest: Hash<D>,
+    },
+    /// Start an empty pairwise chain with `device`, before the first entry
+    /// is appended to it or once it has been admitted.
+    AddChain {
+        device: I,
+    },
+    /// Trim the first `count` entries of the pairwise chain with `device`
+    /// after a validation, dropping any checkpoint.
+    TrimChain {
+        device: I,
+        count: usize,
+    },
+    /// Drop the first `count` entries of the pairwise chain with `device` to
+    /// satisfy the configured limits, keeping the last one as a checkpoint
+    /// if `compact` is set.
+    DropEntries {
+        device: I,
+        count: usize,
+        compact: bool,
+    },
+    /// Update the first local sequence number not validated by `device`.
+    SetValidated {
+        device: I,
+        validated_local_seq: usize,
+    },
+    SetPeerDigestVersion {
+        device: I,
+        version: Option<DigestVersion>,
+    },
+    /// Set the per-pair counter of the next counted message sent to
+    /// `device`, which may be our own device.
+    SetSentCounter {
+        device: I,
+        counter: u64,
+    },
+    /// Set the per-pair counter of the next counted message expected from
+    /// `device`.
+    SetReceivedCounter {
+        device: I,
+        counter: u64,
+    },
+    PushPending {
+        version: DigestVersion,
+        #[serde(
+            serialize_with = "digest_bytes::serialize::<D, _>",
+            deserialize_with = "digest_bytes::deserialize::<D, _>"
+        )]
+        digest: Hash<D>,
+        tick: u64,
+        counted_recipients: Option<Vec<I>>,
+    },
+    /// Drop the oldest pending message, which has been received back from
+    /// the server or declared lost.
+    PopPending,
+    /// Drop the most recently sent pending message.
+    CancelPending,
+    SetPendingTick {
+        id: u64,
+        tick: u64,
+    },
+    SetTick {
+        tick: u64,
+    },
+    /// Assign the next local sequence number to an inserted message, and
+    /// record its recipients.
+    PushMessage {
+        recipients: Vec<I>,
+        counters: Option<Vec<u64>>,
+    },
+    /// Drop the first `count` message records.
+    TrimMessages {
+        count: usize,
+    },
+    /// Drop the first `count` message records to satisfy the configured
+    /// limits, whether or not their messages have been validated.
+    EvictMessages {
+        count: usize,
+    },
+    SetLastServerSeq {
+        seq: u64,
+    },
+    SetSenderCounter {
+        sender: I,
+        counter: u64,
+    },
+    AddGossipClaim {
+        pair: (I, I),
+        local_seq: usize,
+        claimer: I,
+        third_party_seq: usize,
+        #[serde(
+            serialize_with = "digest_bytes::serialize::<D, _>",
+            deserialize_with = "digest_bytes::deserialize::<D, _>"
+        )]
+        digest: Hash<D>,
+    },
+    /// Drop all gossip claims about `pair` up to and including the message
+    /// `local_seq`, on which both devices agree.
+    SettleGossipClaims {
+        pair: (I, I),
+        local_seq: usize,
+    },
+    Retire {
+        device: I,
+    },
+    Unretire {
+        device: I,
+    },
+    SetEpoch {
+        epoch: u64,
+    },
+    /// Peers are healthy unless set otherwise.
+    SetPeerHealth {
+        device: I,
+        health: PeerHealth,
+    },
+    /// Count a violation against `device`.
+    RecordViolation {
+        device: I,
+    },
+}
+
+// Batches are encoded as CBOR, which (unlike the packed form of snapshots)
+// keeps the names of fields and variants. Logs hence remain readable when
+// operations are added later on:
+fn encode_batch<D: DigestAlgorithm, I: DeviceIdentifier>(batch: &[Operation<D, I>]) -> Vec<u8> {
+    // Writing into a Vec never fails, and operations are always
+    // serializable:
+    serde_cbor::to_vec(&batch).unwrap()
+}
+
+fn decode_batch<D: DigestAlgorithm, I: DeviceIdentifier>(
+    bytes: &[u8],
+) -> Result<Vec<Operation<D, I>>, StoreError> {
+    serde_cbor::from_slice(bytes).map_err(|e| StoreError::Corrupt(e.to_string()))
+}
+
+/// State read back from a [`ChainStore`].
+#[derive(Debug, Clone)]
+pub struct StoredState<D: DigestAlgorithm, I: DeviceIdentifier> {
+    /// The latest snapshot, as dumped by [`MessageChains::to_binary`].
+    pub snapshot: Vec<u8>,
+    /// All operations committed since the snapshot was taken, in order.
+    pub operations: Vec<Operation<D, I>>,
+}
+
+/// Storage backend which [`MessageChains`] state is written to incrementally.
+pub trait ChainStore<D: DigestAlgorithm, I: DeviceIdentifier> {
+    /// Durably append a batch of operations. After a crash, the batch must be
+    /// recovered either completely or not at all.
+    fn append(&mut self, batch: &[Operation<D, I>]) -> Result<(), StoreError>;
+
+    /// Durably replace all stored state by a new snapshot.
+    fn snapshot(&mut self, snapshot: &[u8]) -> Result<(), StoreError>;
+
+    /// Read back the stored state, if any snapshot has been taken.
+    fn load(&mut self) -> Result<Option<StoredState<D, I>>, StoreError>;
+
+    /// Whether enough batches have been appended since the last snapshot that
+    /// the next commit should take a new one instead.
+    fn needs_snapshot(&self) -> bool;
+}
+
+/// The [`ChainStore`] attached to a [`MessageChains`] instance, if any. It is
+/// not part of the serialized state.
+pub(crate) struct Persistence<D: DigestAlgorithm, I: DeviceIdentifier>(Option<Attached<D, I>>);
+
+struct Attached<D: DigestAlgorithm, I: DeviceIdentifier> {
+    store: Box<dyn ChainStore<D, I>>,
+    // Operations applied since the last commit:
+    journal: Vec<Operation<D, I>>,
+    // Configuration as of the last commit. It is not modified through
+    // operations, but persisted by taking a snapshot whenever it changes:
+    config: Config,
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> Default for Persistence<D, I> {
+    fn default() -> Self {
+        Persistence(None)
+    }
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> Debug for Persistence<D, I> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.0 {
+            Some(attached) => write!(
+                f,
+                "Persistence(Some(.., {} uncommitted))",
+                attached.journal.len()
+            ),
+            None => write!(f, "Persistence(None)"),
+        }
+    }
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> Attached<D, I> {
+    fn commit(&mut self, chains: &MessageChains<D, I>) -> Result<(), StoreError> {
+        if self.config != chains.config || self.store.needs_snapshot() {
+            self.store.snapshot(&chains.encode_binary())?;
+            self.config = chains.config.clone();
+        } else if !self.journal.is_empty() {
+            self.store.append(&self.journal)?;
+        }
+
+        self.journal.clear();
+        Ok(())
+    }
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> MessageChains<D, I> {
+    /// Persist the state to `store` from now on. This takes an initial
+    /// snapshot, replacing anything held by the store, and starts a new
+    /// epoch like every commit.
+    pub fn attach_store(
+        &mut self,
+        mut store: impl ChainStore<D, I> + 'static,
+    ) -> Result<(), StoreError> {
+        store.snapshot(&self.to_binary())?;
+        self.store = Persistence(Some(Attached {
+            store: Box::new(store),
+            journal: Vec::new(),
+            config: self.config.clone(),
+        }));
+        Ok(())
+    }
+
+    /// Stop persisting the state. Uncommitted operations are discarded.
+    pub fn detach_store(&mut self) {
+        self.store = Persistence(None);
+    }
+
+    /// Write all operations applied since the last commit to the attached
+    /// store, as a single batch. Takes a new snapshot instead if the store
+    /// asks for one, or if the configuration has changed.
+    ///
+    /// This should be called after every call whose effects must survive a
+    /// crash, e.g., before sending a message or acknowledging its receipt to
+    /// the server. If it fails, the operations are kept for the next commit.
+    ///
+    /// Every commit which persists anything starts a new epoch, which must
+    /// be recorded once it succeeds (see [`EpochCounter`]).
+    pub fn commit(&mut self) -> Result<(), StoreError> {
+        let persisting = match &self.store.0 {
+            Some(attached) => {
+                !attached.journal.is_empty()
+                    || attached.config != self.config
+                    || attached.store.needs_snapshot()
+            }
+            None => return Ok(()),
+        };
+        if persisting {
+            self.advance_epoch();
+        }
+
+        let mut attached = match self.store.0.take() {
+            Some(attached) => attached,
+            None => return Ok(()),
+        };
+        let result = attached.commit(self);
+        self.store = Persistence(Some(attached));
+        result
+    }
+
+    /// Rebuild the state held by `store`, by replaying all committed
+    /// operations onto its latest snapshot. The store stays attached.
+    ///
+    /// State of an older epoch than recorded by `counter` is rejected like
+    /// by [`MessageChains::from_dump`].
+    pub fn recover(
+        mut store: impl ChainStore<D, I> + 'static,
+        counter: &mut impl EpochCounter,
+    ) -> Result<Self, StoreError> {
+        let stored = store.load()?.ok_or(StoreError::Empty)?;
+        let mut chains = Self::decode_binary(&stored.snapshot)?;
+        for operation in stored.operations {
+            chains.replay(operation)?;
+        }
+        chains.check_epoch(counter)?;
+
+        chains.store = Persistence(Some(Attached {
+            store: Box::new(store),
+            journal: Vec::new(),
+            config: chains.config.clone(),
+        }));
+        Ok(chains)
+    }
+
+    /// Apply an operation to the state, and record it for the next commit if
+    /// a store is attached. All modifications of the state (except for its
+    /// configuration) must go through this.
+    ///
+    /// Operations are only ever applied to a state they match. An operation
+    /// which doesn't is dropped instead of being recorded, as it could not be
+    /// replayed.
+    pub(crate) fn apply(&mut self, operation: Operation<D, I>) {
+        let journaled = self.store.0.as_ref().map(|_| operation.clone());
+        let result = self.replay(operation);
+        debug_assert!(result.is_ok(), "apply: {:?}", result);
+        match (result, journaled, &mut self.store.0) {
+            (Ok(()), Some(operation), Some(attached)) => attached.journal.push(operation),
+            (Err(e), _, _) => log::error!("apply: {}", e),
+            _ => {}
+        }
+    }
+
+    // Operations read back from a store are not trusted to be consistent with
+    // the state. Any operation which doesn't match it means that the log has
+    // been corrupted, and fails without modifying the state:
+    fn replay(&mut self, operation: Operation<D, I>) -> Result<(), StoreError> {
+        match operation {
+            Operation::AppendEntry {
+                device,
+                local_seq,
+                version,
+                digest,
+            } => {
+                self.chain_mut("AppendEntry", &device)?
+                    .chain
+                    .push_back(ChainEntry {
+                        local_seq,
+                        version,
+                        digest,
+                    });
+            }
+            Operation::AddChain { device } => {
+                if self.chains.contains_key(&device) {
+                    return Err(corrupt("AddChain", &device));
+                }
+                self.chains.insert(device, Default::default());
+            }
+            Operation::TrimChain { device, count } => {
+                let chain = self
+                    .chains
+                    .get_mut(&device)
+                    .filter(|chain| count <= chain.chain.len())
+                    .ok_or_else(|| corrupt("TrimChain", &device))?;
+                chain.offset += count;
+                chain.chain.drain(..count);
+                chain.unverifiable_from = None;
+                chain.checkpoint = None;
+            }
+            Operation::DropEntries {
+                device,
+                count,
+                compact,
+            } => {
+                let chain = self
+                    .chains
+                    .get_mut(&device)
+                    .filter(|chain| 0 < count && count <= chain.chain.len())
+                    .ok_or_else(|| corrupt("DropEntries", &device))?;
+                crate::limits::drop_entries(chain, count, compact);
+            }
+            Operation::SetValidated {
+                device,
+                validated_local_seq,
+            } => {
+                self.chain_mut("SetValidated", &device)?.validated_local_seq = validated_local_seq;
+            }
+            Operation::SetPeerDigestVersion { device, version } => {
+                self.chain_mut("SetPeerDigestVersion", &device)?
+                    .digest_version = version;
+            }
+            Operation::SetSentCounter { device, counter } => {
+                if device == self.own_device {
+                    self.own_counter = counter;
+                } else {
+                    self.chain_mut("SetSentCounter", &device)?.sent_counter = counter;
+                }
+            }
+            Operation::SetReceivedCounter { device, counter } => {
+                self.chain_mut("SetReceivedCounter", &device)?
+                    .received_counter = counter;
+            }
+            Operation::PushPending {
+                version,
+                digest,
+                tick,
+                counted_recipients,
+            } => {
+                self.pending_messages.push_back(PendingMessage {
+                    version,
+                    digest,
+                    tick,
+                    counted_recipients,
+                });
+            }
+            // The base entry of the hash-chain over pending messages is
+            // always kept:
+            Operation::PopPending => {
+                if self.pending_messages.len() <= 1 {
+                    return Err(StoreError::Corrupt(
+                        "PopPending: no pending message".to_string(),
+                    ));
+                }
+                self.pending_messages.pop_front();
+                self.pending_offset += 1;
+            }
+            Operation::CancelPending => {
+                if self.pending_messages.len() <= 1 {
+                    return Err(StoreError::Corrupt(
+                        "CancelPending: no pending message".to_string(),
+                    ));
+                }
+                self.pending_messages.pop_back();
+            }
+            Operation::SetPendingTick { id, tick } => {
+                let index = self.pending_index(id).map_err(|_| {
+                    StoreError::Corrupt(format!("SetPendingTick: unknown pending message {}", id))
+                })?;
+                self.pending_messages[index].tick = tick;
+            }
+            Operation::SetTick { tick } => {
+                self.tick = tick;
+            }
+            Operation::PushMessage {
+                recipients,
+                counters,
+            } => {
+                self.local_seq += 1;
+                self.messages.push_back(MessageRecord {
+                    recipients,
+                    counters,
+                });
+            }
+            Operation::TrimMessages { count } => {
+                if count > self.messages.len() {
+                    return Err(StoreError::Corrupt(format!(
+                        "TrimMessages: only {} message records",
+                        self.messages.len()
+                    )));
+                }
+                self.messages.drain(..count);
+                self.messages_offset += count;
+            }
+            Operation::EvictMessages { count } => {
+                if count > self.messages.len() {
+                    return Err(StoreError::Corrupt(format!(
+                        "EvictMessages: only {} message records",
+                        self.messages.len()
+                    )));
+                }
+                self.messages.drain(..count);
+                self.messages_offset += count;
+                self.messages_known_from = self.messages_offset;
+            }
+            Operation::SetLastServerSeq { seq } => {
+                self.last_server_seq = Some(seq);
+            }
+            Operation::SetSenderCounter { sender, counter } => {
+                self.sender_counters.insert(sender, counter);
+            }
+            Operation::AddGossipClaim {
+                pair,
+                local_seq,
+                claimer,
+                third_party_seq,
+                digest,
+            } => {
+                self.gossip_claims.entry(pair).or_default().insert(
+                    local_seq,
+                    crate::gossip::GossipRecord {
+                        claimer,
+                        third_party_seq,
+                        digest,
+                    },
+                );
+            }
+            Operation::SettleGossipClaims { pair, local_seq } => {
+                let claims = self
+                    .gossip_claims
+                    .get_mut(&pair)
+                    .ok_or_else(|| corrupt("SettleGossipClaims", &pair))?;
+                *claims = claims.split_off(&(local_seq + 1));
+            }
+            Operation::Retire { device } => {
+                self.chains.remove(&device);
+                self.gossip_claims
+                    .retain(|(a, b), _| *a != device && *b != device);
+                self.sender_counters.remove(&device);
+                self.retired.insert(device);
+            }
+            Operation::Unretire { device } => {
+                if !self.retired.remove(&device) {
+                    return Err(corrupt("Unretire", &device));
+                }
+            }
+            Operation::SetEpoch { epoch } => {
+                self.epoch = epoch;
+            }
+            Operation::SetPeerHealth { device, health } => {
+                if health == PeerHealth::Healthy {
+                    self.peer_health.remove(&device);
+                    self.peer_violations.remove(&device);
+                } else {
+                    self.peer_health.insert(device, health);
+                }
+            }
+            Operation::RecordViolation { device } => {
+                *self.peer_violations.entry(device).or_default() += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn chain_mut(
+        &mut self,
+        operation: &str,
+        device: &I,
+    ) -> Result<&mut crate::DeviceState<D>, StoreError> {
+        self.chains
+            .get_mut(device)
+            .ok_or_else(|| corrupt(operation, device))
+    }
+}
+
+fn corrupt(operation: &str, subject: &impl Debug) -> StoreError {
+    StoreError::Corrupt(format!(
+        "{}: does not match the state of {:?}",
+        operation, subject
+    ))
+}
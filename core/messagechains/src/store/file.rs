@@ -0,0 +1,212 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use sha2::Digest;
+
+use super::{
+    decode_batch, encode_batch, ChainStore, Operation, StoredState, DEFAULT_SNAPSHOT_INTERVAL,
+};
+use crate::{DeviceIdentifier, DigestAlgorithm, StoreError};
+
+const SNAPSHOT_FILE: &str = "snapshot";
+const LOG_FILE: &str = "log";
+const CHECKSUM_LEN: usize = 8;
+const FRAME_HEADER_LEN: usize = 4 + CHECKSUM_LEN;
+
+/// A [`ChainStore`] keeping an append-only log of batches next to the latest
+/// snapshot, in the files `log` and `snapshot` of a directory.
+///
+/// Both files start with the generation of the snapshot, which is
+/// incremented with every snapshot. A log of an older generation has been
+/// superseded by a snapshot taken just before a crash, and is discarded.
+/// Batches are framed by their length and a checksum. A torn batch at the
+/// end of the log, as left behind by a crash while appending it, is
+/// discarded as well.
+#[derive(Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+    log: File,
+    log_len: u64,
+    generation: u64,
+    logged: usize,
+    snapshot_interval: usize,
+}
+
+impl FileStore {
+    /// Open the store in `dir`, which is created if necessary, and discard
+    /// any torn batch at the end of its log.
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let generation = match File::open(dir.join(SNAPSHOT_FILE)) {
+            Ok(mut snapshot) => read_generation(&mut snapshot)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+
+        let log_bytes = match fs::read(dir.join(LOG_FILE)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        let mut store = FileStore {
+            log: OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(dir.join(LOG_FILE))?,
+            dir,
+            log_len: 8,
+            generation,
+            logged: 0,
+            snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+        };
+
+        if log_bytes.len() < 8 || log_bytes[..8] != generation.to_le_bytes() {
+            store.reset_log()?;
+        } else {
+            let (frames, valid_len) = frames(&log_bytes);
+            if valid_len < log_bytes.len() {
+                log::debug!(
+                    "FileStore::open: discarding {} bytes at the end of the log",
+                    log_bytes.len() - valid_len
+                );
+                store.log.set_len(valid_len as u64)?;
+                store.log.sync_all()?;
+            }
+            store.log_len = valid_len as u64;
+            store.logged = frames.len();
+        }
+
+        Ok(store)
+    }
+
+    /// Ask for a new snapshot once `interval` batches have been appended
+    /// since the last one.
+    pub fn with_snapshot_interval(mut self, interval: usize) -> Self {
+        self.snapshot_interval = interval;
+        self
+    }
+
+    /// Atomically replace `name` by a file with the given contents.
+    fn replace_file(&self, name: &str, contents: &[&[u8]]) -> io::Result<()> {
+        let tmp_path = self.dir.join(format!("{}.tmp", name));
+        let mut tmp = File::create(&tmp_path)?;
+        for part in contents {
+            tmp.write_all(part)?;
+        }
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, self.dir.join(name))?;
+
+        // Persist the rename itself:
+        #[cfg(unix)]
+        File::open(&self.dir)?.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Start a new, empty log of the current generation.
+    fn reset_log(&mut self) -> io::Result<()> {
+        self.replace_file(LOG_FILE, &[&self.generation.to_le_bytes()])?;
+        self.log = OpenOptions::new()
+            .append(true)
+            .open(self.dir.join(LOG_FILE))?;
+        self.log_len = 8;
+        self.logged = 0;
+        Ok(())
+    }
+}
+
+fn read_generation(file: &mut File) -> io::Result<u64> {
+    let mut generation = [0; 8];
+    file.read_exact(&mut generation)?;
+    Ok(u64::from_le_bytes(generation))
+}
+
+fn checksum(payload: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let mut checksum = [0; CHECKSUM_LEN];
+    checksum.copy_from_slice(&sha2::Sha256::digest(payload)[..CHECKSUM_LEN]);
+    checksum
+}
+
+/// Split a log into the payloads of its intact frames, following the
+/// generation. Returns them along with the length of the intact prefix.
+fn frames(log: &[u8]) -> (Vec<&[u8]>, usize) {
+    let mut frames = Vec::new();
+    let mut pos = 8;
+    while let Some(header) = log.get(pos..pos + FRAME_HEADER_LEN) {
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let start = pos + FRAME_HEADER_LEN;
+        let payload = match log.get(start..start + len) {
+            Some(payload) if checksum(payload) == header[4..] => payload,
+            _ => break,
+        };
+        frames.push(payload);
+        pos = start + len;
+    }
+
+    (frames, pos)
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> ChainStore<D, I> for FileStore {
+    fn append(&mut self, batch: &[Operation<D, I>]) -> Result<(), StoreError> {
+        let payload = encode_batch(batch);
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&checksum(&payload));
+        frame.extend_from_slice(&payload);
+
+        let written = self
+            .log
+            .write_all(&frame)
+            .and_then(|()| self.log.sync_data());
+        if let Err(e) = written {
+            // Don't leave a partial frame behind, which would hide all
+            // subsequently appended ones:
+            let _ = self.log.set_len(self.log_len);
+            return Err(e.into());
+        }
+
+        self.log_len += frame.len() as u64;
+        self.logged += 1;
+        Ok(())
+    }
+
+    fn snapshot(&mut self, snapshot: &[u8]) -> Result<(), StoreError> {
+        let generation = self.generation + 1;
+        self.replace_file(SNAPSHOT_FILE, &[&generation.to_le_bytes(), snapshot])?;
+        self.generation = generation;
+        self.reset_log()?;
+        Ok(())
+    }
+
+    fn load(&mut self) -> Result<Option<StoredState<D, I>>, StoreError> {
+        let snapshot = match fs::read(self.dir.join(SNAPSHOT_FILE)) {
+            Ok(snapshot) => snapshot,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if snapshot.len() < 8 {
+            return Err(StoreError::Corrupt("truncated snapshot".to_string()));
+        }
+
+        // The log has been truncated to its intact frames when opening the
+        // store, and has only been appended to since:
+        let log = fs::read(self.dir.join(LOG_FILE))?;
+        let mut operations = Vec::new();
+        for payload in frames(&log).0 {
+            operations.extend(decode_batch(payload)?);
+        }
+
+        Ok(Some(StoredState {
+            snapshot: snapshot[8..].to_vec(),
+            operations,
+        }))
+    }
+
+    fn needs_snapshot(&self) -> bool {
+        self.logged >= self.snapshot_interval
+    }
+}
@@ -0,0 +1,88 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::{
+    decode_batch, encode_batch, ChainStore, Operation, StoredState, DEFAULT_SNAPSHOT_INTERVAL,
+};
+use crate::{DeviceIdentifier, DigestAlgorithm, StoreError};
+
+/// A [`ChainStore`] keeping the snapshot and the log of encoded batches in
+/// memory, mainly for tests.
+///
+/// Clones share the same storage, such that the state can be recovered from
+/// a clone after the instance it has been attached to is gone, as if it had
+/// crashed.
+#[derive(Debug, Clone)]
+pub struct MemoryStore(Rc<RefCell<MemoryLog>>);
+
+#[derive(Debug)]
+struct MemoryLog {
+    snapshot: Option<Vec<u8>>,
+    batches: Vec<Vec<u8>>,
+    snapshot_interval: usize,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::with_snapshot_interval(DEFAULT_SNAPSHOT_INTERVAL)
+    }
+
+    /// Ask for a new snapshot once `interval` batches have been appended
+    /// since the last one.
+    pub fn with_snapshot_interval(interval: usize) -> Self {
+        MemoryStore(Rc::new(RefCell::new(MemoryLog {
+            snapshot: None,
+            batches: Vec::new(),
+            snapshot_interval: interval,
+        })))
+    }
+
+    /// Number of batches appended since the last snapshot.
+    pub fn logged_batches(&self) -> usize {
+        self.0.borrow().batches.len()
+    }
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> ChainStore<D, I> for MemoryStore {
+    fn append(&mut self, batch: &[Operation<D, I>]) -> Result<(), StoreError> {
+        let mut log = self.0.borrow_mut();
+        log.batches.push(encode_batch(batch));
+        Ok(())
+    }
+
+    fn snapshot(&mut self, snapshot: &[u8]) -> Result<(), StoreError> {
+        let mut log = self.0.borrow_mut();
+        log.snapshot = Some(snapshot.to_vec());
+        log.batches.clear();
+        Ok(())
+    }
+
+    fn load(&mut self) -> Result<Option<StoredState<D, I>>, StoreError> {
+        let log = self.0.borrow();
+        let snapshot = match &log.snapshot {
+            Some(snapshot) => snapshot.clone(),
+            None => return Ok(None),
+        };
+
+        let mut operations = Vec::new();
+        for batch in &log.batches {
+            operations.extend(decode_batch(batch)?);
+        }
+
+        Ok(Some(StoredState {
+            snapshot,
+            operations,
+        }))
+    }
+
+    fn needs_snapshot(&self) -> bool {
+        let log = self.0.borrow();
+        log.batches.len() >= log.snapshot_interval
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::{DeviceIdentifier, DigestAlgorithm, Error, MessageChains};
+
+/// A message shared with a peer which the peer has not validated.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SuspectMessage<I: DeviceIdentifier> {
+    pub local_seq: usize,
+    /// Sorted recipients of the message.
+    pub recipients: Vec<I>,
+    /// Sequence number and digest of the message's entry in the pairwise
+    /// hash-chain with the peer, unless the entry has been dropped due to
+    /// memory limits.
+    pub seq: Option<usize>,
+    pub digest: Option<Vec<u8>>,
+}
+
+/// Messages shared with a peer whose contents or ordering can no longer be
+/// trusted, e.g., after a fork of the pairwise hash-chain with the peer has
+/// been detected.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SuspectReport<I: DeviceIdentifier> {
+    pub device: I,
+    /// The latest local sequence number validated by the device, if any.
+    /// All messages up to this one have been validated, and can be trusted.
+    pub last_validated_local_seq: Option<usize>,
+    /// All messages shared with the device after the latest validated one,
+    /// sorted by local sequence number.
+    pub messages: Vec<SuspectMessage<I>>,
+}
+
+impl<D: DigestAlgorithm, I: DeviceIdentifier> MessageChains<D, I> {
+    /// Report all messages shared with `device` which it has not validated,
+    /// typically after [`Error::ForkDetected`] has been returned for it.
+    /// The server may have altered, dropped or reordered any of them.
+    ///
+    /// Messages whose records are no longer kept (see
+    /// [`MessageChains::message_status`]) are only reported while their
+    /// entries are held in the pairwise chain, without their recipients.
+    pub fn suspect_messages(&self, device: &I) -> Result<SuspectReport<I>, Error<I>> {
+        let chain = self
+            .chains
+            .get(device)
+            .ok_or_else(|| Error::UnknownDevice(device.clone()))?;
+        let validated_local_seq = chain.validated_local_seq;
+
+        // Entries held in the pairwise chain, including the checkpoint:
+        let mut entries: BTreeMap<usize, Option<(usize, Vec<u8>)>> = chain
+            .checkpoint
+            .iter()
+            .map(|(seq, entry)| (*seq, entry))
+            .chain(
+                chain
+                    .chain
+                    .iter()
+                    .enumerate()
+                    .map(|(index, entry)| (chain.offset + index, entry)),
+            )
+            .filter(|(_, entry)| entry.local_seq >= validated_local_seq)
+            .map(|(seq, entry)| (entry.local_seq, Some((seq, entry.digest.to_vec()))))
+            .collect();
+
+        // Messages whose entries have been dropped:
+        for (local_seq, recipients) in self.unvalidated_records(validated_local_seq) {
+            if recipients.binary_search(device).is_ok() {
+                entries.entry(local_seq).or_insert(None);
+            }
+        }
+
+        let messages = entries
+            .into_iter()
+            .map(|(local_seq, entry)| {
+                let (seq, digest) = entry.unzip();
+                SuspectMessage {
+                    local_seq,
+                    recipients: self
+                        .message_recipients(local_seq)
+                        .map(<[I]>::to_vec)
+                        .unwrap_or_default(),
+                    seq,
+                    digest,
+                }
+            })
+            .collect();
+
+        Ok(SuspectReport {
+            device: device.clone(),
+            last_validated_local_seq: validated_local_seq.checked_sub(1),
+            messages,
+        })
+    }
+}
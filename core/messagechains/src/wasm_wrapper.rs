@@ -2,130 +2,577 @@ use wasm_bindgen::prelude::*;
 
 use crate::MessageChains;
 
-pub fn error_to_string(error: crate::Error) -> &'static str {
-    match error {
-        crate::Error::TooFewRecipients => "too_few_recipients",
-        crate::Error::MissingSelfRecipient => "missing_self_recipient",
-        crate::Error::InvalidRecipientsOrder => "invalid_recipients_order",
-        crate::Error::InvariantViolated => "invariant_violated",
-        crate::Error::OwnMessageInvalidReordered => "own_message_invalid_reordered",
-        crate::Error::UnknownDevice => "unknown_device",
+/// Create a JS `Error` with the given message and a `code` property, as well
+/// as a `details` property unless it is `undefined`.
+fn js_error(message: &str, code: &str, details: JsValue) -> JsValue {
+    let error = js_sys::Error::new(message);
+    let _ = js_sys::Reflect::set(&error, &"code".into(), &code.into());
+    if !details.is_undefined() {
+        let _ = js_sys::Reflect::set(&error, &"details".into(), &details);
     }
+    error.into()
 }
 
-#[wasm_bindgen]
-pub struct Sha256StringMessageChains(MessageChains);
+/// Convert an error into a JS `Error`, with the error's [`std::fmt::Display`]
+/// output as message. Its `code` is the variant name in snake case, such as
+/// `"fork_detected"`, and `details` is an object holding the variant's
+/// context, if any (see the serialization of [`crate::Error`]).
+pub fn error_to_js(error: crate::Error) -> JsValue {
+    let serialized = serde_json::to_value(&error).unwrap_or_default();
+    let details = serialized
+        .get("details")
+        .and_then(|details| js_sys::JSON::parse(&details.to_string()).ok())
+        .unwrap_or(JsValue::UNDEFINED);
+    js_error(
+        &error.to_string(),
+        serialized
+            .get("code")
+            .and_then(|code| code.as_str())
+            .unwrap_or("unknown"),
+        details,
+    )
+}
 
-#[wasm_bindgen]
-impl Sha256StringMessageChains {
-    pub fn new(own_device: String) -> Self {
-        Sha256StringMessageChains(MessageChains::new(own_device))
+/// Convert a JS `Error` created by [`error_to_js`] back into a
+/// [`crate::Error`], or `None` if it hasn't been created that way.
+fn error_from_js(error: &JsValue) -> Option<crate::Error> {
+    let code = js_sys::Reflect::get(error, &"code".into())
+        .ok()?
+        .as_string()?;
+    let mut serialized = serde_json::json!({ "code": code });
+    let details = js_sys::Reflect::get(error, &"details".into()).ok()?;
+    if !details.is_undefined() {
+        let details = String::from(js_sys::JSON::stringify(&details).ok()?);
+        serialized["details"] = serde_json::from_str(&details).ok()?;
     }
+    serde_json::from_value(serialized).ok()
+}
 
-    pub fn from_dump(serialized: String) -> Result<Sha256StringMessageChains, String> {
-        serde_json::from_str(&serialized)
-            .map(Sha256StringMessageChains)
-            .map_err(|e| format!("Error while deserializing MessageChains struct: {:?}", e))
-    }
+fn serde_error(context: &str, error: serde_json::Error) -> JsValue {
+    js_error(
+        &format!("Error while {}: {:?}", context, error),
+        "serde_error",
+        JsValue::UNDEFINED,
+    )
+}
 
-    pub fn dump(&self) -> Result<String, String> {
-        serde_json::to_string(&self.0)
-            .map_err(|e| format!("Error while serializing MessageChains struct: {:?}", e))
-    }
+fn dump_error_to_js(error: crate::DumpError) -> JsValue {
+    let (code, details) = match &error {
+        crate::DumpError::Decode(_) => ("serde_error", JsValue::UNDEFINED),
+        crate::DumpError::Unauthenticated => ("unauthenticated", JsValue::UNDEFINED),
+        crate::DumpError::Rollback { epoch, latest } => {
+            let details = js_sys::Object::new();
+            let _ = js_sys::Reflect::set(&details, &"epoch".into(), &(*epoch as f64).into());
+            let _ = js_sys::Reflect::set(&details, &"latest".into(), &(*latest as f64).into());
+            ("rollback", details.into())
+        }
+    };
+    js_error(&error.to_string(), code, details)
+}
 
-    pub fn send_message(&mut self, message: String, recipients: Vec<js_sys::JsString>) {
-        self.0.send_message(
-            message.as_bytes(),
-            recipients.iter().map(Into::<String>::into),
-        )
-    }
+fn invalid_argument(code: &str) -> JsValue {
+    js_error(&code.replace('_', " "), code, JsValue::UNDEFINED)
+}
 
-    pub fn insert_message(
-        &mut self,
-        sender: String,
-        message: String,
-        recipients: Vec<js_sys::JsString>,
-    ) -> Result<usize, String> {
-        self.0
-            .insert_message(
-                &sender,
-                message.as_bytes(),
-                recipients.iter().map(Into::<String>::into),
-            )
-            .map_err(error_to_string)
-            .map_err(ToString::to_string)
-    }
+fn decode_digest_version(version: u8) -> Result<crate::DigestVersion, JsValue> {
+    crate::DigestVersion::try_from(version)
+        .map_err(|_| invalid_argument("unsupported_digest_version"))
+}
 
-    pub fn validate_chain(
-        &mut self,
-        validation_sender: String,
-        seq: Option<usize>,
-        digest: Option<String>,
-    ) -> Result<(), String> {
-        let validation_payload = match (seq, digest) {
-            (Some(seq), Some(digest)) => {
-                let mut digest_bytes = [0_u8; 32];
-                hex::decode_to_slice(&digest, &mut digest_bytes)
-                    .map_err(|_| "invalid_hash_format".to_string())?;
-                Some((seq, digest_bytes))
-            }
-            (None, None) => None,
-            (_, _) => panic!("Invalid arguments to validate_chain!"),
-        };
-
-        self.0
-            .validate_chain(&validation_sender, validation_payload)
-            .map_err(error_to_string)
-            .map_err(ToString::to_string)
+fn decode_validation_payload<D: crate::DigestAlgorithm>(
+    seq: Option<usize>,
+    digest: Option<String>,
+) -> Result<Option<(usize, crate::Hash<D>)>, JsValue> {
+    match (seq, digest) {
+        (Some(seq), Some(digest)) => {
+            let mut digest_bytes = crate::Hash::<D>::default();
+            hex::decode_to_slice(&digest, &mut digest_bytes[..])
+                .map_err(|_| invalid_argument("invalid_hash_format"))?;
+            Ok(Some((seq, digest_bytes)))
+        }
+        (None, None) => Ok(None),
+        (_, _) => Err(invalid_argument("invalid_validation_payload")),
     }
+}
 
-    pub fn validate_trim_chain(
-        &mut self,
-        validation_sender: String,
-        seq: Option<usize>,
-        digest: Option<String>,
-    ) -> Result<u32, String> {
-        let validation_payload = match (seq, digest) {
-            (Some(seq), Some(digest)) => {
-                let mut digest_bytes = [0_u8; 32];
-                hex::decode_to_slice(&digest, &mut digest_bytes)
-                    .map_err(|_| "invalid_hash_format".to_string())?;
-                Some((seq, digest_bytes))
-            }
-            (None, None) => None,
-            (_, _) => panic!("Invalid arguments to validate_chain!"),
-        };
-
-        self.0
-            .validate_trim_chain(&validation_sender, validation_payload)
-            .map_err(error_to_string)
-            .map_err(ToString::to_string)
-            .map(|trimmed| trimmed as u32)
-    }
+fn decode_sealing_key(key: &[u8]) -> Result<crate::SealingKey, JsValue> {
+    key.try_into()
+        .map_err(|_| invalid_argument("invalid_sealing_key"))
+}
 
-    pub fn validation_payload(&self, recipient: String) -> Option<js_sys::Array> {
-        self.0.validation_payload(&recipient).map(|(seq, digest)| {
-            let hex_digest = hex::encode(digest);
-            // TODO: what if the sequence number reaches u32::MAX?
-            js_sys::Array::of2(
-                &js_sys::Number::from(seq as u32),
-                &js_sys::JsString::from(hex_digest),
-            )
-        })
-    }
+// wasm_bindgen does not support generic types, so generate one wrapper type per
+// supported digest algorithm:
+macro_rules! string_message_chains {
+    ($name:ident, $digest:ty) => {
+        #[wasm_bindgen]
+        pub struct $name(MessageChains<$digest>);
 
-    pub fn sort_recipients(&self, recipients: Vec<js_sys::JsString>) -> Vec<js_sys::JsString> {
-        let mut recipients_rust_str: Vec<(js_sys::JsString, String)> = recipients
-            .into_iter()
-            .map(|js_string| {
-                let string = String::from(&js_string);
-                (js_string, string)
-            })
-            .collect();
-        recipients_rust_str.sort_by(|(_, a), (_, b)| a.cmp(b));
-        recipients_rust_str
-            .into_iter()
-            .map(|(js_string, _string)| js_string)
-            .collect()
-    }
+        #[wasm_bindgen]
+        impl $name {
+            pub fn new(own_device: String) -> Self {
+                $name(MessageChains::new(own_device))
+            }
+
+            /// Restore dumped state. `latest_epoch` is the latest epoch
+            /// recorded by the application, and state of an older epoch is
+            /// rejected with the error code `"rollback"`. Otherwise, the
+            /// application should record `epoch()` as the latest epoch.
+            pub fn from_dump(serialized: String, latest_epoch: f64) -> Result<$name, JsValue> {
+                let mut deserializer = serde_json::Deserializer::from_str(&serialized);
+                MessageChains::from_dump(&mut deserializer, &mut (latest_epoch as u64))
+                    .map($name)
+                    .map_err(dump_error_to_js)
+            }
+
+            /// Dump the state, which starts a new epoch. Once the dump has
+            /// been persisted, the application should record `epoch()` apart
+            /// from it, to be passed as `latest_epoch` when restoring state.
+            pub fn dump(&mut self) -> Result<String, JsValue> {
+                self.0
+                    .to_dump(serde_json::value::Serializer)
+                    .map(|dump| dump.to_string())
+                    .map_err(|e| serde_error("serializing MessageChains struct", e))
+            }
+
+            /// Restore state dumped through `dump_binary`, passed as a
+            /// `Uint8Array`. `latest_epoch` is checked as by `from_dump`.
+            pub fn from_binary_dump(
+                serialized: &[u8],
+                latest_epoch: f64,
+            ) -> Result<$name, JsValue> {
+                MessageChains::from_binary(serialized, &mut (latest_epoch as u64))
+                    .map($name)
+                    .map_err(dump_error_to_js)
+            }
+
+            /// Dump the state in the compact binary format, as a
+            /// `Uint8Array`. This starts a new epoch like `dump`.
+            pub fn dump_binary(&mut self) -> Vec<u8> {
+                self.0.to_binary()
+            }
+
+            /// Restore state dumped through `dump_sealed` with the same
+            /// 32-byte `key`. Modified state is rejected with the error code
+            /// `"unauthenticated"`. `latest_epoch` is checked as by
+            /// `from_dump`.
+            pub fn from_sealed_dump(
+                sealed: &[u8],
+                key: &[u8],
+                latest_epoch: f64,
+            ) -> Result<$name, JsValue> {
+                let key = decode_sealing_key(key)?;
+                MessageChains::from_sealed(sealed, &key, &mut (latest_epoch as u64))
+                    .map($name)
+                    .map_err(dump_error_to_js)
+            }
+
+            /// Dump the state in the compact binary format, encrypted and
+            /// authenticated with the given 32-byte `key`, as a `Uint8Array`.
+            /// This starts a new epoch like `dump`.
+            pub fn dump_sealed(&mut self, key: &[u8]) -> Result<Vec<u8>, JsValue> {
+                Ok(self.0.to_sealed(&decode_sealing_key(key)?))
+            }
+
+            pub fn epoch(&self) -> f64 {
+                self.0.epoch() as f64
+            }
+
+            pub fn set_strict_validation_payloads(&mut self, enabled: bool) {
+                self.0.config_mut().strict_validation_payloads = enabled;
+            }
+
+            pub fn set_gossip(&mut self, enabled: bool) {
+                self.0.config_mut().gossip = enabled;
+            }
+
+            pub fn set_digest_version(&mut self, version: u8) -> Result<(), JsValue> {
+                self.0.config_mut().digest_version = decode_digest_version(version)?;
+                Ok(())
+            }
+
+            pub fn set_default_peer_digest_version(&mut self, version: u8) -> Result<(), JsValue> {
+                self.0.config_mut().default_peer_digest_version =
+                    Some(decode_digest_version(version)?);
+                Ok(())
+            }
+
+            pub fn set_peer_digest_version(
+                &mut self,
+                peer: String,
+                version: u8,
+            ) -> Result<(), JsValue> {
+                self.0
+                    .set_peer_digest_version(&peer, decode_digest_version(version)?);
+                Ok(())
+            }
+
+            pub fn digest_version(&self) -> u8 {
+                self.0.config().digest_version.into()
+            }
+
+            pub fn send_message(
+                &mut self,
+                message: String,
+                recipients: Vec<js_sys::JsString>,
+            ) -> Result<u8, JsValue> {
+                self.0
+                    .send_message(
+                        message.as_bytes(),
+                        recipients.iter().map(Into::<String>::into),
+                    )
+                    .map(Into::into)
+                    .map_err(error_to_js)
+            }
+
+            pub fn insert_message(
+                &mut self,
+                sender: String,
+                message: String,
+                recipients: Vec<js_sys::JsString>,
+            ) -> Result<usize, JsValue> {
+                self.0
+                    .insert_message(
+                        &sender,
+                        message.as_bytes(),
+                        recipients.iter().map(Into::<String>::into),
+                    )
+                    .map_err(error_to_js)
+            }
+
+            /// Returns `[version, counters]`, where `counters` is
+            /// `undefined` if the message could not include them.
+            pub fn send_counted_message(
+                &mut self,
+                message: String,
+                recipients: Vec<js_sys::JsString>,
+            ) -> Result<js_sys::Array, JsValue> {
+                let outgoing = self
+                    .0
+                    .send_counted_message(
+                        message.as_bytes(),
+                        recipients.iter().map(Into::<String>::into),
+                    )
+                    .map_err(error_to_js)?;
+                let counters = match outgoing.counters {
+                    Some(counters) => counters
+                        .into_iter()
+                        .map(|counter| js_sys::Number::from(counter as f64))
+                        .collect::<js_sys::Array>()
+                        .into(),
+                    None => JsValue::UNDEFINED,
+                };
+                Ok(js_sys::Array::of2(
+                    &js_sys::Number::from(u8::from(outgoing.digest_version)),
+                    &counters,
+                ))
+            }
+
+            pub fn insert_versioned_message(
+                &mut self,
+                sender: String,
+                message: String,
+                recipients: Vec<js_sys::JsString>,
+                version: u8,
+            ) -> Result<usize, JsValue> {
+                self.0
+                    .insert_versioned_message(
+                        &sender,
+                        message.as_bytes(),
+                        recipients.iter().map(Into::<String>::into),
+                        decode_digest_version(version)?,
+                    )
+                    .map_err(error_to_js)
+            }
+
+            pub fn validate_chain(
+                &mut self,
+                validation_sender: String,
+                seq: Option<usize>,
+                digest: Option<String>,
+            ) -> Result<(), JsValue> {
+                let validation_payload = decode_validation_payload::<$digest>(seq, digest)?;
+
+                self.0
+                    .validate_chain(&validation_sender, validation_payload)
+                    .map_err(error_to_js)
+            }
+
+            pub fn validate_trim_chain(
+                &mut self,
+                validation_sender: String,
+                seq: Option<usize>,
+                digest: Option<String>,
+            ) -> Result<u32, JsValue> {
+                let validation_payload = decode_validation_payload::<$digest>(seq, digest)?;
+
+                self.0
+                    .validate_trim_chain(&validation_sender, validation_payload)
+                    .map_err(error_to_js)
+                    .map(|trimmed| trimmed as u32)
+            }
+
+            /// Returns `[local_seq, trimmed]` on success, or `undefined` if
+            /// the message has been ignored as a duplicate. On error, the
+            /// state is left unchanged. Server sequence numbers and counters
+            /// are passed as JS numbers.
+            #[allow(clippy::too_many_arguments)]
+            pub fn receive(
+                &mut self,
+                sender: String,
+                message: String,
+                recipients: Vec<js_sys::JsString>,
+                seq: Option<usize>,
+                digest: Option<String>,
+                version: Option<u8>,
+                counters: Option<Vec<f64>>,
+                server_seq: Option<f64>,
+            ) -> Result<Option<js_sys::Array>, JsValue> {
+                let recipients: Vec<String> = recipients.iter().map(Into::into).collect();
+                let counters: Option<Vec<u64>> = counters
+                    .map(|counters| counters.into_iter().map(|counter| counter as u64).collect());
+                let envelope = crate::Envelope {
+                    sender: &sender,
+                    message: message.as_bytes(),
+                    recipients: &recipients,
+                    validation_payload: decode_validation_payload::<$digest>(seq, digest)?,
+                    digest_version: version.map(decode_digest_version).transpose()?,
+                    counters: counters.as_deref(),
+                    server_seq: server_seq.map(|seq| seq as u64),
+                };
+
+                self.0
+                    .receive(envelope)
+                    .map_err(error_to_js)
+                    .map(|outcome| {
+                        outcome.map(|outcome| {
+                            js_sys::Array::of2(
+                                &js_sys::Number::from(outcome.local_seq as u32),
+                                &js_sys::Number::from(outcome.trimmed as u32),
+                            )
+                        })
+                    })
+            }
+
+            /// `policy` is one of `"reject"`, `"evict"` or `"compact"`.
+            pub fn set_chain_limits(
+                &mut self,
+                max_chain_len: Option<usize>,
+                max_total_chain_len: Option<usize>,
+                policy: String,
+            ) -> Result<(), JsValue> {
+                let config = self.0.config_mut();
+                config.limit_policy = match policy.as_str() {
+                    "reject" => crate::LimitPolicy::Reject,
+                    "evict" => crate::LimitPolicy::Evict,
+                    "compact" => crate::LimitPolicy::Compact,
+                    _ => return Err(invalid_argument("invalid_limit_policy")),
+                };
+                config.max_chain_len = max_chain_len;
+                config.max_total_chain_len = max_total_chain_len;
+                Ok(())
+            }
+
+            pub fn memory_usage(&self) -> Result<String, JsValue> {
+                serde_json::to_string(&self.0.memory_usage())
+                    .map_err(|e| serde_error("serializing memory usage", e))
+            }
+
+            pub fn message_status(&self, local_seq: usize) -> Result<String, JsValue> {
+                let status = self.0.message_status(local_seq).map_err(error_to_js)?;
+                serde_json::to_string(&status)
+                    .map_err(|e| serde_error("serializing message status", e))
+            }
+
+            /// Serialized as an object mapping devices to local sequence
+            /// numbers.
+            pub fn oldest_unvalidated_messages(&self) -> Result<String, JsValue> {
+                serde_json::to_string(&self.0.oldest_unvalidated_messages())
+                    .map_err(|e| serde_error("serializing oldest unvalidated messages", e))
+            }
+
+            pub fn suspect_messages(&self, device: String) -> Result<String, JsValue> {
+                let report = self.0.suspect_messages(&device).map_err(error_to_js)?;
+                serde_json::to_string(&report)
+                    .map_err(|e| serde_error("serializing suspect messages", e))
+            }
+
+            /// Returns `"healthy"`, `"suspicious"` or `"quarantined"`.
+            pub fn peer_health(&self, device: String) -> String {
+                match self.0.peer_health(&device) {
+                    crate::PeerHealth::Healthy => "healthy",
+                    crate::PeerHealth::Suspicious => "suspicious",
+                    crate::PeerHealth::Quarantined => "quarantined",
+                }
+                .to_string()
+            }
+
+            pub fn recover_peer(&mut self, device: String) -> bool {
+                self.0.recover_peer(&device)
+            }
+
+            /// Record an error thrown by any other method, such as
+            /// `receive`, escalating the health of every peer to blame for
+            /// it if it is an invariant violation. Returns whether any peer
+            /// has been blamed.
+            pub fn record_violation(&mut self, error: JsValue) -> Result<bool, JsValue> {
+                let error =
+                    error_from_js(&error).ok_or_else(|| invalid_argument("invalid_error"))?;
+                Ok(self.0.record_violation(&error))
+            }
+
+            pub fn set_quarantine_threshold(&mut self, threshold: u32) {
+                self.0.config_mut().quarantine_threshold = Some(threshold as usize);
+            }
+
+            pub fn retire_device(&mut self, device: String) -> Result<String, JsValue> {
+                let report = self.0.retire_device(&device).map_err(error_to_js)?;
+                serde_json::to_string(&report)
+                    .map_err(|e| serde_error("serializing retirement report", e))
+            }
+
+            /// `seq` and `digest` are the validation payload the device
+            /// currently sends us, if any.
+            pub fn unretire_device(
+                &mut self,
+                device: String,
+                seq: Option<usize>,
+                digest: Option<String>,
+            ) -> Result<bool, JsValue> {
+                let validation_payload = decode_validation_payload::<$digest>(seq, digest)?;
+
+                self.0
+                    .unretire_device(&device, validation_payload)
+                    .map_err(error_to_js)
+            }
+
+            /// `is_known` is called with a device identifier, and must
+            /// return whether the device is a known contact or linked
+            /// device. Exceptions are treated as unknown devices.
+            pub fn set_membership_oracle(&mut self, is_known: js_sys::Function) {
+                self.0.set_membership_oracle(move |device: &String| {
+                    is_known
+                        .call1(&JsValue::NULL, &JsValue::from_str(device))
+                        .map(|known| known.is_truthy())
+                        .unwrap_or(false)
+                });
+            }
+
+            pub fn clear_membership_oracle(&mut self) {
+                self.0.clear_membership_oracle();
+            }
+
+            /// `on_event` is called with an object for every lifecycle event,
+            /// whose `event` property is the event name in snake case, such
+            /// as `"message_inserted"`, along with the event's fields.
+            /// Exceptions are ignored.
+            pub fn set_observer(&mut self, on_event: js_sys::Function) {
+                self.0.set_observer(move |event: &crate::Event<String>| {
+                    let event = serde_json::to_string(event)
+                        .ok()
+                        .and_then(|event| js_sys::JSON::parse(&event).ok())
+                        .unwrap_or(JsValue::UNDEFINED);
+                    let _ = on_event.call1(&JsValue::NULL, &event);
+                });
+            }
+
+            pub fn clear_observer(&mut self) {
+                self.0.clear_observer();
+            }
+
+            pub fn set_tick(&mut self, tick: f64) {
+                self.0.set_tick(tick as u64)
+            }
+
+            /// Returns the identifiers of all overdue pending messages.
+            pub fn overdue_pending_messages(&self, timeout: f64) -> Vec<f64> {
+                self.0
+                    .overdue_pending_messages(timeout as u64)
+                    .into_iter()
+                    .map(|pending| pending.id as f64)
+                    .collect()
+            }
+
+            pub fn retry_pending_message(&mut self, id: f64) -> Result<(), JsValue> {
+                self.0.retry_pending_message(id as u64).map_err(error_to_js)
+            }
+
+            pub fn declare_pending_message_lost(&mut self, id: f64) -> Result<(), JsValue> {
+                self.0
+                    .declare_pending_message_lost(id as u64)
+                    .map_err(error_to_js)
+            }
+
+            pub fn cancel_pending_message(&mut self, id: f64) -> Result<(), JsValue> {
+                self.0
+                    .cancel_pending_message(id as u64)
+                    .map_err(error_to_js)
+            }
+
+            pub fn message_quorum_validated(
+                &self,
+                local_seq: usize,
+                quorum: usize,
+            ) -> Result<bool, JsValue> {
+                self.0
+                    .message_quorum_validated(local_seq, quorum)
+                    .map_err(error_to_js)
+            }
+
+            pub fn message_fully_validated(&self, local_seq: usize) -> Result<bool, JsValue> {
+                self.0
+                    .message_fully_validated(local_seq)
+                    .map_err(error_to_js)
+            }
+
+            pub fn validation_payload(&self, recipient: String) -> Option<js_sys::Array> {
+                self.0.validation_payload(&recipient).map(|(seq, digest)| {
+                    let hex_digest = hex::encode(digest);
+                    // TODO: what if the sequence number reaches u32::MAX?
+                    js_sys::Array::of2(
+                        &js_sys::Number::from(seq as u32),
+                        &js_sys::JsString::from(hex_digest),
+                    )
+                })
+            }
+
+            pub fn gossip_payload(&self, recipient: String) -> Result<String, JsValue> {
+                serde_json::to_string(&self.0.gossip_payload(&recipient))
+                    .map_err(|e| serde_error("serializing gossip payload", e))
+            }
+
+            pub fn validate_gossip(
+                &mut self,
+                sender: String,
+                claims: String,
+            ) -> Result<(), JsValue> {
+                let claims: Vec<crate::GossipClaim<$digest, String>> =
+                    serde_json::from_str(&claims)
+                        .map_err(|_| invalid_argument("invalid_gossip_format"))?;
+
+                self.0.validate_gossip(&sender, claims).map_err(error_to_js)
+            }
+
+            pub fn sort_recipients(
+                &self,
+                recipients: Vec<js_sys::JsString>,
+            ) -> Vec<js_sys::JsString> {
+                let mut recipients_rust_str: Vec<(js_sys::JsString, String)> = recipients
+                    .into_iter()
+                    .map(|js_string| {
+                        let string = String::from(&js_string);
+                        (js_string, string)
+                    })
+                    .collect();
+                recipients_rust_str.sort_by(|(_, a), (_, b)| a.cmp(b));
+                recipients_rust_str
+                    .into_iter()
+                    .map(|(js_string, _string)| js_string)
+                    .collect()
+            }
+        }
+    };
 }
+
+string_message_chains!(Sha256StringMessageChains, sha2::Sha256);
+string_message_chains!(Sha512_256StringMessageChains, sha2::Sha512_256);
+string_message_chains!(Blake3StringMessageChains, blake3::Hasher);